@@ -1,5 +1,33 @@
 use criterion::{Criterion, criterion_group, criterion_main};
-use radix_trie::Trie;
+use radix_trie::{Granularity, Trie, TrieCommon};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Allocator which keeps track of the number of bytes allocated, to measure memory usage.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
 
 fn get_text() -> Vec<String> {
     use std::fs::File;
@@ -16,8 +44,12 @@ fn get_text() -> Vec<String> {
         .collect()
 }
 
-fn make_trie(words: &[String]) -> Trie<&str, usize> {
-    let mut trie = Trie::new();
+/// The granularities to benchmark, with the suffixes of their benchmark names.
+const GRANULARITIES: [(Granularity, &str); 2] =
+    [(Granularity::Nibble, ""), (Granularity::Byte, " (byte)")];
+
+fn make_trie(words: &[String], granularity: Granularity) -> Trie<&str, usize> {
+    let mut trie = Trie::with_granularity(granularity);
     for w in words {
         trie.insert(&w[..], w.len());
     }
@@ -26,35 +58,130 @@ fn make_trie(words: &[String]) -> Trie<&str, usize> {
 
 fn trie_insert(b: &mut Criterion) {
     let words = get_text();
-    b.bench_function("trie insert", |b| b.iter(|| make_trie(&words)));
+    for (granularity, suffix) in GRANULARITIES {
+        b.bench_function(&format!("trie insert{}", suffix), |b| {
+            b.iter(|| make_trie(&words, granularity))
+        });
+    }
 }
 
 fn trie_get(b: &mut Criterion) {
     let words = get_text();
-    let trie = make_trie(&words);
-    b.bench_function("trie get", |b| {
-        b.iter(|| {
-            words
-                .iter()
-                .map(|w| trie.get(&&w[..]))
-                .collect::<Vec<Option<&usize>>>()
-        })
-    });
+    for (granularity, suffix) in GRANULARITIES {
+        let trie = make_trie(&words, granularity);
+        b.bench_function(&format!("trie get{}", suffix), |b| {
+            b.iter(|| {
+                words
+                    .iter()
+                    .map(|w| trie.get(&&w[..]))
+                    .collect::<Vec<Option<&usize>>>()
+            })
+        });
+    }
 }
 
 fn trie_insert_remove(b: &mut Criterion) {
     let words = get_text();
+    for (granularity, suffix) in GRANULARITIES {
+        b.bench_function(&format!("trie remove{}", suffix), |b| {
+            b.iter(|| {
+                let mut trie = make_trie(&words, granularity);
+                for w in &words {
+                    trie.remove(&&w[..]);
+                }
+            });
+        });
+    }
+}
 
-    b.bench_function("trie remove", |b| {
+/// Long keys with long shared prefixes, like the URLs of a web service.
+fn get_urls() -> Vec<String> {
+    let mut urls = vec![];
+    for user in 0..50 {
+        for project in 0..20 {
+            urls.push(format!(
+                "https://api.example.com/v1/organisations/example-organisation/users/{:06}\
+                 /projects/project-{:04}/files/src/main/resources/config/application.properties",
+                user, project
+            ));
+        }
+    }
+    urls
+}
+
+fn trie_insert_long_keys(b: &mut Criterion) {
+    let urls = get_urls();
+    for (granularity, suffix) in GRANULARITIES {
+        b.bench_function(&format!("trie insert long keys{}", suffix), |b| {
+            b.iter(|| make_trie(&urls, granularity))
+        });
+    }
+}
+
+fn trie_get_long_keys(b: &mut Criterion) {
+    let urls = get_urls();
+    for (granularity, suffix) in GRANULARITIES {
+        let trie = make_trie(&urls, granularity);
+        b.bench_function(&format!("trie get long keys{}", suffix), |b| {
+            b.iter(|| {
+                urls.iter()
+                    .map(|url| trie.get(&&url[..]))
+                    .collect::<Vec<Option<&usize>>>()
+            })
+        });
+    }
+}
+
+fn trie_union(b: &mut Criterion) {
+    let urls = get_urls();
+    let (evens, odds): (Vec<_>, Vec<_>) =
+        urls.chunks(2).map(|c| (c[0].clone(), c[1].clone())).unzip();
+    let evens = make_trie(&evens, Granularity::Nibble);
+    let odds = make_trie(&odds, Granularity::Nibble);
+
+    b.bench_function("trie union", |b| {
+        b.iter(|| {
+            let mut trie = evens.clone();
+            trie.union_with(odds.clone(), |a, _| a);
+            trie
+        })
+    });
+    b.bench_function("trie union by insert", |b| {
         b.iter(|| {
-            let mut trie = make_trie(&words);
-            for w in &words {
-                trie.remove(&&w[..]);
+            let mut trie = evens.clone();
+            for (k, v) in odds.clone() {
+                trie.insert(k, v);
             }
-        });
+            trie
+        })
     });
 }
 
-criterion_group!(benches, trie_insert, trie_get, trie_insert_remove);
+fn trie_memory(_: &mut Criterion) {
+    for (name, keys) in [("words", get_text()), ("long keys", get_urls())] {
+        for (granularity, suffix) in GRANULARITIES {
+            let before = ALLOCATED.load(Ordering::Relaxed);
+            let trie = make_trie(&keys, granularity);
+            let bytes = ALLOCATED.load(Ordering::Relaxed) - before;
+            println!(
+                "trie memory ({}){}: {:.1} bytes per key",
+                name,
+                suffix,
+                bytes as f64 / trie.len() as f64
+            );
+        }
+    }
+}
+
+criterion_group!(
+    benches,
+    trie_insert,
+    trie_get,
+    trie_insert_remove,
+    trie_insert_long_keys,
+    trie_get_long_keys,
+    trie_union,
+    trie_memory
+);
 
 criterion_main!(benches);
@@ -0,0 +1,22 @@
+extern crate radix_trie;
+
+use radix_trie::{IpPrefix, Trie};
+use std::net::Ipv4Addr;
+
+fn main() {
+    let mut routes = Trie::new();
+    routes.insert(IpPrefix::new(Ipv4Addr::new(0, 0, 0, 0), 0), "default");
+    routes.insert(IpPrefix::new(Ipv4Addr::new(10, 0, 0, 0), 8), "eth0");
+    routes.insert(IpPrefix::new(Ipv4Addr::new(10, 8, 0, 0), 13), "eth1");
+    routes.insert(IpPrefix::new(Ipv4Addr::new(10, 8, 1, 0), 24), "eth2");
+
+    for addr in [
+        Ipv4Addr::new(10, 8, 1, 7),
+        Ipv4Addr::new(10, 15, 255, 255),
+        Ipv4Addr::new(10, 16, 0, 0),
+        Ipv4Addr::new(192, 168, 0, 1),
+    ] {
+        let route = routes.get_ancestor_value(&IpPrefix::from(addr)).unwrap();
+        println!("{} -> {}", addr, route);
+    }
+}
@@ -0,0 +1,37 @@
+extern crate radix_trie;
+
+use radix_trie::{PersistentTrie, TrieCommon};
+use std::sync::Mutex;
+use std::thread;
+
+fn main() {
+    let mut trie = PersistentTrie::new();
+    // The latest snapshot, which readers take out of the lock before querying it.
+    let latest = Mutex::new(trie.snapshot());
+
+    thread::scope(|s| {
+        for reader in 0..4 {
+            let latest = &latest;
+            s.spawn(move || {
+                let mut seen = 0;
+                while seen < 10 * 1000 {
+                    let snapshot = latest.lock().unwrap().snapshot();
+                    if snapshot.len() != seen {
+                        seen = snapshot.len();
+                        let total: u32 = snapshot.values().sum();
+                        println!("reader {}: {} keys, total {}", reader, seen, total);
+                    }
+                    thread::yield_now();
+                }
+            });
+        }
+
+        // Snapshots are only published between batches, so readers never see half a batch.
+        for batch in 0..10 {
+            for i in 0..1000 {
+                trie.insert_mut(format!("key-{}-{}", batch, i), batch);
+            }
+            *latest.lock().unwrap() = trie.snapshot();
+        }
+    });
+}
@@ -0,0 +1,182 @@
+//! Derive macro for the `TrieKey` trait of `radix_trie`.
+//!
+//! Enable the `derive` feature of `radix_trie` and use `#[derive(TrieKey)]` on structs and enums
+//! whose fields all implement `TrieKey`.
+//!
+//! Fields are encoded in declaration order, in the same way as the components of a tuple, so
+//! distinct values never share an encoding and the order of keys in the trie matches the order
+//! given by `#[derive(PartialOrd, Ord)]`. Enum variants are encoded by their discriminants, which
+//! must be integer literals if given explicitly. Fields marked `#[trie_key(skip)]` are left out
+//! of the encoding, so they must not affect equality.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Literal, Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    Data, DataEnum, DeriveInput, Error, Expr, ExprLit, ExprUnary, Field, Fields, Lit, UnOp,
+    parse_macro_input, parse_quote,
+};
+
+#[proc_macro_derive(TrieKey, attributes(trie_key))]
+pub fn derive_trie_key(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(mut input: DeriveInput) -> syn::Result<TokenStream2> {
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::radix_trie::TrieKey));
+    }
+
+    let body = match input.data {
+        Data::Struct(ref data) => {
+            let (pattern, encode) = encode_fields(&data.fields)?;
+            quote! {
+                let Self #pattern = self;
+                #encode
+            }
+        }
+        Data::Enum(ref data) => encode_enum(data)?,
+        Data::Union(_) => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "TrieKey can't be derived for unions",
+            ));
+        }
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::radix_trie::TrieKey for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn encode_bytes(&self) -> ::std::vec::Vec<u8> {
+                let mut v = ::std::vec::Vec::new();
+                #body
+                v
+            }
+        }
+    })
+}
+
+/// Generate a pattern binding the encoded fields, and the code to encode them into `v`.
+fn encode_fields(fields: &Fields) -> syn::Result<(TokenStream2, TokenStream2)> {
+    let mut bindings = vec![];
+    let mut patterns = vec![];
+
+    for (i, field) in fields.iter().enumerate() {
+        let binding = format_ident!("__field{}", i);
+        let skip = is_skipped(field)?;
+        match (&field.ident, skip) {
+            (Some(ident), false) => patterns.push(quote!(#ident: #binding)),
+            (Some(_), true) => (),
+            (None, false) => patterns.push(quote!(#binding)),
+            (None, true) => patterns.push(quote!(_)),
+        }
+        if !skip {
+            bindings.push(binding);
+        }
+    }
+
+    let pattern = match *fields {
+        Fields::Named(_) => quote!({ #(#patterns,)* .. }),
+        Fields::Unnamed(_) => quote!(( #(#patterns),* )),
+        Fields::Unit => quote!(),
+    };
+    let encode = quote! {
+        #( ::radix_trie::__private::encode_component(#bindings, &mut v); )*
+    };
+    Ok((pattern, encode))
+}
+
+/// Generate the code to encode an enum's discriminant and fields into `v`.
+fn encode_enum(data: &DataEnum) -> syn::Result<TokenStream2> {
+    if data.variants.is_empty() {
+        return Err(Error::new(
+            Span::call_site(),
+            "TrieKey can't be derived for enums without variants",
+        ));
+    }
+
+    // Work out the discriminants, following the rules for implicit discriminants.
+    let mut discriminants = vec![];
+    let mut next = 0i128;
+    for variant in &data.variants {
+        let value = match variant.discriminant {
+            Some((_, ref expr)) => literal_discriminant(expr)?,
+            None => next,
+        };
+        discriminants.push(value);
+        next = value + 1;
+    }
+
+    // Use a single byte if possible, or the order-preserving encoding of an `i64` otherwise.
+    let single_byte = discriminants.iter().all(|&d| (0..=255).contains(&d));
+
+    let mut arms = vec![];
+    for (variant, &discriminant) in data.variants.iter().zip(&discriminants) {
+        let encode_discriminant = if single_byte {
+            let byte = Literal::u8_suffixed(discriminant as u8);
+            quote!(v.push(#byte);)
+        } else {
+            let value = i64::try_from(discriminant)
+                .map_err(|_| Error::new_spanned(variant, "discriminant doesn't fit in an i64"))?;
+            let value = Literal::i64_suffixed(value);
+            quote!(v.extend_from_slice(&::radix_trie::TrieKey::encode_bytes(&#value));)
+        };
+        let ident = &variant.ident;
+        let (pattern, encode) = encode_fields(&variant.fields)?;
+        arms.push(quote! {
+            Self::#ident #pattern => {
+                #encode_discriminant
+                #encode
+            }
+        });
+    }
+
+    Ok(quote! {
+        match self {
+            #(#arms)*
+        }
+    })
+}
+
+/// Evaluate an explicit discriminant, which must be an integer literal (possibly negated).
+fn literal_discriminant(expr: &Expr) -> syn::Result<i128> {
+    match *expr {
+        Expr::Lit(ExprLit {
+            lit: Lit::Int(ref int),
+            ..
+        }) => int.base10_parse(),
+        Expr::Unary(ExprUnary {
+            op: UnOp::Neg(_),
+            ref expr,
+            ..
+        }) => literal_discriminant(expr).map(|value| -value),
+        _ => Err(Error::new_spanned(
+            expr,
+            "TrieKey can only be derived for enums with integer literal discriminants",
+        )),
+    }
+}
+
+/// Check a field for the `#[trie_key(skip)]` attribute.
+fn is_skipped(field: &Field) -> syn::Result<bool> {
+    let mut skip = false;
+    for attr in &field.attrs {
+        if !attr.path().is_ident("trie_key") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                skip = true;
+                Ok(())
+            } else {
+                Err(meta.error("unsupported trie_key attribute"))
+            }
+        })?;
+    }
+    Ok(skip)
+}
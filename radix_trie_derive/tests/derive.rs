@@ -0,0 +1,253 @@
+use radix_trie::{IpPrefix, Trie, TrieCommon, TrieKey};
+use std::fmt::Debug;
+use std::net::Ipv4Addr;
+
+/// Insert `keys` into a trie and check that it iterates them in `Ord` order.
+fn check_order<K>(keys: Vec<K>)
+where
+    K: TrieKey + Ord + Clone + Debug,
+{
+    let mut trie = Trie::new();
+    for (i, key) in keys.iter().enumerate() {
+        trie.insert(key.clone(), i);
+    }
+    assert!(trie.check_integrity());
+
+    let mut sorted = keys;
+    sorted.sort();
+    sorted.dedup();
+    assert_eq!(trie.keys().cloned().collect::<Vec<_>>(), sorted);
+}
+
+#[derive(TrieKey, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+struct Named {
+    tenant: String,
+    id: u64,
+    delta: i32,
+}
+
+#[derive(TrieKey, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+struct Tuple(String, Vec<u8>);
+
+#[derive(TrieKey, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+struct Route {
+    table: u16,
+    prefix: IpPrefix<Ipv4Addr>,
+}
+
+#[derive(TrieKey, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+struct Unit;
+
+#[derive(TrieKey, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+enum Shape {
+    Empty,
+    Circle(u32),
+    Rect { width: u32, height: u32 },
+    Named(String, Box<Shape>),
+}
+
+#[derive(TrieKey, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+enum Explicit {
+    A = 5,
+    B,
+    C = 3,
+}
+
+#[derive(TrieKey, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+enum Wide {
+    Low = -1000,
+    Zero = 0,
+    High = 100_000,
+}
+
+#[derive(TrieKey, Clone, Debug)]
+struct Skipped {
+    name: String,
+    #[trie_key(skip)]
+    cached_len: usize,
+}
+
+impl PartialEq for Skipped {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for Skipped {}
+
+#[derive(TrieKey, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+struct Generic<T> {
+    first: T,
+    second: T,
+}
+
+impl TrieKey for Box<Shape> {
+    fn encode_bytes(&self) -> Vec<u8> {
+        (**self).encode_bytes()
+    }
+}
+
+fn strings() -> Vec<String> {
+    ["", "a", "ab", "abc", "b", "a\0", "\0", "ba"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+}
+
+#[test]
+fn named_struct_order() {
+    let mut keys = vec![];
+    for tenant in strings() {
+        for id in [0, 1, 255, 256, u64::MAX] {
+            for delta in [i32::MIN, -1, 0, 1, i32::MAX] {
+                keys.push(Named {
+                    tenant: tenant.clone(),
+                    id,
+                    delta,
+                });
+            }
+        }
+    }
+    check_order(keys);
+}
+
+#[test]
+fn tuple_struct_order() {
+    let mut keys = vec![];
+    for s in strings() {
+        for bytes in [vec![], vec![0], vec![0, 0], vec![1], vec![0xFF, 0]] {
+            keys.push(Tuple(s.clone(), bytes));
+        }
+    }
+    check_order(keys);
+}
+
+#[test]
+fn ip_prefix_field_order() {
+    let mut keys = vec![];
+    for table in [0, 1, 256] {
+        for (addr, len) in [
+            (0, 0),
+            (10 << 24, 7),
+            (10 << 24, 8),
+            (11 << 24, 8),
+            (u32::MAX, 32),
+        ] {
+            keys.push(Route {
+                table,
+                prefix: IpPrefix::new(Ipv4Addr::from_bits(addr), len),
+            });
+        }
+    }
+    check_order(keys);
+}
+
+#[test]
+fn unit_struct() {
+    let mut trie = Trie::new();
+    trie.insert(Unit, 1);
+    trie.insert(Unit, 2);
+    assert_eq!(trie.len(), 1);
+    assert_eq!(trie.get(&Unit), Some(&2));
+}
+
+#[test]
+fn enum_order() {
+    let mut keys = vec![Shape::Empty];
+    for n in [0, 1, 1000] {
+        keys.push(Shape::Circle(n));
+        keys.push(Shape::Rect {
+            width: n,
+            height: 1,
+        });
+        keys.push(Shape::Rect {
+            width: 1,
+            height: n,
+        });
+    }
+    for s in strings() {
+        keys.push(Shape::Named(s.clone(), Box::new(Shape::Empty)));
+        keys.push(Shape::Named(s.clone(), Box::new(Shape::Circle(3))));
+        keys.push(Shape::Named(
+            s.clone(),
+            Box::new(Shape::Named(s, Box::new(Shape::Empty))),
+        ));
+    }
+    check_order(keys);
+}
+
+#[test]
+fn explicit_discriminant_order() {
+    check_order(vec![Explicit::A, Explicit::B, Explicit::C]);
+    check_order(vec![Wide::High, Wide::Zero, Wide::Low]);
+}
+
+#[test]
+fn skipped_fields() {
+    let mut trie = Trie::new();
+    trie.insert(
+        Skipped {
+            name: "a".into(),
+            cached_len: 1,
+        },
+        1,
+    );
+    trie.insert(
+        Skipped {
+            name: "a".into(),
+            cached_len: 2,
+        },
+        2,
+    );
+    assert_eq!(trie.len(), 1);
+    assert_eq!(trie.keys().next().unwrap().cached_len, 2);
+    assert_eq!(
+        trie.get(&Skipped {
+            name: "a".into(),
+            cached_len: 3
+        }),
+        Some(&2)
+    );
+}
+
+#[test]
+fn generic_struct_order() {
+    let mut keys = vec![];
+    for first in strings() {
+        for second in strings() {
+            keys.push(Generic {
+                first: first.clone(),
+                second,
+            });
+        }
+    }
+    check_order(keys);
+
+    let mut keys = vec![];
+    for first in [-5i64, 0, 5] {
+        for second in [-1i64, 1] {
+            keys.push(Generic { first, second });
+        }
+    }
+    check_order(keys);
+}
+
+#[test]
+fn leading_fields_as_prefix() {
+    let mut trie = Trie::new();
+    for id in 0..10u64 {
+        for tenant in ["a", "ab", "b"] {
+            trie.insert(
+                Named {
+                    tenant: tenant.into(),
+                    id,
+                    delta: 0,
+                },
+                id,
+            );
+        }
+    }
+    let subtrie = trie.get_raw_descendant(&("a".to_string(),)).unwrap();
+    assert_eq!(subtrie.len(), 10);
+    assert!(subtrie.keys().all(|key| key.tenant == "a"));
+}
@@ -0,0 +1,572 @@
+//! Storage for the children of a trie node, which adapts to the number of children.
+
+use std::fmt;
+use std::iter::{FilterMap, Zip};
+use std::ops::{Bound, Deref, Range, RangeBounds};
+use std::slice;
+use std::sync::Arc;
+use std::vec;
+
+use crate::{Granularity, TrieNode};
+
+/// The maximum number of children stored in a sparse list, at each granularity.
+#[inline]
+fn sparse_max(granularity: Granularity) -> usize {
+    match granularity {
+        Granularity::Nibble => 4,
+        Granularity::Byte => 48,
+    }
+}
+
+/// The numbers of children at which dense and wide arrays are converted back to sparse lists.
+///
+/// These are less than the maximum lengths of the sparse lists, so that a node which gains and
+/// loses a single child repeatedly doesn't convert back and forth.
+const DENSE_SHRINK: usize = 3;
+const WIDE_SHRINK: usize = 40;
+
+/// The kind of pointer through which trie nodes own their children.
+///
+/// The nodes of a `PersistentTrie` are shared between its versions, so they own their children
+/// through an `Arc` (`Shared`). The nodes of every other trie have a single owner, and own their
+/// children through a `Box` (`Owned`), which is smaller and doesn't require the keys and values
+/// to be `Sync` for the trie to be `Send`.
+///
+/// This trait only appears in the types of the subtries and iterators of a `PersistentTrie`, and
+/// can't be implemented outside this crate.
+pub trait ChildPtr: sealed::Sealed + Sized {
+    #[doc(hidden)]
+    type Child<K, V>: Deref<Target = TrieNode<K, V, Self>>;
+
+    /// Put a node behind a new pointer.
+    #[doc(hidden)]
+    fn new<K, V>(node: TrieNode<K, V, Self>) -> Self::Child<K, V>;
+
+    /// Get a mutable reference to a child, which mustn't be shared.
+    #[doc(hidden)]
+    fn get_mut<K, V>(child: &mut Self::Child<K, V>) -> &mut TrieNode<K, V, Self>;
+
+    /// Get a mutable reference to a child, or None if it's shared.
+    #[doc(hidden)]
+    fn try_get_mut<K, V>(child: &mut Self::Child<K, V>) -> Option<&mut TrieNode<K, V, Self>>;
+
+    /// Move a child, which mustn't be shared, out of its pointer.
+    #[doc(hidden)]
+    fn into_node<K, V>(child: Self::Child<K, V>) -> TrieNode<K, V, Self>;
+}
+
+/// Children owned through a `Box`, by the nodes of every trie but a `PersistentTrie`.
+#[derive(Debug, Clone, Copy)]
+pub enum Owned {}
+
+/// Children owned through an `Arc`, by the nodes of a `PersistentTrie`.
+#[derive(Debug, Clone, Copy)]
+pub enum Shared {}
+
+impl sealed::Sealed for Owned {}
+impl sealed::Sealed for Shared {}
+
+impl ChildPtr for Owned {
+    type Child<K, V> = Box<TrieNode<K, V>>;
+
+    #[inline]
+    fn new<K, V>(node: TrieNode<K, V>) -> Box<TrieNode<K, V>> {
+        Box::new(node)
+    }
+
+    #[inline]
+    fn get_mut<K, V>(child: &mut Box<TrieNode<K, V>>) -> &mut TrieNode<K, V> {
+        child
+    }
+
+    #[inline]
+    fn try_get_mut<K, V>(child: &mut Box<TrieNode<K, V>>) -> Option<&mut TrieNode<K, V>> {
+        Some(child)
+    }
+
+    #[inline]
+    fn into_node<K, V>(child: Box<TrieNode<K, V>>) -> TrieNode<K, V> {
+        *child
+    }
+}
+
+/// The nodes of a `PersistentTrie` are only mutated or moved once `make_unique` has been called
+/// on them, or on their parents by `unshare_path`.
+impl ChildPtr for Shared {
+    type Child<K, V> = Arc<TrieNode<K, V, Shared>>;
+
+    #[inline]
+    fn new<K, V>(node: TrieNode<K, V, Shared>) -> Arc<TrieNode<K, V, Shared>> {
+        Arc::new(node)
+    }
+
+    #[inline]
+    fn get_mut<K, V>(child: &mut Arc<TrieNode<K, V, Shared>>) -> &mut TrieNode<K, V, Shared> {
+        Arc::get_mut(child).expect("shared trie node can't be mutated in place")
+    }
+
+    #[inline]
+    fn try_get_mut<K, V>(
+        child: &mut Arc<TrieNode<K, V, Shared>>,
+    ) -> Option<&mut TrieNode<K, V, Shared>> {
+        Arc::get_mut(child)
+    }
+
+    #[inline]
+    fn into_node<K, V>(child: Arc<TrieNode<K, V, Shared>>) -> TrieNode<K, V, Shared> {
+        Arc::try_unwrap(child).unwrap_or_else(|_| panic!("shared trie node can't be moved"))
+    }
+}
+
+pub type Child<K, V, P = Owned> = <P as ChildPtr>::Child<K, V>;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Get a mutable reference to a node of a `PersistentTrie`, replacing it with a copy first if
+/// it's shared.
+///
+/// Only the node itself is copied: its children are shared with the original.
+#[inline]
+pub fn make_unique<K, V>(child: &mut Arc<TrieNode<K, V, Shared>>) -> &mut TrieNode<K, V, Shared>
+where
+    K: Clone,
+    V: Clone,
+{
+    if Arc::get_mut(child).is_none() {
+        *child = Arc::new(child.shallow_clone());
+    }
+    Shared::get_mut(child)
+}
+
+/// The children of a trie node, indexed by bucket.
+///
+/// Like the node classes of an Adaptive Radix Tree, nodes with few children (which is most of
+/// them) store them in a list sorted by bucket, which takes no space at all for leaves. Nodes
+/// with many children store them in an array with a slot for every bucket: 16 buckets for a
+/// trie with nibble granularity, and 256 for a trie with byte granularity.
+pub enum ChildMap<K, V, P: ChildPtr> {
+    /// Up to `sparse_max` children, sorted by bucket.
+    Sparse(Vec<(u8, P::Child<K, V>)>),
+    /// More than `DENSE_SHRINK` children of a node with nibble granularity.
+    Dense(Box<DenseChildren<K, V, P, 16>>),
+    /// More than `WIDE_SHRINK` children of a node with byte granularity.
+    Wide(Box<DenseChildren<K, V, P, 256>>),
+}
+
+pub struct DenseChildren<K, V, P: ChildPtr, const N: usize> {
+    /// The number of slots which are Some rather than None.
+    len: usize,
+    slots: [Option<P::Child<K, V>>; N],
+}
+
+impl<K, V, P: ChildPtr, const N: usize> DenseChildren<K, V, P, N> {
+    /// Move the children of a sparse list into the slots for their buckets.
+    fn from_sparse(list: &mut Vec<(u8, Child<K, V, P>)>) -> Box<Self> {
+        let mut dense = Box::new(DenseChildren {
+            len: list.len(),
+            slots: std::array::from_fn(|_| None),
+        });
+        for (b, child) in list.drain(..) {
+            dense.slots[b as usize] = Some(child);
+        }
+        dense
+    }
+
+    /// Move the children out of the slots, into a sparse list.
+    fn take_sparse(&mut self) -> Vec<(u8, Child<K, V, P>)> {
+        self.slots
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(b, slot)| slot.take().map(|child| (b as u8, child)))
+            .collect()
+    }
+
+    fn insert(&mut self, bucket: usize, node: Child<K, V, P>) {
+        self.len += 1;
+        self.slots[bucket] = Some(node);
+    }
+
+    fn remove(&mut self, bucket: usize) -> Option<Child<K, V, P>> {
+        let child = self.slots.get_mut(bucket)?.take()?;
+        self.len -= 1;
+        Some(child)
+    }
+
+    fn range(&self, buckets: Range<usize>) -> Iter<'_, K, V, P> {
+        let start = buckets.start.min(N);
+        let end = buckets.end.clamp(start, N);
+        let filter: DenseFilter<'_, K, V, P> = |(b, slot)| slot.as_deref().map(|child| (b, child));
+        Iter::Dense(
+            (start..end)
+                .zip(self.slots[start..end].iter())
+                .filter_map(filter),
+        )
+    }
+
+    fn iter_mut(&mut self) -> IterMut<'_, K, V, P> {
+        let filter: DenseFilterMut<'_, K, V, P> =
+            |(b, slot)| slot.as_mut().map(|child| (b, P::get_mut(child)));
+        IterMut::Dense((0..N).zip(self.slots.iter_mut()).filter_map(filter))
+    }
+
+    fn is_consistent(&self) -> bool {
+        self.len == self.slots.iter().filter(|slot| slot.is_some()).count()
+    }
+}
+
+impl<K, V, P: ChildPtr> ChildMap<K, V, P> {
+    /// Create a map with no children.
+    #[inline]
+    pub fn new() -> ChildMap<K, V, P> {
+        ChildMap::Sparse(Vec::new())
+    }
+
+    /// Get the number of children.
+    #[inline]
+    pub fn len(&self) -> usize {
+        match *self {
+            ChildMap::Sparse(ref list) => list.len(),
+            ChildMap::Dense(ref dense) => dense.len,
+            ChildMap::Wide(ref wide) => wide.len,
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the child in the given bucket, if any.
+    #[inline]
+    pub fn get(&self, bucket: usize) -> Option<&TrieNode<K, V, P>> {
+        match *self {
+            ChildMap::Sparse(ref list) => {
+                let idx = sparse_position(list, bucket)?;
+                Some(&list[idx].1)
+            }
+            ChildMap::Dense(ref dense) => dense.slots.get(bucket)?.as_deref(),
+            ChildMap::Wide(ref wide) => wide.slots.get(bucket)?.as_deref(),
+        }
+    }
+
+    /// Get a mutable reference to the child in the given bucket, if any.
+    #[inline]
+    pub fn get_mut(&mut self, bucket: usize) -> Option<&mut TrieNode<K, V, P>> {
+        match *self {
+            ChildMap::Sparse(ref mut list) => {
+                let idx = sparse_position(list, bucket)?;
+                Some(P::get_mut(&mut list[idx].1))
+            }
+            ChildMap::Dense(ref mut dense) => dense.slots.get_mut(bucket)?.as_mut().map(P::get_mut),
+            ChildMap::Wide(ref mut wide) => wide.slots.get_mut(bucket)?.as_mut().map(P::get_mut),
+        }
+    }
+
+    /// Add a child in the given bucket, given that none exists there already.
+    ///
+    /// The granularity of the trie determines the layout that a full sparse list grows into.
+    #[inline]
+    pub fn insert(&mut self, bucket: usize, node: Child<K, V, P>, granularity: Granularity) {
+        debug_assert!(bucket < granularity.branch_factor());
+        debug_assert!(self.get(bucket).is_none());
+        match *self {
+            ChildMap::Sparse(ref mut list) if list.len() < sparse_max(granularity) => {
+                let idx = list.partition_point(|&(b, _)| (b as usize) < bucket);
+                list.insert(idx, (bucket as u8, node));
+            }
+            ChildMap::Sparse(ref mut list) => {
+                *self = match granularity {
+                    Granularity::Nibble => ChildMap::Dense(DenseChildren::from_sparse(list)),
+                    Granularity::Byte => ChildMap::Wide(DenseChildren::from_sparse(list)),
+                };
+                self.insert(bucket, node, granularity);
+            }
+            ChildMap::Dense(ref mut dense) => dense.insert(bucket, node),
+            ChildMap::Wide(ref mut wide) => wide.insert(bucket, node),
+        }
+    }
+
+    /// Remove the child in the given bucket, if it exists.
+    #[inline]
+    pub fn remove(&mut self, bucket: usize) -> Option<Child<K, V, P>> {
+        match *self {
+            ChildMap::Sparse(ref mut list) => {
+                let idx = sparse_position(list, bucket)?;
+                Some(list.remove(idx).1)
+            }
+            ChildMap::Dense(ref mut dense) => {
+                let child = dense.remove(bucket)?;
+                if dense.len <= DENSE_SHRINK {
+                    *self = ChildMap::Sparse(dense.take_sparse());
+                }
+                Some(child)
+            }
+            ChildMap::Wide(ref mut wide) => {
+                let child = wide.remove(bucket)?;
+                if wide.len <= WIDE_SHRINK {
+                    *self = ChildMap::Sparse(wide.take_sparse());
+                }
+                Some(child)
+            }
+        }
+    }
+
+    /// Create an empty map with the same layout as this one, for copying its children into
+    /// using `push`.
+    pub fn empty_like(&self) -> ChildMap<K, V, P> {
+        match *self {
+            ChildMap::Sparse(ref list) => ChildMap::Sparse(Vec::with_capacity(list.len())),
+            ChildMap::Dense(_) => ChildMap::Dense(DenseChildren::from_sparse(&mut vec![])),
+            ChildMap::Wide(_) => ChildMap::Wide(DenseChildren::from_sparse(&mut vec![])),
+        }
+    }
+
+    /// Add a child after all of the existing ones, without changing the layout of the map.
+    pub fn push(&mut self, bucket: usize, node: Child<K, V, P>) {
+        match *self {
+            ChildMap::Sparse(ref mut list) => {
+                debug_assert!(list.last().is_none_or(|&(b, _)| (b as usize) < bucket));
+                list.push((bucket as u8, node));
+            }
+            ChildMap::Dense(ref mut dense) => dense.insert(bucket, node),
+            ChildMap::Wide(ref mut wide) => wide.insert(bucket, node),
+        }
+    }
+
+    /// Iterate over the children in order of their buckets, along with their buckets.
+    #[inline]
+    pub fn iter(&self) -> Iter<'_, K, V, P> {
+        self.range(..)
+    }
+
+    /// Iterate over the children within a range of buckets, in order.
+    #[inline]
+    pub fn range<R>(&self, buckets: R) -> Iter<'_, K, V, P>
+    where
+        R: RangeBounds<usize>,
+    {
+        let start = match buckets.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match buckets.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => usize::MAX,
+        };
+        match *self {
+            ChildMap::Sparse(ref list) => {
+                let start = list.partition_point(|&(b, _)| (b as usize) < start);
+                let end = list
+                    .partition_point(|&(b, _)| (b as usize) < end)
+                    .max(start);
+                Iter::Sparse(list[start..end].iter())
+            }
+            ChildMap::Dense(ref dense) => dense.range(start..end),
+            ChildMap::Wide(ref wide) => wide.range(start..end),
+        }
+    }
+
+    /// Iterate mutably over the children in order of their buckets, along with their buckets.
+    #[inline]
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V, P> {
+        match *self {
+            ChildMap::Sparse(ref mut list) => IterMut::Sparse(list.iter_mut()),
+            ChildMap::Dense(ref mut dense) => dense.iter_mut(),
+            ChildMap::Wide(ref mut wide) => wide.iter_mut(),
+        }
+    }
+
+    /// Check that the recorded number of children is accurate, and that the children are
+    /// stored in the layout for their number and the granularity of the trie.
+    pub fn check_integrity(&self, granularity: Granularity) -> bool {
+        match (self, granularity) {
+            (ChildMap::Sparse(list), _) => {
+                list.len() <= sparse_max(granularity)
+                    && list.windows(2).all(|pair| pair[0].0 < pair[1].0)
+                    && list
+                        .iter()
+                        .all(|&(b, _)| (b as usize) < granularity.branch_factor())
+            }
+            (ChildMap::Dense(dense), Granularity::Nibble) => {
+                dense.len > DENSE_SHRINK && dense.is_consistent()
+            }
+            (ChildMap::Wide(wide), Granularity::Byte) => {
+                wide.len > WIDE_SHRINK && wide.is_consistent()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<K, V> ChildMap<K, V, Shared> {
+    /// Get a mutable reference to the child in the given bucket, if any, replacing it with a
+    /// copy first if it's shared (see `make_unique`).
+    #[inline]
+    pub fn make_unique(&mut self, bucket: usize) -> Option<&mut TrieNode<K, V, Shared>>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        match *self {
+            ChildMap::Sparse(ref mut list) => {
+                let idx = sparse_position(list, bucket)?;
+                Some(make_unique(&mut list[idx].1))
+            }
+            ChildMap::Dense(ref mut dense) => {
+                dense.slots.get_mut(bucket)?.as_mut().map(make_unique)
+            }
+            ChildMap::Wide(ref mut wide) => wide.slots.get_mut(bucket)?.as_mut().map(make_unique),
+        }
+    }
+}
+
+impl<K, V, P> fmt::Debug for ChildMap<K, V, P>
+where
+    K: fmt::Debug,
+    V: fmt::Debug,
+    P: ChildPtr + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// Copying the children of a shared node shares them with the original.
+impl<K, V> Clone for ChildMap<K, V, Shared> {
+    fn clone(&self) -> Self {
+        match *self {
+            ChildMap::Sparse(ref list) => ChildMap::Sparse(list.clone()),
+            ChildMap::Dense(ref dense) => ChildMap::Dense(Box::new(DenseChildren {
+                len: dense.len,
+                slots: dense.slots.clone(),
+            })),
+            ChildMap::Wide(ref wide) => ChildMap::Wide(Box::new(DenseChildren {
+                len: wide.len,
+                slots: wide.slots.clone(),
+            })),
+        }
+    }
+}
+
+/// Find the index of the child in the given bucket, within a sparse list.
+#[inline]
+fn sparse_position<C>(list: &[(u8, C)], bucket: usize) -> Option<usize> {
+    let bucket = u8::try_from(bucket).ok()?;
+    list.binary_search_by_key(&bucket, |&(b, _)| b).ok()
+}
+
+impl<K, V, P: ChildPtr> Default for ChildMap<K, V, P> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+type DenseFilter<'a, K, V, P> =
+    fn((usize, &'a Option<Child<K, V, P>>)) -> Option<(usize, &'a TrieNode<K, V, P>)>;
+type DenseFilterMut<'a, K, V, P> =
+    fn((usize, &'a mut Option<Child<K, V, P>>)) -> Option<(usize, &'a mut TrieNode<K, V, P>)>;
+type Slots<'a, K, V, P> = Zip<Range<usize>, slice::Iter<'a, Option<Child<K, V, P>>>>;
+type SlotsMut<'a, K, V, P> = Zip<Range<usize>, slice::IterMut<'a, Option<Child<K, V, P>>>>;
+
+/// Iterator over the children of a node and their buckets.
+pub enum Iter<'a, K: 'a, V: 'a, P: ChildPtr> {
+    Sparse(slice::Iter<'a, (u8, Child<K, V, P>)>),
+    Dense(FilterMap<Slots<'a, K, V, P>, DenseFilter<'a, K, V, P>>),
+}
+
+impl<'a, K, V, P: ChildPtr> Iterator for Iter<'a, K, V, P> {
+    type Item = (usize, &'a TrieNode<K, V, P>);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        match *self {
+            Iter::Sparse(ref mut iter) => iter.next().map(|(b, child)| (*b as usize, &**child)),
+            Iter::Dense(ref mut iter) => iter.next(),
+        }
+    }
+}
+
+impl<'a, K, V, P: ChildPtr> DoubleEndedIterator for Iter<'a, K, V, P> {
+    #[inline]
+    fn next_back(&mut self) -> Option<Self::Item> {
+        match *self {
+            Iter::Sparse(ref mut iter) => {
+                iter.next_back().map(|(b, child)| (*b as usize, &**child))
+            }
+            Iter::Dense(ref mut iter) => iter.next_back(),
+        }
+    }
+}
+
+/// Mutable iterator over the children of a node and their buckets.
+pub enum IterMut<'a, K: 'a, V: 'a, P: ChildPtr> {
+    Sparse(slice::IterMut<'a, (u8, Child<K, V, P>)>),
+    Dense(FilterMap<SlotsMut<'a, K, V, P>, DenseFilterMut<'a, K, V, P>>),
+}
+
+impl<'a, K, V, P: ChildPtr> Iterator for IterMut<'a, K, V, P> {
+    type Item = (usize, &'a mut TrieNode<K, V, P>);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        match *self {
+            IterMut::Sparse(ref mut iter) => iter
+                .next()
+                .map(|(b, child)| (*b as usize, P::get_mut(child))),
+            IterMut::Dense(ref mut iter) => iter.next(),
+        }
+    }
+}
+
+/// Owning iterator over the children of a node, in order of their buckets.
+pub enum IntoIter<K, V, P: ChildPtr> {
+    Sparse(vec::IntoIter<(u8, Child<K, V, P>)>),
+    Dense(Box<DenseChildren<K, V, P, 16>>, usize),
+    Wide(Box<DenseChildren<K, V, P, 256>>, usize),
+}
+
+impl<K, V, P: ChildPtr> Iterator for IntoIter<K, V, P> {
+    type Item = Child<K, V, P>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Child<K, V, P>> {
+        /// Take the child from the next non-empty slot, starting at `next`.
+        fn take_next<K, V, P: ChildPtr, const N: usize>(
+            dense: &mut DenseChildren<K, V, P, N>,
+            next: &mut usize,
+        ) -> Option<Child<K, V, P>> {
+            while *next < N {
+                *next += 1;
+                if let Some(child) = dense.slots[*next - 1].take() {
+                    return Some(child);
+                }
+            }
+            None
+        }
+
+        match *self {
+            IntoIter::Sparse(ref mut iter) => iter.next().map(|(_, child)| child),
+            IntoIter::Dense(ref mut dense, ref mut next) => take_next(dense, next),
+            IntoIter::Wide(ref mut wide, ref mut next) => take_next(wide, next),
+        }
+    }
+}
+
+impl<K, V, P: ChildPtr> IntoIterator for ChildMap<K, V, P> {
+    type Item = Child<K, V, P>;
+    type IntoIter = IntoIter<K, V, P>;
+
+    #[inline]
+    fn into_iter(self) -> IntoIter<K, V, P> {
+        match self {
+            ChildMap::Sparse(list) => IntoIter::Sparse(list.into_iter()),
+            ChildMap::Dense(dense) => IntoIter::Dense(dense, 0),
+            ChildMap::Wide(wide) => IntoIter::Wide(wide, 0),
+        }
+    }
+}
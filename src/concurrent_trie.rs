@@ -0,0 +1,288 @@
+//! Trie divided into independently locked shards.
+
+use crate::keys::{ByteNibbles, Nibbles, match_len};
+use crate::{ConcurrentTrie, Entry, Granularity, Trie, TrieCommon, TrieKey};
+use std::borrow::Borrow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// The number of shards.
+const SHARDS: usize = 64;
+
+/// The number of leading nibbles of a key which choose its shard: 16 bytes, or 32 bits of a key
+/// encoded bit by bit, such as the whole of an IPv4 address.
+///
+/// Keys this long or longer are in the shard for their first `SHARD_PREFIX` nibbles, and shorter
+/// keys are in the shard for their whole encoding. So finding the longest prefix of a key looks
+/// in the key's shard, and then in the shard of each shorter length at which keys are stored.
+const SHARD_PREFIX: usize = 32;
+
+impl<K, V> ConcurrentTrie<K, V>
+where
+    K: TrieKey,
+{
+    /// Create an empty ConcurrentTrie.
+    #[inline]
+    pub fn new() -> ConcurrentTrie<K, V> {
+        ConcurrentTrie::with_granularity(Granularity::Nibble)
+    }
+
+    /// Create an empty ConcurrentTrie, which branches on the given number of key bits at each
+    /// node.
+    ///
+    /// Panics if the keys are encoded bit by bit and `granularity` is `Granularity::Byte`.
+    pub fn with_granularity(granularity: Granularity) -> ConcurrentTrie<K, V> {
+        ConcurrentTrie {
+            granularity,
+            shards: (0..SHARDS)
+                .map(|_| RwLock::new(Trie::with_granularity(granularity)))
+                .collect(),
+            short_keys: (0..SHARD_PREFIX).map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+
+    /// Get the number of key bits that this trie branches on at each node.
+    #[inline]
+    pub fn granularity(&self) -> Granularity {
+        self.granularity
+    }
+
+    /// Get the number of key-value pairs in the trie.
+    ///
+    /// The shards are counted one at a time, so keys inserted or removed by other threads in
+    /// the meantime may or may not be counted.
+    pub fn len(&self) -> usize {
+        (0..SHARDS).map(|idx| self.read(idx).len()).sum()
+    }
+
+    /// Determine if the trie contains no key-value pairs, with the same caveat as `len`.
+    pub fn is_empty(&self) -> bool {
+        (0..SHARDS).all(|idx| self.read(idx).is_empty())
+    }
+
+    /// Fetch a clone of the given key's corresponding value, if any.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+        V: Clone,
+    {
+        match key.encode_borrowed() {
+            Some(bytes) => self.read_value(&ByteNibbles(bytes), key, |v| v.cloned()),
+            None => self.read_value(&key.encode(), key, |v| v.cloned()),
+        }
+    }
+
+    /// Determine if the trie contains the given key.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        match key.encode_borrowed() {
+            Some(bytes) => self.read_value(&ByteNibbles(bytes), key, |v| v.is_some()),
+            None => self.read_value(&key.encode(), key, |v| v.is_some()),
+        }
+    }
+
+    /// Insert the given key-value pair, returning any previous value associated with the key.
+    #[inline]
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        let nv = key.encode();
+        let count = self.short_keys.get(nv.len());
+        let mut shard = self.write(key_shard(&nv));
+
+        // A short key is counted before it's inserted, so that `longest_prefix_match` can't skip
+        // its length while it's in the trie.
+        if let Some(count) = count {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+        let previous = shard.insert_nv(key, value, nv);
+        if let Some(count) = count
+            && previous.is_some()
+        {
+            count.fetch_sub(1, Ordering::Relaxed);
+        }
+        previous
+    }
+
+    /// Remove the value associated with the given key.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        let nv = key.encode();
+        let removed = self.write(key_shard(&nv)).remove_nv(key, &nv);
+        if let Some(count) = self.short_keys.get(nv.len())
+            && removed.is_some()
+        {
+            count.fetch_sub(1, Ordering::Relaxed);
+        }
+        removed
+    }
+
+    /// Apply `f` to the value stored at `key`, or store `default` if there isn't one.
+    ///
+    /// The key's shard stays locked throughout, so no other thread can update the key in the
+    /// meantime.
+    #[inline]
+    pub fn map_with_default<F>(&self, key: K, f: F, default: V)
+    where
+        F: Fn(&mut V),
+    {
+        let nv = key.encode();
+        let count = self.short_keys.get(nv.len());
+        let mut shard = self.write(key_shard(&nv));
+        match shard.entry_nv(key, nv) {
+            Entry::Occupied(mut entry) => f(entry.get_mut()),
+            Entry::Vacant(entry) => {
+                if let Some(count) = count {
+                    count.fetch_add(1, Ordering::Relaxed);
+                }
+                entry.insert(default);
+            }
+        }
+    }
+
+    /// Find the longest key that is a prefix of the given key, along with its value and its
+    /// length, as `Trie::longest_prefix_match`.
+    ///
+    /// The shards holding the prefixes are searched one at a time, from the longest prefixes
+    /// to the shortest. So if other threads are updating the prefixes, the result is one of
+    /// the prefixes that were in the trie at some point during the search.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    pub fn longest_prefix_match<Q>(&self, key: &Q) -> Option<(K, V, usize)>
+    where
+        K: Borrow<Q> + Clone,
+        Q: ?Sized + TrieKey,
+        V: Clone,
+    {
+        let nv = key.encode();
+        let sharded_len = nv.len().min(SHARD_PREFIX);
+
+        // The key's own shard has all of its prefixes which are at least `sharded_len` long,
+        // and maybe some shorter ones too.
+        let mut longest = None;
+        let mut min_len = 0;
+        if let Some((node, depth)) = self
+            .read(key_shard(&nv))
+            .node
+            .get_ancestor(&nv, self.granularity)
+            && let Some(ref kv) = node.key_value
+        {
+            if depth >= sharded_len {
+                return Some((kv.key.clone(), kv.value.clone(), match_len::<K>(depth)));
+            }
+            longest = Some((kv.key.clone(), kv.value.clone(), match_len::<K>(depth)));
+            min_len = depth + 1;
+        }
+
+        // Each shorter prefix is in the shard for its whole encoding. Lengths at which no keys
+        // are stored are skipped.
+        let mut prefix = nv;
+        for len in (min_len..sharded_len).rev() {
+            if !self.granularity.is_aligned(len)
+                || self.short_keys[len].load(Ordering::Relaxed) == 0
+            {
+                continue;
+            }
+            prefix.split(len);
+            if let Some(node) = self
+                .read(key_shard(&prefix))
+                .node
+                .get(&prefix, self.granularity)
+                && let Some(ref kv) = node.key_value
+            {
+                return Some((kv.key.clone(), kv.value.clone(), match_len::<K>(len)));
+            }
+        }
+
+        longest
+    }
+
+    /// Move all the keys and values into an ordinary `Trie`.
+    pub fn into_trie(self) -> Trie<K, V> {
+        let mut trie = Trie::with_granularity(self.granularity);
+        for shard in self.shards {
+            let mut shard = shard.into_inner().unwrap();
+            trie.append(&mut shard);
+        }
+        trie
+    }
+
+    /// Check that the Trie invariants are satisfied - you shouldn't ever have to call this!
+    /// Quite slow!
+    #[doc(hidden)]
+    pub fn check_integrity(&self) -> bool {
+        let mut short_keys = vec![0; SHARD_PREFIX];
+        let shards_ok = (0..SHARDS).all(|idx| {
+            let shard = self.read(idx);
+            shard.check_integrity()
+                && shard.keys().all(|key| {
+                    let nv = key.encode();
+                    if let Some(count) = short_keys.get_mut(nv.len()) {
+                        *count += 1;
+                    }
+                    key_shard(&nv) == idx
+                })
+        });
+        shards_ok
+            && short_keys
+                .iter()
+                .zip(self.short_keys.iter())
+                .all(|(&count, recorded)| count == recorded.load(Ordering::Relaxed))
+    }
+
+    /// Look up a key in its shard, given its encoding, and apply `f` to its value while the
+    /// shard is locked.
+    fn read_value<N, Q, T>(&self, nv: &N, key: &Q, f: fn(Option<&V>) -> T) -> T
+    where
+        N: ?Sized + Nibbles,
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        f(self.read(key_shard(nv)).get_nv(nv, key))
+    }
+
+    fn read(&self, idx: usize) -> RwLockReadGuard<'_, Trie<K, V>> {
+        self.shards[idx].read().unwrap()
+    }
+
+    fn write(&self, idx: usize) -> RwLockWriteGuard<'_, Trie<K, V>> {
+        self.shards[idx].write().unwrap()
+    }
+}
+
+/// Get the shard for a key, given its encoding, by hashing up to `SHARD_PREFIX` of its leading
+/// nibbles (with FNV-1a).
+#[inline]
+fn key_shard<N: ?Sized + Nibbles>(nv: &N) -> usize {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for i in 0..nv.len().min(SHARD_PREFIX) {
+        hash ^= u64::from(nv.get(i));
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    (hash % SHARDS as u64) as usize
+}
+
+impl<K: TrieKey, V> Default for ConcurrentTrie<K, V> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,233 @@
+//! Entry API, for inspecting and modifying the value of a single key in place.
+
+use crate::keys::check_keys;
+use crate::traversal::KeyPosition::{self, *};
+use crate::traversal::{insert_below, remove_child_value};
+use crate::{Granularity, TrieKey, TrieNode};
+
+use nibble_vec::Nibblet;
+
+/// A view into a single key of a trie, which is either occupied or vacant.
+///
+/// Constructed by `Trie::entry` and `SubTrieMut::entry`.
+pub enum Entry<'a, K: 'a, V: 'a> {
+    /// The key is present in the trie.
+    Occupied(OccupiedEntry<'a, K, V>),
+    /// The key is absent from the trie.
+    Vacant(VacantEntry<'a, K, V>),
+}
+
+/// A view into an occupied entry of a trie.
+pub struct OccupiedEntry<'a, K: 'a, V: 'a> {
+    position: KeyPosition<'a, K, V>,
+    length: &'a mut usize,
+}
+
+/// A view into a vacant entry of a trie.
+pub struct VacantEntry<'a, K: 'a, V: 'a> {
+    position: KeyPosition<'a, K, V>,
+    key: K,
+    key_fragments: Nibblet,
+    granularity: Granularity,
+    length: &'a mut usize,
+}
+
+impl<'a, K, V> Entry<'a, K, V>
+where
+    K: TrieKey,
+{
+    /// Create an entry for `key`, which is located at `position` by its encoding `key_fragments`.
+    ///
+    /// Panics if the key found at `position` differs from `key` (see `check_keys`).
+    pub(crate) fn new(
+        mut position: KeyPosition<'a, K, V>,
+        key: K,
+        key_fragments: Nibblet,
+        granularity: Granularity,
+        length: &'a mut usize,
+    ) -> Entry<'a, K, V> {
+        let occupied = match node_mut(&mut position).and_then(|node| node.key()) {
+            Some(existing) => {
+                check_keys(existing, &key);
+                true
+            }
+            None => false,
+        };
+
+        if occupied {
+            Entry::Occupied(OccupiedEntry { position, length })
+        } else {
+            Entry::Vacant(VacantEntry {
+                position,
+                key,
+                key_fragments,
+                granularity,
+                length,
+            })
+        }
+    }
+
+    /// Get a reference to this entry's key.
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref entry) => entry.key(),
+            Entry::Vacant(ref entry) => entry.key(),
+        }
+    }
+
+    /// Insert `default` if the entry is vacant, and return a mutable reference to the value.
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    /// Insert the result of `default` if the entry is vacant, and return a mutable reference
+    /// to the value.
+    pub fn or_insert_with<F>(self, default: F) -> &'a mut V
+    where
+        F: FnOnce() -> V,
+    {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Apply `f` to the value if the entry is occupied, and return the entry.
+    pub fn and_modify<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut V),
+    {
+        if let Entry::Occupied(ref mut entry) = self {
+            f(entry.get_mut());
+        }
+        self
+    }
+}
+
+impl<'a, K, V> Entry<'a, K, V>
+where
+    K: TrieKey,
+    V: Default,
+{
+    /// Insert the default value if the entry is vacant, and return a mutable reference to
+    /// the value.
+    pub fn or_default(self) -> &'a mut V {
+        self.or_insert_with(Default::default)
+    }
+}
+
+impl<'a, K, V> OccupiedEntry<'a, K, V>
+where
+    K: TrieKey,
+{
+    /// Get a reference to the key stored in the trie.
+    pub fn key(&self) -> &K {
+        node(&self.position).key().unwrap()
+    }
+
+    /// Get a reference to the value.
+    pub fn get(&self) -> &V {
+        node(&self.position).value().unwrap()
+    }
+
+    /// Get a mutable reference to the value.
+    pub fn get_mut(&mut self) -> &mut V {
+        node_mut(&mut self.position)
+            .and_then(TrieNode::value_mut)
+            .unwrap()
+    }
+
+    /// Convert the entry into a mutable reference to its value, with the lifetime of the trie.
+    pub fn into_mut(self) -> &'a mut V {
+        into_node_mut(self.position)
+            .and_then(TrieNode::value_mut)
+            .unwrap()
+    }
+
+    /// Replace the value, returning the old one.
+    pub fn insert(&mut self, value: V) -> V {
+        ::std::mem::replace(self.get_mut(), value)
+    }
+
+    /// Remove the key and value from the trie, returning the value.
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    /// Remove the key and value from the trie, returning both.
+    pub fn remove_entry(self) -> (K, V) {
+        let key_value = match self.position {
+            Root(node) => node.key_value.take(),
+            Child(parent, bucket, parent_is_root) => {
+                remove_child_value(parent, bucket, parent_is_root)
+            }
+            Missing(..) => None,
+        }
+        .unwrap();
+        *self.length -= 1;
+        (key_value.key, key_value.value)
+    }
+}
+
+impl<'a, K, V> VacantEntry<'a, K, V>
+where
+    K: TrieKey,
+{
+    /// Get a reference to the key that would be used when inserting a value.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Take ownership of the key.
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Insert a value for this entry's key, and return a mutable reference to it.
+    pub fn insert(self, value: V) -> &'a mut V {
+        *self.length += 1;
+        match self.position {
+            Missing(parent, depth) => insert_below(
+                parent,
+                depth,
+                self.key_fragments,
+                self.key,
+                value,
+                self.granularity,
+            ),
+            position => {
+                let node = into_node_mut(position).unwrap();
+                node.add_key_value(self.key, value);
+                node.value_mut().unwrap()
+            }
+        }
+    }
+}
+
+/// Get the node for an existing key (a `Root` or `Child` position).
+fn node<'b, K, V>(position: &'b KeyPosition<'_, K, V>) -> &'b TrieNode<K, V> {
+    match *position {
+        Root(ref node) => node,
+        Child(ref parent, bucket, _) => parent.children.get(bucket).unwrap(),
+        Missing(..) => unreachable!("occupied entry without a node"),
+    }
+}
+
+fn node_mut<'b, K, V>(position: &'b mut KeyPosition<'_, K, V>) -> Option<&'b mut TrieNode<K, V>> {
+    match *position {
+        Root(ref mut node) => Some(node),
+        Child(ref mut parent, bucket, _) => parent.children.get_mut(bucket),
+        Missing(..) => None,
+    }
+}
+
+fn into_node_mut<'a, K, V>(position: KeyPosition<'a, K, V>) -> Option<&'a mut TrieNode<K, V>> {
+    match position {
+        Root(node) => Some(node),
+        Child(parent, bucket, _) => parent.children.get_mut(bucket),
+        Missing(..) => None,
+    }
+}
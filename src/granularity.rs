@@ -0,0 +1,81 @@
+//! The number of key bits that a trie branches on at each node.
+
+use crate::keys::{Nibbles, TrieKey};
+
+/// The number of key bits that a trie branches on at each node.
+///
+/// A trie with nibble granularity splits each byte of a key into two levels of 16-way branching,
+/// whereas a trie with byte granularity branches 256 ways on whole bytes. The byte granularity
+/// halves the depth of the trie, and the number of nodes visited by each operation, at the cost
+/// of sharing less of the keys which differ part way through a byte.
+///
+/// Both granularities store the same keys in the same order, and support the same operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Granularity {
+    /// Branch on 4 bits of the key at a time, with up to 16 children per node.
+    #[default]
+    Nibble,
+    /// Branch on 8 bits of the key at a time, with up to 256 children per node.
+    ///
+    /// The keys used with a byte-granular trie must encode to a whole number of bytes, as they
+    /// do unless `TrieKey::encode` is overridden. Operations on other keys may panic, and
+    /// creating a byte-granular trie of keys with `TrieKey::BITWISE` set panics.
+    Byte,
+}
+
+impl Granularity {
+    /// Get the number of buckets that the children of a node are stored in.
+    #[inline]
+    pub fn branch_factor(self) -> usize {
+        match self {
+            Granularity::Nibble => 16,
+            Granularity::Byte => 256,
+        }
+    }
+
+    /// Panic if keys of type `K` can't be stored at this granularity.
+    #[inline]
+    pub(crate) fn check_key<K>(self)
+    where
+        K: ?Sized + TrieKey,
+    {
+        assert!(
+            !(K::BITWISE && self == Granularity::Byte),
+            "keys encoded bit by bit can't be stored in a byte-granular trie"
+        );
+    }
+
+    /// Get the bucket of the child for the key `nv`, whose nibbles up to `depth` have been
+    /// matched already.
+    #[inline]
+    pub(crate) fn bucket<N>(self, nv: &N, depth: usize) -> usize
+    where
+        N: ?Sized + Nibbles,
+    {
+        match self {
+            Granularity::Nibble => nv.get(depth) as usize,
+            Granularity::Byte => {
+                assert!(
+                    depth + 1 < nv.len(),
+                    "keys of a byte-granular trie must encode to whole bytes"
+                );
+                ((nv.get(depth) << 4) | nv.get(depth + 1)) as usize
+            }
+        }
+    }
+
+    /// Round the length of a shared key prefix down to a point at which a node can be split.
+    #[inline]
+    pub(crate) fn align(self, len: usize) -> usize {
+        match self {
+            Granularity::Nibble => len,
+            Granularity::Byte => len & !1,
+        }
+    }
+
+    /// Determine if a node key of the given length can be stored at this granularity.
+    #[inline]
+    pub(crate) fn is_aligned(self, len: usize) -> bool {
+        self.align(len) == len
+    }
+}
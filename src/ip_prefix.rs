@@ -0,0 +1,138 @@
+//! Network prefixes, for use as the keys of routing tables.
+
+use crate::keys::{TrieKey, encode_bits};
+use nibble_vec::Nibblet;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// A network prefix, made up of an IP address and the number of leading bits of the address
+/// which belong to the prefix, like `10.0.0.0/13`.
+///
+/// A prefix is encoded as its leading bits, so in a trie keyed by prefixes, the ancestors of a
+/// prefix are the shorter prefixes containing it. `Trie::get_ancestor_value` therefore finds the
+/// value for the longest prefix which contains an address, as in a routing table, when it's
+/// given the prefix `IpPrefix::from(addr)` of the address on its own.
+///
+/// Prefixes are ordered in the same way as in a trie: by address, and then shortest first.
+///
+/// Prefix lengths need not be even, so tries keyed by prefixes must use the default
+/// `Granularity::Nibble`. As a tuple component, a prefix is encoded as the bytes of its address
+/// followed by its length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct IpPrefix<A> {
+    /// The address, with all bits after the prefix set to zero.
+    addr: A,
+    /// The number of bits in the prefix.
+    len: u8,
+}
+
+impl<A: Copy> IpPrefix<A> {
+    /// Get the address of this prefix, which has every bit after the prefix set to zero.
+    #[inline]
+    pub fn addr(&self) -> A {
+        self.addr
+    }
+
+    /// Get the number of bits in this prefix.
+    #[inline]
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+}
+
+impl<A: fmt::Display> fmt::Display for IpPrefix<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+impl<A: IpAddress> IpPrefix<A> {
+    /// Create the prefix made up of the first `len` bits of `addr`.
+    ///
+    /// The rest of the address is ignored, so e.g. `10.1.2.3/8` is the same as `10.0.0.0/8`.
+    /// Panics if `len` is greater than the number of bits in an address.
+    #[inline]
+    pub fn new(addr: A, len: u8) -> Self {
+        assert!(len <= A::BITS, "prefix is longer than the address");
+        IpPrefix {
+            addr: addr.masked(len),
+            len,
+        }
+    }
+
+    /// Determine whether an address is in the network of this prefix.
+    #[inline]
+    pub fn contains(&self, addr: A) -> bool {
+        addr.masked(self.len) == self.addr
+    }
+}
+
+impl<A: IpAddress> From<A> for IpPrefix<A> {
+    /// Get the prefix which contains only the given address.
+    #[inline]
+    fn from(addr: A) -> Self {
+        IpPrefix { addr, len: A::BITS }
+    }
+}
+
+impl<A: IpAddress> TrieKey for IpPrefix<A> {
+    const BITWISE: bool = true;
+
+    #[inline]
+    fn encode_bytes(&self) -> Vec<u8> {
+        let mut v = self.addr.encode_bytes();
+        v.push(self.len);
+        v
+    }
+
+    #[inline]
+    fn encode(&self) -> Nibblet {
+        self.addr.encode_prefix(self.len)
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// An IP address type, which can be the address of an `IpPrefix`.
+///
+/// This is implemented for `Ipv4Addr` and `Ipv6Addr`, and can't be implemented for other types.
+pub trait IpAddress: Copy + Eq + TrieKey + sealed::Sealed {
+    /// The number of bits in an address.
+    const BITS: u8;
+
+    /// Set every bit after the first `len` to zero.
+    #[doc(hidden)]
+    fn masked(self, len: u8) -> Self;
+
+    /// Encode the first `len` bits.
+    #[doc(hidden)]
+    fn encode_prefix(self, len: u8) -> Nibblet;
+}
+
+macro_rules! ip_address {
+    ($addr:ty, $bits:ty) => {
+        impl sealed::Sealed for $addr {}
+
+        impl IpAddress for $addr {
+            const BITS: u8 = <$bits>::BITS as u8;
+
+            #[inline]
+            fn masked(self, len: u8) -> Self {
+                let mask = <$bits>::MAX
+                    .checked_shl(u32::from(<Self as IpAddress>::BITS - len))
+                    .unwrap_or(0);
+                <$addr>::from_bits(self.to_bits() & mask)
+            }
+
+            #[inline]
+            fn encode_prefix(self, len: u8) -> Nibblet {
+                encode_bits(&self.octets(), usize::from(len))
+            }
+        }
+    };
+}
+
+ip_address!(Ipv4Addr, u32);
+ip_address!(Ipv6Addr, u128);
@@ -1,46 +1,312 @@
 //! Iterators over key-value pairs, keys, values and child subtries.
 
-use std::iter::{FilterMap, FromIterator, Map};
-use std::slice;
+use std::cmp::Ordering;
+use std::iter::{FromIterator, Map};
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
 
 use crate::TrieNode;
-use crate::{SubTrie, Trie, TrieKey};
+use crate::child_map::{self, ChildPtr, Owned, Shared};
+use crate::keys::compare_keys;
+use crate::path_trie::Unkeyed;
+use crate::{Granularity, PersistentTrie, SubTrie, Trie, TrieKey, TrieKeyDecode};
 
 use nibble_vec::Nibblet;
 
 // MY EYES.
-type Child<K, V> = Box<TrieNode<K, V>>;
-type RawChildIter<'a, K, V> = slice::Iter<'a, Option<Child<K, V>>>;
-type ChildMapFn<'a, K, V> = fn(&'a Option<Child<K, V>>) -> Option<&'a Child<K, V>>;
-type ChildIter<'a, K, V> = FilterMap<RawChildIter<'a, K, V>, ChildMapFn<'a, K, V>>;
+type RawChildIter<'a, K, V, P> = child_map::Iter<'a, K, V, P>;
+type ChildMapFn<'a, K, V, P> = fn((usize, &'a TrieNode<K, V, P>)) -> &'a TrieNode<K, V, P>;
+type ChildIter<'a, K, V, P> = Map<RawChildIter<'a, K, V, P>, ChildMapFn<'a, K, V, P>>;
+type NodeChildIter<'a, K, V, P> = (&'a TrieNode<K, V, P>, ChildIter<'a, K, V, P>);
+type RawChildIterMut<'a, K, V> = child_map::IterMut<'a, K, V, Owned>;
+type ChildMapMutFn<'a, K, V> = fn((usize, &'a mut TrieNode<K, V>)) -> &'a mut TrieNode<K, V>;
+type ChildIterMut<'a, K, V> = Map<RawChildIterMut<'a, K, V>, ChildMapMutFn<'a, K, V>>;
 
 /// Iterator over the keys and values of a Trie.
-pub struct Iter<'a, K: 'a, V: 'a> {
-    root: &'a TrieNode<K, V>,
+pub struct Iter<'a, K: 'a, V: 'a, P: ChildPtr = Owned> {
+    root: Option<&'a TrieNode<K, V, P>>,
     root_visited: bool,
-    stack: Vec<ChildIter<'a, K, V>>,
+    stack: Vec<ChildIter<'a, K, V, P>>,
+    // State for iterating from the back. Nodes are visited after all of their children.
+    back_started: bool,
+    back_stack: Vec<NodeChildIter<'a, K, V, P>>,
+    // The keys most recently yielded from the front and back, used to detect when the two
+    // ends meet.
+    front_last: Option<&'a K>,
+    back_last: Option<&'a K>,
+    finished: bool,
 }
 
-impl<'a, K, V> Iter<'a, K, V> {
+impl<'a, K, V, P: ChildPtr> Iter<'a, K, V, P> {
     // TODO: make this private somehow (and same for the other iterators).
-    pub fn new(root: &'a TrieNode<K, V>) -> Iter<'a, K, V> {
+    pub fn new(root: &'a TrieNode<K, V, P>) -> Iter<'a, K, V, P> {
+        Iter::with_stack(Some(root), false, vec![])
+    }
+
+    /// Create an iterator over no keys, e.g. for a subtrie whose keys have all been removed.
+    pub fn empty() -> Iter<'a, K, V, P> {
+        Iter::with_stack(None, false, vec![])
+    }
+
+    fn with_stack(
+        root: Option<&'a TrieNode<K, V, P>>,
+        root_visited: bool,
+        stack: Vec<ChildIter<'a, K, V, P>>,
+    ) -> Iter<'a, K, V, P> {
         Iter {
             root,
-            root_visited: false,
+            root_visited,
+            stack,
+            back_started: false,
+            back_stack: vec![],
+            front_last: None,
+            back_last: None,
+            finished: false,
+        }
+    }
+
+    /// Yield a key-value pair from the front, unless it has already been yielded from the back.
+    fn yield_front(&mut self, (key, value): (&'a K, &'a V)) -> Option<(&'a K, &'a V)> {
+        if self.back_last.is_some_and(|back| ptr::eq(back, key)) {
+            self.finished = true;
+            return None;
+        }
+        self.front_last = Some(key);
+        Some((key, value))
+    }
+
+    /// Yield a key-value pair from the back, unless it has already been yielded from the front.
+    fn yield_back(&mut self, (key, value): (&'a K, &'a V)) -> Option<(&'a K, &'a V)> {
+        if self.front_last.is_some_and(|front| ptr::eq(front, key)) {
+            self.finished = true;
+            return None;
+        }
+        self.back_last = Some(key);
+        Some((key, value))
+    }
+}
+
+/// Iterator over the keys and values of a Trie that lie within a range.
+pub struct Range<'a, K: 'a, V: 'a> {
+    iter: Iter<'a, K, V>,
+    last: Option<&'a K>,
+}
+
+impl<'a, K, V> Range<'a, K, V> {
+    /// Create an iterator that starts from the given path (see `TrieNode::seek_lower_bound`),
+    /// or from the root if no path is given, and stops once `last` has been yielded.
+    pub fn new(
+        root: &'a TrieNode<K, V>,
+        start: Option<Vec<(&'a TrieNode<K, V>, usize)>>,
+        last: Option<&'a K>,
+    ) -> Range<'a, K, V> {
+        let iter = match start {
+            Some(path) => Iter::with_stack(
+                Some(root),
+                true,
+                path.into_iter()
+                    .map(|(node, bucket)| node.child_iter_from(bucket))
+                    .collect(),
+            ),
+            None => Iter::new(root),
+        };
+        Range { iter, last }
+    }
+}
+
+impl<'a, K, V> Iterator for Range<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let last = self.last?;
+        let (key, value) = self.iter.next()?;
+        if ptr::eq(key, last) {
+            self.last = None;
+        }
+        Some((key, value))
+    }
+}
+
+/// Iterator over the keys and mutable values of a Trie.
+pub struct IterMut<'a, K: 'a, V: 'a> {
+    root: Option<&'a mut TrieNode<K, V>>,
+    stack: Vec<ChildIterMut<'a, K, V>>,
+}
+
+impl<'a, K, V> IterMut<'a, K, V> {
+    pub fn new(root: &'a mut TrieNode<K, V>) -> IterMut<'a, K, V> {
+        IterMut {
+            root: Some(root),
+            stack: vec![],
+        }
+    }
+
+    /// Create an iterator over no keys, e.g. for a subtrie whose keys have all been removed.
+    pub fn empty() -> IterMut<'a, K, V> {
+        IterMut {
+            root: None,
+            stack: vec![],
+        }
+    }
+
+    /// Push the children of a node onto the stack, and return its key and value (if any).
+    fn visit(&mut self, node: &'a mut TrieNode<K, V>) -> Option<(&'a K, &'a mut V)> {
+        fn child_node<K, V>((_, child): (usize, &mut TrieNode<K, V>)) -> &mut TrieNode<K, V> {
+            child
+        }
+
+        let TrieNode {
+            key_value,
+            children,
+            ..
+        } = node;
+        self.stack.push(children.iter_mut().map(child_node));
+        key_value.as_deref_mut().map(|kv| (&kv.key, &mut kv.value))
+    }
+}
+
+impl<'a, K, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Visit each node as it is reached from its parent (with special root handling).
+        if let Some(root) = self.root.take()
+            && let Some(kv) = self.visit(root)
+        {
+            return Some(kv);
+        }
+
+        loop {
+            let child = match self.stack.last_mut() {
+                Some(stack_top) => stack_top.next(),
+                None => return None,
+            };
+
+            match child {
+                Some(trie) => {
+                    if let Some(kv) = self.visit(trie) {
+                        return Some(kv);
+                    }
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+/// Owning iterator over the keys and values of a Trie.
+pub struct IntoIter<K, V> {
+    root: Option<TrieNode<K, V>>,
+    stack: Vec<child_map::IntoIter<K, V, Owned>>,
+    remaining: usize,
+}
+
+impl<K, V> IntoIter<K, V> {
+    pub fn new(trie: Trie<K, V>) -> IntoIter<K, V> {
+        IntoIter {
+            root: Some(trie.node),
             stack: vec![],
+            remaining: trie.length,
+        }
+    }
+
+    /// Push the children of a node onto the stack, and return its key and value (if any).
+    fn visit(&mut self, mut node: TrieNode<K, V>) -> Option<(K, V)> {
+        let children = mem::take(&mut node.children);
+        self.stack.push(children.into_iter());
+        node.key_value.take().map(|kv| (kv.key, kv.value))
+    }
+}
+
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Visit each node as it is reached from its parent (with special root handling).
+        if let Some(root) = self.root.take()
+            && let Some(kv) = self.visit(root)
+        {
+            self.remaining -= 1;
+            return Some(kv);
+        }
+
+        loop {
+            let child = match self.stack.last_mut() {
+                Some(stack_top) => stack_top.next(),
+                None => return None,
+            };
+
+            match child {
+                Some(trie) => {
+                    if let Some(kv) = self.visit(*trie) {
+                        self.remaining -= 1;
+                        return Some(kv);
+                    }
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V> ExactSizeIterator for IntoIter<K, V> {}
+
+/// Iterator over the keys and values of a Trie whose keys are prefixes of a given key.
+pub struct Ancestors<'a, K: 'a, V: 'a, P: ChildPtr = Owned> {
+    next_node: Option<(&'a TrieNode<K, V, P>, usize)>,
+    key: Nibblet,
+    granularity: Granularity,
+}
+
+impl<'a, K, V, P: ChildPtr> Ancestors<'a, K, V, P> {
+    pub fn new(
+        root: &'a TrieNode<K, V, P>,
+        key: Nibblet,
+        granularity: Granularity,
+    ) -> Ancestors<'a, K, V, P> {
+        Ancestors {
+            next_node: Some((root, 0)),
+            key,
+            granularity,
+        }
+    }
+}
+
+impl<'a, K, V, P: ChildPtr> Iterator for Ancestors<'a, K, V, P>
+where
+    K: TrieKey,
+{
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (node, depth) = self.next_node?;
+            self.next_node = node.next_ancestor(&self.key, depth, self.granularity);
+            if let Some(kv) = node.kv_as_pair() {
+                return Some(kv);
+            }
         }
     }
 }
 
 /// Iterator over the keys of a Trie.
-pub struct Keys<'a, K: 'a, V: 'a> {
-    inner: Map<Iter<'a, K, V>, KeyMapFn<'a, K, V>>,
+pub struct Keys<'a, K: 'a, V: 'a, P: ChildPtr = Owned> {
+    inner: Map<Iter<'a, K, V, P>, KeyMapFn<'a, K, V>>,
 }
 
 type KeyMapFn<'a, K, V> = fn((&'a K, &'a V)) -> &'a K;
 
-impl<'a, K, V> Keys<'a, K, V> {
-    pub fn new(iter: Iter<'a, K, V>) -> Keys<'a, K, V> {
+impl<'a, K, V, P: ChildPtr> Keys<'a, K, V, P> {
+    pub fn new(iter: Iter<'a, K, V, P>) -> Keys<'a, K, V, P> {
         fn first<'b, K, V>((k, _): (&'b K, &'b V)) -> &'b K {
             k
         }
@@ -50,7 +316,7 @@ impl<'a, K, V> Keys<'a, K, V> {
     }
 }
 
-impl<'a, K, V> Iterator for Keys<'a, K, V> {
+impl<'a, K, V, P: ChildPtr> Iterator for Keys<'a, K, V, P> {
     type Item = &'a K;
 
     fn next(&mut self) -> Option<&'a K> {
@@ -58,15 +324,21 @@ impl<'a, K, V> Iterator for Keys<'a, K, V> {
     }
 }
 
+impl<'a, K, V, P: ChildPtr> DoubleEndedIterator for Keys<'a, K, V, P> {
+    fn next_back(&mut self) -> Option<&'a K> {
+        self.inner.next_back()
+    }
+}
+
 /// Iterator over the values of a Trie.
-pub struct Values<'a, K: 'a, V: 'a> {
-    inner: Map<Iter<'a, K, V>, ValueMapFn<'a, K, V>>,
+pub struct Values<'a, K: 'a, V: 'a, P: ChildPtr = Owned> {
+    inner: Map<Iter<'a, K, V, P>, ValueMapFn<'a, K, V>>,
 }
 
 type ValueMapFn<'a, K, V> = fn((&'a K, &'a V)) -> &'a V;
 
-impl<'a, K, V> Values<'a, K, V> {
-    pub fn new(iter: Iter<'a, K, V>) -> Values<'a, K, V> {
+impl<'a, K, V, P: ChildPtr> Values<'a, K, V, P> {
+    pub fn new(iter: Iter<'a, K, V, P>) -> Values<'a, K, V, P> {
         fn second<'b, K, V>((_, v): (&'b K, &'b V)) -> &'b V {
             v
         }
@@ -76,7 +348,7 @@ impl<'a, K, V> Values<'a, K, V> {
     }
 }
 
-impl<'a, K, V> Iterator for Values<'a, K, V> {
+impl<'a, K, V, P: ChildPtr> Iterator for Values<'a, K, V, P> {
     type Item = &'a V;
 
     fn next(&mut self) -> Option<&'a V> {
@@ -84,40 +356,377 @@ impl<'a, K, V> Iterator for Values<'a, K, V> {
     }
 }
 
+impl<'a, K, V, P: ChildPtr> DoubleEndedIterator for Values<'a, K, V, P> {
+    fn next_back(&mut self) -> Option<&'a V> {
+        self.inner.next_back()
+    }
+}
+
+/// Iterator over the keys and values of a PathTrie, which decodes each key from its path.
+pub struct PathIter<'a, K, V: 'a> {
+    // The root of the iteration, which is visited first.
+    root: Option<&'a TrieNode<Unkeyed, V>>,
+    // Nodes still to be visited, each paired with the length of its parent's key.
+    stack: Vec<(&'a TrieNode<Unkeyed, V>, usize)>,
+    // The nibbles of the key of the node most recently visited.
+    path: Vec<u8>,
+    key_type: PhantomData<fn() -> K>,
+}
+
+impl<'a, K, V> PathIter<'a, K, V> {
+    pub fn new(root: &'a TrieNode<Unkeyed, V>, prefix: &Nibblet) -> PathIter<'a, K, V> {
+        PathIter {
+            root: Some(root),
+            stack: vec![],
+            path: (0..prefix.len()).map(|i| prefix.get(i)).collect(),
+            key_type: PhantomData,
+        }
+    }
+
+    pub fn empty() -> PathIter<'a, K, V> {
+        PathIter {
+            root: None,
+            stack: vec![],
+            path: vec![],
+            key_type: PhantomData,
+        }
+    }
+
+    /// Queue the children of a node, whose key is the current path, for visiting in order.
+    fn push_children(&mut self, node: &'a TrieNode<Unkeyed, V>) {
+        let depth = self.path.len();
+        self.stack
+            .extend(node.child_iter().rev().map(|child| (child, depth)));
+    }
+
+    /// Decode the key of the node most recently visited.
+    fn decode_path(&self) -> K
+    where
+        K: TrieKeyDecode,
+    {
+        let bytes: Vec<u8> = self
+            .path
+            .chunks(2)
+            .map(|pair| (pair[0] << 4) | pair.get(1).copied().unwrap_or(0))
+            .collect();
+        K::decode_bytes(&bytes)
+    }
+}
+
+impl<'a, K, V> Iterator for PathIter<'a, K, V>
+where
+    K: TrieKeyDecode,
+{
+    type Item = (K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root) = self.root.take() {
+            self.push_children(root);
+            if let Some(value) = root.value() {
+                return Some((self.decode_path(), value));
+            }
+        }
+
+        while let Some((node, depth)) = self.stack.pop() {
+            self.path.truncate(depth);
+            self.path
+                .extend((0..node.key.len()).map(|i| node.key.get(i)));
+            self.push_children(node);
+            if let Some(value) = node.value() {
+                return Some((self.decode_path(), value));
+            }
+        }
+        None
+    }
+}
+
+/// Iterator over the keys of a PathTrie.
+pub struct PathKeys<'a, K, V: 'a> {
+    inner: PathIter<'a, K, V>,
+}
+
+impl<'a, K, V> PathKeys<'a, K, V> {
+    pub fn new(iter: PathIter<'a, K, V>) -> PathKeys<'a, K, V> {
+        PathKeys { inner: iter }
+    }
+}
+
+impl<'a, K, V> Iterator for PathKeys<'a, K, V>
+where
+    K: TrieKeyDecode,
+{
+    type Item = K;
+
+    fn next(&mut self) -> Option<K> {
+        self.inner.next().map(|(k, _)| k)
+    }
+}
+
+/// Iterator over the values of a PathTrie.
+pub struct PathValues<'a, V: 'a> {
+    inner: Values<'a, Unkeyed, V>,
+}
+
+impl<'a, V> PathValues<'a, V> {
+    pub fn new(root: &'a TrieNode<Unkeyed, V>) -> PathValues<'a, V> {
+        PathValues {
+            inner: Values::new(Iter::new(root)),
+        }
+    }
+}
+
+impl<'a, V> Iterator for PathValues<'a, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.inner.next()
+    }
+}
+
+/// Iterator over the mutable values of a Trie.
+pub struct ValuesMut<'a, K: 'a, V: 'a> {
+    inner: Map<IterMut<'a, K, V>, ValueMutMapFn<'a, K, V>>,
+}
+
+type ValueMutMapFn<'a, K, V> = fn((&'a K, &'a mut V)) -> &'a mut V;
+
+impl<'a, K, V> ValuesMut<'a, K, V> {
+    pub fn new(iter: IterMut<'a, K, V>) -> ValuesMut<'a, K, V> {
+        fn second<'b, K, V>((_, v): (&'b K, &'b mut V)) -> &'b mut V {
+            v
+        }
+        ValuesMut {
+            inner: iter.map(second),
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for ValuesMut<'a, K, V> {
+    type Item = &'a mut V;
+
+    fn next(&mut self) -> Option<&'a mut V> {
+        self.inner.next()
+    }
+}
+
+/// Iterator over the keys of a TrieSet.
+pub struct SetIter<'a, K: 'a> {
+    inner: Option<Keys<'a, K, ()>>,
+}
+
+impl<'a, K> SetIter<'a, K> {
+    pub fn new(inner: Option<Keys<'a, K, ()>>) -> SetIter<'a, K> {
+        SetIter { inner }
+    }
+}
+
+impl<'a, K> Iterator for SetIter<'a, K> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        self.inner.as_mut()?.next()
+    }
+}
+
+impl<'a, K> DoubleEndedIterator for SetIter<'a, K> {
+    fn next_back(&mut self) -> Option<&'a K> {
+        self.inner.as_mut()?.next_back()
+    }
+}
+
+/// Iterator over the keys of a TrieSet, consuming the set.
+pub struct SetIntoIter<K> {
+    inner: IntoIter<K, ()>,
+}
+
+impl<K> SetIntoIter<K> {
+    pub fn new(inner: IntoIter<K, ()>) -> SetIntoIter<K> {
+        SetIntoIter { inner }
+    }
+}
+
+impl<K> Iterator for SetIntoIter<K> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<K> {
+        self.inner.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K> ExactSizeIterator for SetIntoIter<K> {}
+
+/// Iterator over the keys of two sets in order, which pairs up the keys in both sets.
+///
+/// Each key is encoded once, to compare it to the keys of the other set.
+struct MergeIter<'a, K: 'a> {
+    a: SetIter<'a, K>,
+    b: SetIter<'a, K>,
+    peeked_a: Option<(Nibblet, &'a K)>,
+    peeked_b: Option<(Nibblet, &'a K)>,
+}
+
+impl<'a, K> MergeIter<'a, K>
+where
+    K: TrieKey,
+{
+    fn new(a: SetIter<'a, K>, b: SetIter<'a, K>) -> MergeIter<'a, K> {
+        MergeIter {
+            a,
+            b,
+            peeked_a: None,
+            peeked_b: None,
+        }
+    }
+
+    /// Get the next key of either set, along with whether it is in the first set and whether
+    /// it is in the second.
+    fn next(&mut self) -> Option<(&'a K, bool, bool)> {
+        let a = self
+            .peeked_a
+            .take()
+            .or_else(|| self.a.next().map(|k| (k.encode(), k)));
+        let b = self
+            .peeked_b
+            .take()
+            .or_else(|| self.b.next().map(|k| (k.encode(), k)));
+
+        match (a, b) {
+            (None, None) => None,
+            (Some((_, a)), None) => Some((a, true, false)),
+            (None, Some((_, b))) => Some((b, false, true)),
+            (Some(a), Some(b)) => match compare_keys(&a.0, &b.0) {
+                Ordering::Less => {
+                    self.peeked_b = Some(b);
+                    Some((a.1, true, false))
+                }
+                Ordering::Greater => {
+                    self.peeked_a = Some(a);
+                    Some((b.1, false, true))
+                }
+                Ordering::Equal => Some((a.1, true, true)),
+            },
+        }
+    }
+}
+
+macro_rules! set_op_iter {
+    ($(#[$doc:meta])* $name:ident, |$in_a:ident, $in_b:ident| $include:expr) => {
+        $(#[$doc])*
+        pub struct $name<'a, K: 'a> {
+            inner: MergeIter<'a, K>,
+        }
+
+        impl<'a, K> $name<'a, K>
+        where
+            K: TrieKey,
+        {
+            pub fn new(a: SetIter<'a, K>, b: SetIter<'a, K>) -> $name<'a, K> {
+                $name {
+                    inner: MergeIter::new(a, b),
+                }
+            }
+        }
+
+        impl<'a, K> Iterator for $name<'a, K>
+        where
+            K: TrieKey,
+        {
+            type Item = &'a K;
+
+            fn next(&mut self) -> Option<&'a K> {
+                loop {
+                    let (key, $in_a, $in_b) = self.inner.next()?;
+                    if $include {
+                        return Some(key);
+                    }
+                }
+            }
+        }
+    };
+}
+
+set_op_iter!(
+    /// Iterator over the keys in either of two TrieSets.
+    Union,
+    |_in_a, _in_b| true
+);
+set_op_iter!(
+    /// Iterator over the keys in both of two TrieSets.
+    Intersection,
+    |in_a, in_b| in_a && in_b
+);
+set_op_iter!(
+    /// Iterator over the keys in one TrieSet but not another.
+    Difference,
+    |in_a, in_b| in_a && !in_b
+);
+set_op_iter!(
+    /// Iterator over the keys in exactly one of two TrieSets.
+    SymmetricDifference,
+    |in_a, in_b| in_a != in_b
+);
+
 /// Iterator over the child subtries of a trie.
-pub struct Children<'a, K: 'a, V: 'a> {
+pub struct Children<'a, K: 'a, V: 'a, P: ChildPtr = Owned> {
     prefix: Nibblet,
-    inner: ChildIter<'a, K, V>,
+    granularity: Granularity,
+    inner: ChildIter<'a, K, V, P>,
 }
 
-impl<'a, K, V> Children<'a, K, V> {
-    pub fn new(key: Nibblet, node: &'a TrieNode<K, V>) -> Self {
+impl<'a, K, V, P: ChildPtr> Children<'a, K, V, P> {
+    pub fn new(key: Nibblet, granularity: Granularity, node: &'a TrieNode<K, V, P>) -> Self {
         Children {
             prefix: key,
+            granularity,
             inner: node.child_iter(),
         }
     }
+
+    /// Create an iterator over no children, e.g. for a subtrie whose keys have all been removed.
+    pub fn empty(granularity: Granularity) -> Self {
+        Children {
+            prefix: Nibblet::new(),
+            granularity,
+            inner: child_map::Iter::Sparse([].iter()).map(child_node),
+        }
+    }
 }
 
-impl<'a, K, V> Iterator for Children<'a, K, V> {
-    type Item = SubTrie<'a, K, V>;
+impl<'a, K, V, P: ChildPtr> Iterator for Children<'a, K, V, P> {
+    type Item = SubTrie<'a, K, V, P>;
 
-    fn next(&mut self) -> Option<SubTrie<'a, K, V>> {
+    fn next(&mut self) -> Option<SubTrie<'a, K, V, P>> {
         self.inner.next().map(|node| SubTrie {
             prefix: self.prefix.clone().join(&node.key),
+            granularity: self.granularity,
             node,
         })
     }
 }
 
-impl<K, V> TrieNode<K, V> {
+impl<'a, K, V, P: ChildPtr> DoubleEndedIterator for Children<'a, K, V, P> {
+    fn next_back(&mut self) -> Option<SubTrie<'a, K, V, P>> {
+        self.inner.next_back().map(|node| SubTrie {
+            prefix: self.prefix.clone().join(&node.key),
+            granularity: self.granularity,
+            node,
+        })
+    }
+}
+
+impl<K, V, P: ChildPtr> TrieNode<K, V, P> {
     /// Helper function to get all the non-empty children of a node.
-    fn child_iter(&self) -> ChildIter<'_, K, V> {
-        fn id<K, V>(x: &Option<Child<K, V>>) -> Option<&Child<K, V>> {
-            x.as_ref()
-        }
+    fn child_iter(&self) -> ChildIter<'_, K, V, P> {
+        self.child_iter_from(0)
+    }
 
-        self.children.iter().filter_map(id)
+    /// Helper function to get the non-empty children of a node, from the given bucket onwards.
+    fn child_iter_from(&self, bucket: usize) -> ChildIter<'_, K, V, P> {
+        self.children.range(bucket..).map(child_node)
     }
 
     /// Get the key and value of a node as a pair.
@@ -126,23 +735,32 @@ impl<K, V> TrieNode<K, V> {
     }
 }
 
-enum IterAction<'a, K: 'a, V: 'a> {
-    Push(&'a TrieNode<K, V>),
+fn child_node<K, V, P: ChildPtr>((_, child): (usize, &TrieNode<K, V, P>)) -> &TrieNode<K, V, P> {
+    child
+}
+
+enum IterAction<'a, K: 'a, V: 'a, P: ChildPtr> {
+    Push(&'a TrieNode<K, V, P>),
     Pop,
 }
 
-impl<'a, K, V> Iterator for Iter<'a, K, V> {
+impl<'a, K, V, P: ChildPtr> Iterator for Iter<'a, K, V, P> {
     type Item = (&'a K, &'a V);
 
     fn next(&mut self) -> Option<Self::Item> {
         use self::IterAction::*;
 
+        if self.finished {
+            return None;
+        }
+
         // Visit each node as it is reached from its parent (with special root handling).
         if !self.root_visited {
             self.root_visited = true;
-            self.stack.push(self.root.child_iter());
-            if let Some(kv) = self.root.kv_as_pair() {
-                return Some(kv);
+            let root = self.root?;
+            self.stack.push(root.child_iter());
+            if let Some(kv) = root.kv_as_pair() {
+                return self.yield_front(kv);
             }
         }
 
@@ -159,7 +777,7 @@ impl<'a, K, V> Iterator for Iter<'a, K, V> {
                 Push(trie) => {
                     self.stack.push(trie.child_iter());
                     if let Some(kv) = trie.kv_as_pair() {
-                        return Some(kv);
+                        return self.yield_front(kv);
                     }
                 }
                 Pop => {
@@ -170,6 +788,45 @@ impl<'a, K, V> Iterator for Iter<'a, K, V> {
     }
 }
 
+impl<'a, K, V, P: ChildPtr> DoubleEndedIterator for Iter<'a, K, V, P> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        use self::IterAction::*;
+
+        if self.finished {
+            return None;
+        }
+
+        if !self.back_started {
+            self.back_started = true;
+            let root = self.root?;
+            self.back_stack.push((root, root.child_iter()));
+        }
+
+        // Visit each node once all of its children have been visited (in reverse order).
+        loop {
+            let action = match self.back_stack.last_mut() {
+                Some((_, stack_top)) => match stack_top.next_back() {
+                    Some(child) => Push(child),
+                    None => Pop,
+                },
+                None => return None,
+            };
+
+            match action {
+                Push(trie) => {
+                    self.back_stack.push((trie, trie.child_iter()));
+                }
+                Pop => {
+                    let (trie, _) = self.back_stack.pop().unwrap();
+                    if let Some(kv) = trie.kv_as_pair() {
+                        return self.yield_back(kv);
+                    }
+                }
+            }
+        }
+    }
+}
+
 impl<K, V> FromIterator<(K, V)> for Trie<K, V>
 where
     K: TrieKey,
@@ -185,3 +842,39 @@ where
         trie
     }
 }
+
+impl<K, V> IntoIterator for Trie<K, V> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter(self) -> IntoIter<K, V> {
+        IntoIter::new(self)
+    }
+}
+
+impl<'a, K, V> IntoIterator for &'a Trie<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        Iter::new(&self.node)
+    }
+}
+
+impl<'a, K, V> IntoIterator for &'a PersistentTrie<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V, Shared>;
+
+    fn into_iter(self) -> Iter<'a, K, V, Shared> {
+        Iter::new(&self.node)
+    }
+}
+
+impl<'a, K, V> IntoIterator for &'a mut Trie<K, V> {
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V>;
+
+    fn into_iter(self) -> IterMut<'a, K, V> {
+        IterMut::new(&mut self.node)
+    }
+}
@@ -1,5 +1,7 @@
 use endian_type::{BigEndian, LittleEndian};
 use nibble_vec::Nibblet;
+use std::cmp::Ordering;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::path::{Path, PathBuf};
 
 /// Trait for types which can be used to key a Radix Trie.
@@ -17,7 +19,31 @@ use std::path::{Path, PathBuf};
 ///
 /// Many standard types implement this trait already. Integer types are encoded *big-endian*
 /// by default but can be encoded little-endian using the `LittleEndian<T>` wrapper type.
+/// Signed integers have their sign bit flipped, so that the order of keys in the trie is
+/// their numeric order. Use the `BigEndian<T>` wrapper type to encode them without the flip.
+///
+/// Tuples of up to 8 keys are encoded so that no two tuples share an encoding, even when their
+/// components' encodings could be concatenated ambiguously, e.g. `("a", "bc")` and `("ab", "c")`.
+/// Tuples are ordered by their components, and a shorter tuple such as `(tenant,)` can be used
+/// as a prefix of longer ones like `(tenant, id)` by methods like `Trie::get_raw_descendant`.
+/// Tuple components are encoded with `encode_bytes`, even if they override `encode`.
+///
+/// Keys whose length is a number of bits rather than bytes, like the network prefix
+/// `10.0.0.0/13`, can implement `encode` using `encode_bits`, which gives each bit a nibble of
+/// its own, and set `BITWISE`. `Ipv4Addr`, `Ipv6Addr` and `IpPrefix` are encoded this way, so
+/// that a prefix's encoding is a prefix of the encodings of the addresses in it. They also
+/// implement `encode_bytes`, with the same order, for use as tuple components.
+///
+/// With the `derive` feature enabled, `#[derive(TrieKey)]` implements this trait for structs
+/// and enums, encoding their fields like the components of a tuple.
 pub trait TrieKey: PartialEq + Eq {
+    /// Whether `encode` gives each bit of the key a nibble of its own (see `encode_bits`).
+    ///
+    /// The encodings of such keys may end halfway through a byte, so they can only be stored
+    /// in tries with `Granularity::Nibble`, and creating a trie of them with `Granularity::Byte`
+    /// panics. Lengths of matches are also counted in bits rather than bytes.
+    const BITWISE: bool = false;
+
     /// Encode a value as a vector of bytes.
     fn encode_bytes(&self) -> Vec<u8> {
         panic!("implement this method or TrieKey::encode");
@@ -28,10 +54,96 @@ pub trait TrieKey: PartialEq + Eq {
     fn encode(&self) -> Nibblet {
         Nibblet::from_byte_vec(self.encode_bytes())
     }
+
+    /// Borrow the encoding of a value, if the value already stores it as bytes.
+    ///
+    /// Lookups use this to walk the trie without encoding the key, which avoids allocating.
+    /// If this returns `Some`, the bytes must be the same as those returned by `encode_bytes`,
+    /// and `encode` must not be overridden.
+    #[inline]
+    fn encode_borrowed(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// Trait for keys which can be rebuilt from their encoding.
+///
+/// This allows a `PathTrie` to store only the encoded keys, in the nibbles along the path to
+/// each value, rather than a copy of every key.
+///
+/// `decode_bytes` must be the inverse of `encode_bytes`, and it is only ever called on the output
+/// of `encode_bytes`, so it may panic on other input.
+pub trait TrieKeyDecode: TrieKey + Sized {
+    /// Decode a value from a vector of bytes produced by `encode_bytes`.
+    fn decode_bytes(bytes: &[u8]) -> Self;
+}
+
+/// A sequence of nibbles, which keys can be looked up by.
+///
+/// This is implemented by `Nibblet`, and by `ByteNibbles` for keys that borrow their encoding.
+pub trait Nibbles {
+    /// The number of nibbles.
+    fn len(&self) -> usize;
+
+    /// Get the nibble at the given index.
+    fn get(&self, idx: usize) -> u8;
+
+    /// Get the nibbles packed into bytes, with the high nibble of each byte first.
+    ///
+    /// If the length is odd, the low nibble of the last byte is unused.
+    fn as_bytes(&self) -> &[u8];
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Nibbles for Nibblet {
+    #[inline]
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    #[inline]
+    fn get(&self, idx: usize) -> u8 {
+        self.get(idx)
+    }
+
+    #[inline]
+    fn as_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+/// The nibbles of a borrowed byte slice, with the high nibble of each byte first.
+#[derive(Debug, Clone, Copy)]
+pub struct ByteNibbles<'a>(pub &'a [u8]);
+
+impl Nibbles for ByteNibbles<'_> {
+    #[inline]
+    fn len(&self) -> usize {
+        self.0.len() * 2
+    }
+
+    #[inline]
+    fn get(&self, idx: usize) -> u8 {
+        let byte = self.0[idx / 2];
+        if idx.is_multiple_of(2) {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        }
+    }
+
+    #[inline]
+    fn as_bytes(&self) -> &[u8] {
+        self.0
+    }
 }
 
 /// Key comparison result.
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum KeyMatch {
     /// The keys match up to the given index.
     Partial(usize),
@@ -46,12 +158,26 @@ pub enum KeyMatch {
 /// Compare two Trie keys.
 ///
 /// Compares `first[start_idx .. ]` to `second`, i.e. only looks at a slice of the first key.
+///
+/// If the slice starts on a byte boundary, whole bytes are compared, and nibbles are only
+/// compared at the end.
 #[inline]
-pub fn match_keys(start_idx: usize, first: &Nibblet, second: &Nibblet) -> KeyMatch {
+pub fn match_keys<N>(start_idx: usize, first: &N, second: &Nibblet) -> KeyMatch
+where
+    N: ?Sized + Nibbles,
+{
     let first_len = first.len() - start_idx;
     let min_length = ::std::cmp::min(first_len, second.len());
 
-    for i in 0..min_length {
+    let mut start = 0;
+    if start_idx.is_multiple_of(2) {
+        let num_bytes = min_length / 2;
+        let first_bytes = &first.as_bytes()[start_idx / 2..][..num_bytes];
+        let second_bytes = &second.as_bytes()[..num_bytes];
+        start = 2 * common_prefix_len(first_bytes, second_bytes);
+    }
+
+    for i in start..min_length {
         if first.get(start_idx + i) != second.get(i) {
             return KeyMatch::Partial(i);
         }
@@ -64,6 +190,77 @@ pub fn match_keys(start_idx: usize, first: &Nibblet, second: &Nibblet) -> KeyMat
     }
 }
 
+/// Find the length of the common prefix of two byte slices of the same length.
+///
+/// The slices are compared a machine word at a time, and then a byte at a time within the
+/// first word that differs.
+#[inline]
+fn common_prefix_len(first: &[u8], second: &[u8]) -> usize {
+    const WORD: usize = std::mem::size_of::<usize>();
+
+    let mut len = 0;
+    for (a, b) in first.chunks_exact(WORD).zip(second.chunks_exact(WORD)) {
+        let a = usize::from_ne_bytes(a.try_into().unwrap());
+        let b = usize::from_ne_bytes(b.try_into().unwrap());
+        if a != b {
+            break;
+        }
+        len += WORD;
+    }
+
+    len + first[len..]
+        .iter()
+        .zip(&second[len..])
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+/// Encode the first `len` bits of `bytes`, most significant bit first, with each bit in a nibble
+/// of its own.
+///
+/// Encoding the first `n` bits of a key in this way gives a prefix of the encoding of the
+/// whole key, for any `n`.
+///
+/// Panics if `len` is greater than the number of bits in `bytes`.
+pub fn encode_bits(bytes: &[u8], len: usize) -> Nibblet {
+    assert!(len <= bytes.len() * 8, "not enough bits to encode");
+    let bit = |i: usize| (bytes[i / 8] >> (7 - i % 8)) & 1;
+    let packed = (0..len)
+        .step_by(2)
+        .map(|i| (bit(i) << 4) | if i + 1 < len { bit(i + 1) } else { 0 })
+        .collect();
+
+    let mut nv = Nibblet::from_byte_vec(packed);
+    if !len.is_multiple_of(2) {
+        // Drop the padding nibble.
+        nv.split(len);
+    }
+    nv
+}
+
+/// Convert the length of a match against the encoding of a key of type `K` from nibbles into
+/// the units of the key: bits if `K` is encoded bit by bit, and otherwise whole bytes.
+#[inline]
+pub fn match_len<K>(nibbles: usize) -> usize
+where
+    K: ?Sized + TrieKey,
+{
+    if K::BITWISE { nibbles } else { nibbles / 2 }
+}
+
+/// Compare two Trie keys by their position in the trie.
+///
+/// This is the lexicographic order of their nibbles, with prefixes sorting first.
+#[inline]
+pub fn compare_keys(first: &Nibblet, second: &Nibblet) -> Ordering {
+    match match_keys(0, first, second) {
+        KeyMatch::Partial(i) => first.get(i).cmp(&second.get(i)),
+        KeyMatch::FirstPrefix => Ordering::Less,
+        KeyMatch::SecondPrefix => Ordering::Greater,
+        KeyMatch::Full => Ordering::Equal,
+    }
+}
+
 /// Check two keys for equality and panic if they differ.
 #[inline]
 pub fn check_keys<K: ?Sized>(key1: &K, key2: &K)
@@ -89,6 +286,11 @@ impl TrieKey for Vec<u8> {
     fn encode_bytes(&self) -> Vec<u8> {
         self.clone()
     }
+
+    #[inline]
+    fn encode_borrowed(&self) -> Option<&[u8]> {
+        Some(self)
+    }
 }
 
 impl TrieKey for [u8] {
@@ -96,6 +298,11 @@ impl TrieKey for [u8] {
     fn encode_bytes(&self) -> Vec<u8> {
         self.to_vec()
     }
+
+    #[inline]
+    fn encode_borrowed(&self) -> Option<&[u8]> {
+        Some(self)
+    }
 }
 
 impl TrieKey for String {
@@ -103,6 +310,11 @@ impl TrieKey for String {
     fn encode_bytes(&self) -> Vec<u8> {
         self.as_bytes().encode_bytes()
     }
+
+    #[inline]
+    fn encode_borrowed(&self) -> Option<&[u8]> {
+        Some(self.as_bytes())
+    }
 }
 
 impl TrieKey for str {
@@ -110,6 +322,11 @@ impl TrieKey for str {
     fn encode_bytes(&self) -> Vec<u8> {
         self.as_bytes().encode_bytes()
     }
+
+    #[inline]
+    fn encode_borrowed(&self) -> Option<&[u8]> {
+        Some(self.as_bytes())
+    }
 }
 
 impl<T: ?Sized + TrieKey> TrieKey for &T {
@@ -117,6 +334,16 @@ impl<T: ?Sized + TrieKey> TrieKey for &T {
     fn encode_bytes(&self) -> Vec<u8> {
         (**self).encode_bytes()
     }
+
+    #[inline]
+    fn encode(&self) -> Nibblet {
+        (**self).encode()
+    }
+
+    #[inline]
+    fn encode_borrowed(&self) -> Option<&[u8]> {
+        (**self).encode_borrowed()
+    }
 }
 
 impl<T: ?Sized + TrieKey> TrieKey for &mut T {
@@ -124,14 +351,15 @@ impl<T: ?Sized + TrieKey> TrieKey for &mut T {
     fn encode_bytes(&self) -> Vec<u8> {
         (**self).encode_bytes()
     }
-}
 
-impl TrieKey for i8 {
     #[inline]
-    fn encode_bytes(&self) -> Vec<u8> {
-        let mut v: Vec<u8> = Vec::with_capacity(1);
-        v.push(*self as u8);
-        v
+    fn encode(&self) -> Nibblet {
+        (**self).encode()
+    }
+
+    #[inline]
+    fn encode_borrowed(&self) -> Option<&[u8]> {
+        (**self).encode_borrowed()
     }
 }
 
@@ -142,6 +370,11 @@ impl TrieKey for u8 {
         v.push(*self);
         v
     }
+
+    #[inline]
+    fn encode_borrowed(&self) -> Option<&[u8]> {
+        Some(std::slice::from_ref(self))
+    }
 }
 
 #[cfg(unix)]
@@ -152,6 +385,10 @@ impl TrieKey for PathBuf {
         let str: OsString = self.clone().into();
         str.into_vec()
     }
+
+    fn encode_borrowed(&self) -> Option<&[u8]> {
+        self.as_path().encode_borrowed()
+    }
 }
 
 #[cfg(unix)]
@@ -160,6 +397,11 @@ impl TrieKey for Path {
         use std::os::unix::ffi::OsStrExt;
         self.as_os_str().as_bytes().encode_bytes()
     }
+
+    fn encode_borrowed(&self) -> Option<&[u8]> {
+        use std::os::unix::ffi::OsStrExt;
+        Some(self.as_os_str().as_bytes())
+    }
 }
 
 #[cfg(windows)]
@@ -167,6 +409,10 @@ impl TrieKey for PathBuf {
     fn encode_bytes(&self) -> Vec<u8> {
         self.as_os_str().as_encoded_bytes().to_vec()
     }
+
+    fn encode_borrowed(&self) -> Option<&[u8]> {
+        Some(self.as_os_str().as_encoded_bytes())
+    }
 }
 
 #[cfg(windows)]
@@ -174,6 +420,38 @@ impl TrieKey for Path {
     fn encode_bytes(&self) -> Vec<u8> {
         self.as_os_str().as_encoded_bytes().to_vec()
     }
+
+    fn encode_borrowed(&self) -> Option<&[u8]> {
+        Some(self.as_os_str().as_encoded_bytes())
+    }
+}
+
+impl TrieKey for Ipv4Addr {
+    const BITWISE: bool = true;
+
+    #[inline]
+    fn encode_bytes(&self) -> Vec<u8> {
+        self.octets().to_vec()
+    }
+
+    #[inline]
+    fn encode(&self) -> Nibblet {
+        encode_bits(&self.octets(), 32)
+    }
+}
+
+impl TrieKey for Ipv6Addr {
+    const BITWISE: bool = true;
+
+    #[inline]
+    fn encode_bytes(&self) -> Vec<u8> {
+        self.octets().to_vec()
+    }
+
+    #[inline]
+    fn encode(&self) -> Nibblet {
+        encode_bits(&self.octets(), 128)
+    }
 }
 
 impl<T> TrieKey for LittleEndian<T>
@@ -194,6 +472,36 @@ where
     }
 }
 
+impl TrieKeyDecode for Vec<u8> {
+    #[inline]
+    fn decode_bytes(bytes: &[u8]) -> Vec<u8> {
+        bytes.to_vec()
+    }
+}
+
+impl TrieKeyDecode for String {
+    #[inline]
+    fn decode_bytes(bytes: &[u8]) -> String {
+        String::from_utf8(bytes.to_vec()).expect("invalid UTF-8 in encoded string key")
+    }
+}
+
+impl TrieKeyDecode for u8 {
+    #[inline]
+    fn decode_bytes(bytes: &[u8]) -> u8 {
+        bytes[0]
+    }
+}
+
+#[cfg(unix)]
+impl TrieKeyDecode for PathBuf {
+    fn decode_bytes(bytes: &[u8]) -> PathBuf {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+        OsString::from_vec(bytes.to_vec()).into()
+    }
+}
+
 macro_rules! int_keys {
     ( $( $t:ty ),* ) => {
         $(
@@ -203,11 +511,44 @@ macro_rules! int_keys {
                 be.encode_bytes()
             }
         }
+
+        impl TrieKeyDecode for $t {
+            fn decode_bytes(bytes: &[u8]) -> $t {
+                <$t>::from_be_bytes(bytes.try_into().expect("wrong length for integer key"))
+            }
+        }
         )*
     };
 }
 
-int_keys!(u16, u32, u64, i16, i32, i64, usize, isize);
+int_keys!(u16, u32, u64, usize);
+
+// Flipping the sign bit of a two's complement integer maps its range onto the unsigned range in
+// order, so the big-endian bytes of the result sort numerically.
+macro_rules! signed_int_keys {
+    ( $( $t:ty ),* ) => {
+        $(
+        impl TrieKey for $t {
+            fn encode_bytes(&self) -> Vec<u8> {
+                let mut v = self.to_be_bytes().to_vec();
+                v[0] ^= 0x80;
+                v
+            }
+        }
+
+        impl TrieKeyDecode for $t {
+            fn decode_bytes(bytes: &[u8]) -> $t {
+                let mut be: [u8; std::mem::size_of::<$t>()] =
+                    bytes.try_into().expect("wrong length for integer key");
+                be[0] ^= 0x80;
+                <$t>::from_be_bytes(be)
+            }
+        }
+        )*
+    };
+}
+
+signed_int_keys!(i8, i16, i32, i64, isize);
 
 macro_rules! vec_int_keys {
   ( $( $t:ty ),* ) => {
@@ -221,11 +562,135 @@ macro_rules! vec_int_keys {
               v
           }
       }
+
+      impl TrieKeyDecode for Vec<$t> {
+          fn decode_bytes(bytes: &[u8]) -> Vec<$t> {
+              bytes
+                  .chunks_exact(std::mem::size_of::<$t>())
+                  .map(<$t>::decode_bytes)
+                  .collect()
+          }
+      }
       )*
    };
 }
 
-vec_int_keys!(u16, u32, u64, i16, i32, i64, usize, isize);
+vec_int_keys!(u16, u32, u64, usize);
+
+macro_rules! vec_signed_int_keys {
+  ( $( $t:ty ),* ) => {
+      $(
+      impl TrieKey for Vec<$t> {
+          fn encode_bytes(&self) -> Vec<u8> {
+              let mut v = Vec::<u8>::with_capacity(self.len() * std::mem::size_of::<$t>());
+              for i in self {
+                  let mut bytes = i.to_be_bytes();
+                  bytes[0] ^= 0x80;
+                  v.extend_from_slice(&bytes);
+              }
+              v
+          }
+      }
+
+      impl TrieKeyDecode for Vec<$t> {
+          fn decode_bytes(bytes: &[u8]) -> Vec<$t> {
+              bytes
+                  .chunks_exact(std::mem::size_of::<$t>())
+                  .map(<$t>::decode_bytes)
+                  .collect()
+          }
+      }
+      )*
+   };
+}
+
+vec_signed_int_keys!(i16, i32, i64, isize);
+
+/// Append the encoding of a tuple component to `v`.
+///
+/// Zero bytes are escaped as `0x00 0xFF`, and the component is terminated by `0x00 0x01`,
+/// which sorts before any continuation of the component. This makes the encodings of the
+/// components prefix-free, and preserves their order.
+#[inline]
+pub fn encode_component<T>(component: &T, v: &mut Vec<u8>)
+where
+    T: ?Sized + TrieKey,
+{
+    for byte in component.encode_bytes() {
+        v.push(byte);
+        if byte == 0x00 {
+            v.push(0xFF);
+        }
+    }
+    v.extend_from_slice(&[0x00, 0x01]);
+}
+
+/// Decode the tuple component at the start of `bytes`, and advance `bytes` past it.
+///
+/// This is the inverse of `encode_component`.
+#[inline]
+fn decode_component<T>(bytes: &mut &[u8]) -> T
+where
+    T: TrieKeyDecode,
+{
+    let mut component = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    loop {
+        match (bytes[i], bytes[i + 1]) {
+            (0x00, 0x01) => break,
+            (0x00, _) => {
+                component.push(0x00);
+                i += 2;
+            }
+            (byte, _) => {
+                component.push(byte);
+                i += 1;
+            }
+        }
+    }
+    *bytes = &bytes[i + 2..];
+    T::decode_bytes(&component)
+}
+
+// Tuples are encoded component by component (see `encode_component`), so that distinct tuples
+// never share an encoding, tuples sort by their components in order, and the encoding of a
+// tuple is a prefix of the encodings of any longer tuples that start with the same components.
+macro_rules! tuple_keys {
+    ( $( ( $( $t:ident $idx:tt ),+ ) )+ ) => {
+        $(
+        impl<$( $t ),+> TrieKey for ( $( $t, )+ )
+        where
+            $( $t: TrieKey ),+
+        {
+            fn encode_bytes(&self) -> Vec<u8> {
+                let mut v = Vec::new();
+                $( encode_component(&self.$idx, &mut v); )+
+                v
+            }
+        }
+
+        impl<$( $t ),+> TrieKeyDecode for ( $( $t, )+ )
+        where
+            $( $t: TrieKeyDecode ),+
+        {
+            fn decode_bytes(mut bytes: &[u8]) -> Self {
+                ( $( decode_component::<$t>(&mut bytes), )+ )
+            }
+        }
+        )+
+    };
+}
+
+tuple_keys! {
+    (A 0)
+    (A 0, B 1)
+    (A 0, B 1, C 2)
+    (A 0, B 1, C 2, D 3)
+    (A 0, B 1, C 2, D 3, E 4)
+    (A 0, B 1, C 2, D 3, E 4, F 5)
+    (A 0, B 1, C 2, D 3, E 4, F 5, G 6)
+    (A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7)
+}
 
 #[cfg(test)]
 mod test {
@@ -11,17 +11,33 @@ extern crate quickcheck;
 #[cfg(test)]
 extern crate rand;
 
-pub use keys::TrieKey;
+pub use child_map::{ChildPtr, Owned, Shared};
+pub use entry::{Entry, OccupiedEntry, VacantEntry};
+pub use granularity::Granularity;
+pub use ip_prefix::{IpAddress, IpPrefix};
+pub use keys::{TrieKey, TrieKeyDecode, encode_bits};
 pub use nibble_vec::NibbleVec;
+#[cfg(feature = "derive")]
+pub use radix_trie_derive::TrieKey;
 pub use trie_common::TrieCommon;
 use trie_node::TrieNode;
 
 use nibble_vec::Nibblet;
+use std::marker::PhantomData;
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, RwLock};
 
 #[macro_use]
 mod macros;
+mod child_map;
+mod concurrent_trie;
+mod entry;
+mod granularity;
+mod ip_prefix;
 pub mod iter;
 mod keys;
+mod path_trie;
+mod persistent_trie;
 #[cfg(feature = "serde")]
 mod serde;
 mod subtrie;
@@ -29,14 +45,19 @@ mod traversal;
 mod trie;
 mod trie_common;
 mod trie_node;
+mod trie_set;
+
+// Used by the code generated by `#[derive(TrieKey)]`.
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::keys::encode_component;
+}
 
 #[cfg(test)]
 mod qc_test;
 #[cfg(test)]
 mod test;
 
-const BRANCH_FACTOR: usize = 16;
-
 /// Data-structure for storing and querying string-like keys and associated values.
 ///
 /// Any keys which share a common *prefix* are stored below a single copy of that prefix.
@@ -47,28 +68,110 @@ const BRANCH_FACTOR: usize = 16;
 /// Lots of the methods on `Trie` return optional values - they can be composed
 /// nicely using `Option::and_then`.
 ///
+/// By default each node branches on a nibble (4 bits) of the key. A trie which branches on
+/// whole bytes instead can be created with `Trie::with_granularity`, see `Granularity`.
+///
 /// [radix-wiki]: http://en.wikipedia.org/wiki/Radix_tree
 #[derive(Debug, Clone)]
 pub struct Trie<K, V> {
     /// The number of values stored in this sub-trie (this node and all descendants).
     length: usize,
+    /// The number of key bits that each node branches on.
+    granularity: Granularity,
     /// The main content of this trie.
     node: TrieNode<K, V>,
 }
 
+/// Trie which stores only the encoded form of each key, in the nibbles along its path.
+///
+/// Keys are rebuilt using `TrieKeyDecode` when they are needed, e.g. by the iterators. This
+/// saves storing a copy of every key, at the cost of decoding keys on the way out.
+///
+/// Keys are encoded using `TrieKey::encode_bytes`.
+#[derive(Debug, Clone)]
+pub struct PathTrie<K, V> {
+    /// The number of values stored in this trie.
+    length: usize,
+    /// The main content of this trie, with placeholders for the keys.
+    node: TrieNode<path_trie::Unkeyed, V>,
+    key_type: PhantomData<fn() -> K>,
+}
+
+/// Trie whose versions share their nodes, so that old versions stay available after updates.
+///
+/// `insert` and `remove` leave the trie unchanged, and return a new version of it. Only the
+/// nodes on the path to the key are copied, and the rest are shared with the old version, so
+/// each version takes memory in proportion to the length of the key rather than the size of
+/// the trie. Cloning a version copies no nodes at all.
+///
+/// Versions are queried in the same way as a `Trie`, including through `TrieCommon`. Their
+/// subtries and iterators take `Shared` as their last type parameter, rather than the default
+/// `Owned` of other tries (see `ChildPtr`).
+///
+/// A trie can also be updated in place with `insert_mut` and `remove_mut`, which copy only the
+/// nodes still shared with other versions. Together with `snapshot`, this lets a writer keep
+/// updating a trie while readers on other threads query snapshots of it, without any locking.
+#[derive(Debug)]
+pub struct PersistentTrie<K, V> {
+    /// The number of values stored in this version.
+    length: usize,
+    /// The number of key bits that each node branches on.
+    granularity: Granularity,
+    /// The root node, which may be shared with other versions.
+    node: Arc<TrieNode<K, V, Shared>>,
+}
+
+/// Trie which can be updated and queried from many threads at once.
+///
+/// The keys are divided between a fixed number of shards, each of which is a `Trie` behind its
+/// own lock, so threads only wait for each other when they use keys in the same shard. A key's
+/// shard is chosen by the first 16 bytes of its encoding (or 32 bits, for keys encoded bit by
+/// bit), so that the prefixes of a key which are longer than that are all in the same shard as
+/// the key. Keys which share a shorter prefix, like URLs on the same scheme or the addresses in
+/// a network, are still spread between the shards.
+///
+/// Values are cloned out of the trie rather than borrowed, as a borrow would hold its shard's
+/// lock. `into_trie` gives back an ordinary `Trie`, e.g. once ingestion is done.
+#[derive(Debug)]
+pub struct ConcurrentTrie<K, V> {
+    /// The number of key bits that each node branches on.
+    granularity: Granularity,
+    /// The shards, which together hold every key.
+    shards: Box<[RwLock<Trie<K, V>>]>,
+    /// The number of keys of each encoded length which is too short to choose a shard by its
+    /// prefix, so that `longest_prefix_match` only searches for prefixes of lengths in use.
+    short_keys: Box<[AtomicUsize]>,
+}
+
+/// Set of keys, stored in a `Trie`.
+///
+/// Like a `Trie`, the set can find the keys starting with a given prefix, and the keys which
+/// are prefixes of a given key. Keys are visited in the same order as the keys of a `Trie`,
+/// including by the iterators over unions, intersections and differences of sets.
+#[derive(Debug, Clone)]
+pub struct TrieSet<K> {
+    trie: Trie<K, ()>,
+}
+
 /// Immutable view of a sub-tree a larger trie.
 #[derive(Debug)]
-pub struct SubTrie<'a, K: 'a, V: 'a> {
+pub struct SubTrie<'a, K: 'a, V: 'a, P: ChildPtr = Owned> {
     prefix: Nibblet,
-    node: &'a TrieNode<K, V>,
+    granularity: Granularity,
+    node: &'a TrieNode<K, V, P>,
 }
 
 /// Mutable view of a sub-tree of a larger trie.
+///
+/// Keys are inserted and removed through the root of the whole trie, so that nodes are split,
+/// merged and removed exactly as they are by the trie's own methods. Removing every key of the
+/// subtrie leaves it empty, without a node.
 #[derive(Debug)]
 pub struct SubTrieMut<'a, K: 'a, V: 'a> {
     prefix: Nibblet,
+    granularity: Granularity,
     length: &'a mut usize,
-    node: &'a mut TrieNode<K, V>,
+    root: &'a mut TrieNode<K, V>,
 }
 
 /// Wrapper for subtrie lookup results.
@@ -0,0 +1,302 @@
+//! Trie which rebuilds its keys from their encodings, rather than storing them.
+
+use crate::iter::{PathIter, PathKeys, PathValues};
+use crate::keys::ByteNibbles;
+use crate::traversal::DescendantResult::*;
+use crate::traversal::KeyPosition::*;
+use crate::traversal::remove_child_value;
+use crate::{Granularity, PathTrie, TrieKey, TrieKeyDecode, TrieNode};
+use std::borrow::Borrow;
+use std::iter::FromIterator;
+use std::marker::PhantomData;
+
+use nibble_vec::Nibblet;
+
+/// Placeholder stored in place of the keys of a `PathTrie`.
+///
+/// All placeholders are equal, and they are never encoded, as the traversals only look at the
+/// key fragments along the path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unkeyed;
+
+impl TrieKey for Unkeyed {}
+
+/// The granularity of every PathTrie.
+const GRANULARITY: Granularity = Granularity::Nibble;
+
+impl<K, V> PathTrie<K, V>
+where
+    K: TrieKeyDecode,
+{
+    /// Create an empty PathTrie.
+    #[inline]
+    pub fn new() -> PathTrie<K, V> {
+        PathTrie {
+            length: 0,
+            node: TrieNode::new(),
+            key_type: PhantomData,
+        }
+    }
+
+    /// Fetch the number of key-value pairs in the trie.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Determine if the trie contains no key-value pairs.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Fetch a reference to the given key's corresponding value, if any.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        let node = match key.encode_borrowed() {
+            Some(bytes) => self.node.get(&ByteNibbles(bytes), GRANULARITY),
+            None => self.node.get(&encode(key), GRANULARITY),
+        };
+        node.and_then(TrieNode::value)
+    }
+
+    /// Fetch a mutable reference to the given key's corresponding value, if any.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        let node = match key.encode_borrowed() {
+            Some(bytes) => self.node.get_mut(&ByteNibbles(bytes), GRANULARITY),
+            None => self.node.get_mut(&encode(key), GRANULARITY),
+        };
+        node.and_then(TrieNode::value_mut)
+    }
+
+    /// Determine if the trie contains a value for the given key.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        self.get(key).is_some()
+    }
+
+    /// Insert the given key-value pair, returning any previous value associated with the key.
+    ///
+    /// Only the encoding of the key is stored, so the key itself is dropped.
+    #[inline]
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let result = self.node.insert(Unkeyed, value, encode(&key), GRANULARITY);
+        if result.is_none() {
+            self.length += 1;
+        }
+        result
+    }
+
+    /// Remove the value associated with the given key.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        let removed = match self.node.find_position(&encode(key), GRANULARITY) {
+            Root(node) => node.key_value.take(),
+            Child(parent, bucket, parent_is_root) => {
+                if parent.children.get(bucket).unwrap().key_value.is_some() {
+                    remove_child_value(parent, bucket, parent_is_root)
+                } else {
+                    None
+                }
+            }
+            Missing(..) => None,
+        };
+        removed.map(|key_value| {
+            self.length -= 1;
+            key_value.value
+        })
+    }
+
+    /// Find the longest key that is a prefix of the given key, and return it with its value.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn get_ancestor<Q>(&self, key: &Q) -> Option<(K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        let mut key_fragments = encode(key);
+        self.node
+            .get_ancestor(&key_fragments, GRANULARITY)
+            .map(|(node, node_key_len)| {
+                key_fragments.split(node_key_len);
+                (
+                    K::decode_bytes(key_fragments.as_bytes()),
+                    node.value().unwrap(),
+                )
+            })
+    }
+
+    /// Fetch the value of the longest key that is a prefix of the given key.
+    ///
+    /// Unlike `get_ancestor` this doesn't need to decode the key.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn get_ancestor_value<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        let ancestor = match key.encode_borrowed() {
+            Some(bytes) => self.node.get_ancestor(&ByteNibbles(bytes), GRANULARITY),
+            None => self.node.get_ancestor(&encode(key), GRANULARITY),
+        };
+        ancestor.and_then(|(node, _)| node.value())
+    }
+
+    /// Return an iterator over the keys and values of the trie, in the same order as
+    /// `Trie::iter`.
+    #[inline]
+    pub fn iter(&self) -> PathIter<'_, K, V> {
+        PathIter::new(&self.node, &Nibblet::new())
+    }
+
+    /// Return an iterator over the keys of the trie, in the same order as `Trie::keys`.
+    #[inline]
+    pub fn keys(&self) -> PathKeys<'_, K, V> {
+        PathKeys::new(self.iter())
+    }
+
+    /// Return an iterator over the values of the trie, in the same order as `Trie::values`.
+    ///
+    /// No keys are decoded.
+    #[inline]
+    pub fn values(&self) -> PathValues<'_, V> {
+        PathValues::new(&self.node)
+    }
+
+    /// Return an iterator over the keys and values which start with the given prefix.
+    ///
+    /// The prefix is matched against the encoded keys, so it needn't be a key itself, or even
+    /// have the same type as the keys.
+    pub fn iter_prefix<Q>(&self, prefix: &Q) -> PathIter<'_, K, V>
+    where
+        Q: ?Sized + TrieKey,
+    {
+        let mut nv = encode(prefix);
+        match self.node.get_raw_descendant(&nv, GRANULARITY) {
+            Some(NoModification(node)) => PathIter::new(node, &nv),
+            Some(ExtendKey(node, depth, extension)) => {
+                nv.split(depth);
+                PathIter::new(node, &nv.join(extension))
+            }
+            None => PathIter::empty(),
+        }
+    }
+
+    /// Check that the PathTrie invariants are satisfied - you shouldn't ever have to call this!
+    /// Quite slow!
+    #[doc(hidden)]
+    pub fn check_integrity(&self) -> bool {
+        let (ok, length) =
+            self.node
+                .check_integrity_with(&Nibblet::new(), GRANULARITY, &|_, key_fragments| {
+                    let bytes = key_fragments.as_bytes();
+                    key_fragments.len() % 2 == 0 && K::decode_bytes(bytes).encode_bytes() == bytes
+                });
+        ok && length == self.length
+    }
+}
+
+/// Encode a key as whole bytes, which is what `TrieKeyDecode` decodes.
+#[inline]
+fn encode<Q>(key: &Q) -> Nibblet
+where
+    Q: ?Sized + TrieKey,
+{
+    Nibblet::from_byte_vec(key.encode_bytes())
+}
+
+impl<K, V> Default for PathTrie<K, V>
+where
+    K: TrieKeyDecode,
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> PartialEq for PathTrie<K, V>
+where
+    K: TrieKeyDecode,
+    V: PartialEq,
+{
+    #[inline]
+    fn eq(&self, other: &PathTrie<K, V>) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl<K, V> FromIterator<(K, V)> for PathTrie<K, V>
+where
+    K: TrieKeyDecode,
+{
+    fn from_iter<T>(iter: T) -> PathTrie<K, V>
+    where
+        T: IntoIterator<Item = (K, V)>,
+    {
+        let mut trie = PathTrie::new();
+        trie.extend(iter);
+        trie
+    }
+}
+
+impl<K, V> Extend<(K, V)> for PathTrie<K, V>
+where
+    K: TrieKeyDecode,
+{
+    fn extend<T>(&mut self, iter: T)
+    where
+        T: IntoIterator<Item = (K, V)>,
+    {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<'a, K, V> IntoIterator for &'a PathTrie<K, V>
+where
+    K: TrieKeyDecode,
+{
+    type Item = (K, &'a V);
+    type IntoIter = PathIter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
@@ -0,0 +1,383 @@
+//! Trie with versions that share their nodes.
+
+use crate::child_map::{Shared, make_unique};
+use crate::iter::Ancestors;
+use crate::keys::{ByteNibbles, Nibbles, match_len};
+use crate::traversal::DescendantResult::*;
+use crate::{Granularity, PersistentTrie, SubTrie, Trie, TrieCommon, TrieKey, TrieNode};
+use std::borrow::Borrow;
+use std::iter::FromIterator;
+use std::sync::Arc;
+
+use nibble_vec::Nibblet;
+
+impl<K, V> PersistentTrie<K, V>
+where
+    K: TrieKey,
+{
+    /// Create an empty PersistentTrie.
+    #[inline]
+    pub fn new() -> PersistentTrie<K, V> {
+        PersistentTrie::with_granularity(Granularity::Nibble)
+    }
+
+    /// Create an empty PersistentTrie, which branches on the given number of key bits at each
+    /// node.
+    ///
+    /// Panics if the keys are encoded bit by bit and `granularity` is `Granularity::Byte`.
+    #[inline]
+    pub fn with_granularity(granularity: Granularity) -> PersistentTrie<K, V> {
+        granularity.check_key::<K>();
+        PersistentTrie {
+            length: 0,
+            granularity,
+            node: Arc::new(TrieNode::new()),
+        }
+    }
+
+    /// Get the number of key bits that this trie branches on at each node.
+    #[inline]
+    pub fn granularity(&self) -> Granularity {
+        self.granularity
+    }
+
+    /// Fetch a reference to the given key's corresponding value, if any.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        let node = match key.encode_borrowed() {
+            Some(bytes) => self.node.get(&ByteNibbles(bytes), self.granularity),
+            None => self.node.get(&key.encode(), self.granularity),
+        };
+        node.and_then(|t| t.value_checked(key))
+    }
+
+    /// Fetch a reference to the subtrie for a given key.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn subtrie<'a, Q>(&'a self, key: &Q) -> Option<SubTrie<'a, K, V, Shared>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        let key_fragments = key.encode();
+        self.node
+            .get(&key_fragments, self.granularity)
+            .map(|node| node.as_subtrie(key_fragments, self.granularity))
+    }
+
+    /// Fetch a reference to the closest ancestor node of the given key, as `Trie::get_ancestor`.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn get_ancestor<'a, Q>(&'a self, key: &Q) -> Option<SubTrie<'a, K, V, Shared>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        let mut key_fragments = key.encode();
+        self.node
+            .get_ancestor(&key_fragments, self.granularity)
+            .map(|(node, node_key_len)| {
+                key_fragments.split(node_key_len);
+                node.as_subtrie(key_fragments, self.granularity)
+            })
+    }
+
+    /// Fetch the closest ancestor *value* for a given key.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn get_ancestor_value<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        let ancestor = match key.encode_borrowed() {
+            Some(bytes) => self
+                .node
+                .get_ancestor(&ByteNibbles(bytes), self.granularity),
+            None => self.node.get_ancestor(&key.encode(), self.granularity),
+        };
+        ancestor.and_then(|(node, _)| node.value())
+    }
+
+    /// Find the longest key that is a prefix of the given key, along with its value and its
+    /// length, as `Trie::longest_prefix_match`.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn longest_prefix_match<Q>(&self, key: &Q) -> Option<(&K, &V, usize)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        match key.encode_borrowed() {
+            Some(bytes) => self.longest_prefix_match_nv(&ByteNibbles(bytes)),
+            None => self.longest_prefix_match_nv(&key.encode()),
+        }
+    }
+
+    fn longest_prefix_match_nv<N>(&self, nv: &N) -> Option<(&K, &V, usize)>
+    where
+        N: ?Sized + Nibbles,
+    {
+        self.node
+            .get_ancestor(nv, self.granularity)
+            .and_then(|(node, depth)| {
+                node.key_value
+                    .as_ref()
+                    .map(|kv| (&kv.key, &kv.value, match_len::<K>(depth)))
+            })
+    }
+
+    /// Return an iterator over all the keys and values whose keys are prefixes of the given key,
+    /// from the shortest to the longest.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn ancestors<Q>(&self, key: &Q) -> Ancestors<'_, K, V, Shared>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        Ancestors::new(&self.node, key.encode(), self.granularity)
+    }
+
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type
+    #[inline]
+    pub fn get_raw_ancestor<'a, Q>(&'a self, key: &Q) -> SubTrie<'a, K, V, Shared>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        let mut nv = key.encode();
+        let (ancestor_node, depth) = self.node.get_raw_ancestor(&nv, self.granularity);
+        nv.split(depth);
+        ancestor_node.as_subtrie(nv, self.granularity)
+    }
+
+    /// Fetch the closest descendant for a given key.
+    ///
+    /// The key is only used as a prefix of the encoded keys, so it needn't have the same type
+    /// as the keys.
+    #[inline]
+    pub fn get_raw_descendant<'a, Q>(&'a self, key: &Q) -> Option<SubTrie<'a, K, V, Shared>>
+    where
+        Q: ?Sized + TrieKey,
+    {
+        let mut nv = key.encode();
+        self.node
+            .get_raw_descendant(&nv, self.granularity)
+            .map(|desc| {
+                let (node, prefix) = match desc {
+                    NoModification(node) => (node, nv),
+                    ExtendKey(node, depth, extension) => {
+                        nv.split(depth);
+                        (node, nv.join(extension))
+                    }
+                };
+                node.as_subtrie(prefix, self.granularity)
+            })
+    }
+
+    /// Check that the Trie invariants are satisfied - you shouldn't ever have to call this!
+    /// Quite slow!
+    #[doc(hidden)]
+    pub fn check_integrity(&self) -> bool {
+        let (ok, length) = self
+            .node
+            .check_integrity_recursive(&Nibblet::new(), self.granularity);
+        ok && length == self.length
+    }
+}
+
+impl<K, V> PersistentTrie<K, V>
+where
+    K: TrieKey + Clone,
+    V: Clone,
+{
+    /// Return a new version of the trie with the given key and value inserted, replacing the
+    /// key's previous value if it had one.
+    ///
+    /// The nodes on the path to the key are copied, so keys and values are cloned along it.
+    #[inline]
+    pub fn insert(&self, key: K, value: V) -> PersistentTrie<K, V> {
+        let mut trie = self.clone();
+        trie.insert_mut(key, value);
+        trie
+    }
+
+    /// Return a new version of the trie with the given key removed.
+    ///
+    /// If the key isn't in the trie, the new version shares all of its nodes with this one.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn remove<Q>(&self, key: &Q) -> PersistentTrie<K, V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        let mut trie = self.clone();
+        trie.remove_mut(key);
+        trie
+    }
+
+    /// Take a snapshot of this version of the trie, which is unaffected by later updates made
+    /// in place with `insert_mut`, `remove_mut` and `get_mut`.
+    ///
+    /// No nodes are copied. Snapshots are `Send` and `Sync` if the keys and values are, so
+    /// they can be handed to reader threads while the trie is updated.
+    #[inline]
+    pub fn snapshot(&self) -> PersistentTrie<K, V> {
+        self.clone()
+    }
+
+    /// Insert the given key and value in place, returning the key's previous value if it had
+    /// one.
+    ///
+    /// Nodes on the path to the key are copied only if they are shared with another version,
+    /// such as a snapshot. Others are updated in place, as in a `Trie`.
+    pub fn insert_mut(&mut self, key: K, value: V) -> Option<V> {
+        let nv = key.encode();
+        let node = make_unique(&mut self.node);
+        node.unshare_path(&nv, false, self.granularity);
+        let previous = node.insert(key, value, nv, self.granularity);
+        if previous.is_none() {
+            self.length += 1;
+        }
+        previous
+    }
+
+    /// Remove the given key in place, returning its value if it had one.
+    ///
+    /// Like `insert_mut`, only nodes shared with another version are copied, and no nodes are
+    /// copied if the key isn't in the trie.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    pub fn remove_mut<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        self.get(key)?;
+        let nv = key.encode();
+        let node = make_unique(&mut self.node);
+        node.unshare_path(&nv, true, self.granularity);
+        let removed = node.remove(key, &nv, self.granularity);
+        self.length -= 1;
+        removed
+    }
+
+    /// Fetch a mutable reference to the given key's corresponding value, if any.
+    ///
+    /// Like `insert_mut`, only nodes shared with another version are copied, and no nodes are
+    /// copied if the key isn't in the trie.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        self.get(key)?;
+        let nv = key.encode();
+        let node = make_unique(&mut self.node);
+        node.unshare_path(&nv, false, self.granularity);
+        node.get_mut(&nv, self.granularity)
+            .and_then(|t| t.value_checked_mut(key))
+    }
+}
+
+impl<K, V> Clone for PersistentTrie<K, V> {
+    /// Get another reference to this version of the trie, without copying any nodes.
+    #[inline]
+    fn clone(&self) -> Self {
+        PersistentTrie {
+            length: self.length,
+            granularity: self.granularity,
+            node: Arc::clone(&self.node),
+        }
+    }
+}
+
+impl<K, V> From<Trie<K, V>> for PersistentTrie<K, V> {
+    /// Make a trie the first version of a persistent trie, moving its keys and values into
+    /// nodes which can be shared.
+    fn from(trie: Trie<K, V>) -> Self {
+        PersistentTrie {
+            length: trie.length,
+            granularity: trie.granularity,
+            node: Arc::new(trie.node.into_shared(trie.granularity)),
+        }
+    }
+}
+
+impl<K, V> PartialEq for PersistentTrie<K, V>
+where
+    K: TrieKey,
+    V: PartialEq,
+{
+    #[inline]
+    fn eq(&self, other: &PersistentTrie<K, V>) -> bool {
+        if self.len() != other.len() {
+            return false;
+        }
+
+        Arc::ptr_eq(&self.node, &other.node)
+            || self
+                .iter()
+                .all(|(key, value)| other.get(key).is_some_and(|v| *value == *v))
+    }
+}
+
+impl<K: TrieKey, V> Default for PersistentTrie<K, V> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> Extend<(K, V)> for PersistentTrie<K, V>
+where
+    K: TrieKey + Clone,
+    V: Clone,
+{
+    fn extend<T>(&mut self, iter: T)
+    where
+        T: IntoIterator<Item = (K, V)>,
+    {
+        for (k, v) in iter {
+            self.insert_mut(k, v);
+        }
+    }
+}
+
+impl<K, V> FromIterator<(K, V)> for PersistentTrie<K, V>
+where
+    K: TrieKey,
+{
+    fn from_iter<T>(iter: T) -> PersistentTrie<K, V>
+    where
+        T: IntoIterator<Item = (K, V)>,
+    {
+        Trie::from_iter(iter).into()
+    }
+}
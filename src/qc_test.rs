@@ -1,11 +1,19 @@
 //! Proper testing, with QuickCheck.
 
-use crate::{Trie, TrieCommon, TrieKey};
+use crate::keys::{ByteNibbles, KeyMatch, match_keys};
+use crate::{
+    ConcurrentTrie, Entry, Granularity, IpPrefix, PathTrie, PersistentTrie, Trie, TrieCommon,
+    TrieKey, TrieKeyDecode, TrieSet,
+};
+use nibble_vec::Nibblet;
 use quickcheck::{Arbitrary, Gen, quickcheck};
-use std::collections::{HashMap, HashSet};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::iter::FromIterator;
+use std::net::Ipv4Addr;
+use std::ops::{Bound, RangeBounds};
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 struct Key(Vec<u8>);
 
 #[derive(Clone, Debug)]
@@ -112,6 +120,34 @@ fn insert_all_remove_all() {
     quickcheck(prop as fn(RandomKeys) -> bool);
 }
 
+// Inserting and removing via entries should behave like `insert` and `remove`.
+#[test]
+fn entry_insert_remove() {
+    fn prop(RandomKeys(insert_keys): RandomKeys, RandomKeys(remove_keys): RandomKeys) -> bool {
+        let mut trie = Trie::new();
+
+        for k in &insert_keys {
+            *trie.entry(k.clone()).or_insert(0) += k.len();
+        }
+        if trie != length_trie(insert_keys.clone()) || !trie.check_integrity() {
+            return false;
+        }
+
+        for k in remove_keys {
+            let removed = match trie.entry(k.clone()) {
+                Entry::Occupied(entry) => Some(entry.remove()),
+                Entry::Vacant(_) => None,
+            };
+            if removed.is_some() != insert_keys.contains(&k) {
+                return false;
+            }
+        }
+        trie.check_integrity()
+    }
+
+    quickcheck(prop as fn(RandomKeys, RandomKeys) -> bool);
+}
+
 #[test]
 fn subtrie() {
     fn prop(RandomKeys(keys): RandomKeys) -> bool {
@@ -272,3 +308,589 @@ fn values_iter() {
     }
     quickcheck(prop as fn(RandomKeys) -> bool);
 }
+
+#[test]
+fn range_iter() {
+    fn prop(RandomKeys(keys): RandomKeys, lower: Key, upper: Key, bounds: (u8, u8)) -> bool {
+        let trie = length_trie(keys.clone());
+
+        let bound = |key: &Key, kind: u8| match kind % 3 {
+            0 => Bound::Included(key.clone()),
+            1 => Bound::Excluded(key.clone()),
+            _ => Bound::Unbounded,
+        };
+        let range = (bound(&lower, bounds.0), bound(&upper, bounds.1));
+
+        // Byte-wise order of the keys is the same as the trie's order.
+        let mut expected: Vec<&Key> = keys.iter().filter(|k| range.contains(*k)).collect();
+        expected.sort();
+
+        let observed: Vec<&Key> = trie.range(range.clone()).map(|(k, _)| k).collect();
+        observed == expected
+    }
+    quickcheck(prop as fn(RandomKeys, Key, Key, (u8, u8)) -> bool);
+}
+
+// Consuming an iterator from both ends should yield every key exactly once, in order.
+#[test]
+fn double_ended_iter() {
+    fn prop(RandomKeys(keys): RandomKeys, directions: Vec<bool>) -> bool {
+        let trie = length_trie(keys.clone());
+        let mut expected: Vec<&Key> = keys.iter().collect();
+        expected.sort();
+
+        let mut front = vec![];
+        let mut back = vec![];
+        let mut iter = trie.keys();
+        for from_back in directions.into_iter().chain(std::iter::repeat(false)) {
+            let (next, side) = if from_back {
+                (iter.next_back(), &mut back)
+            } else {
+                (iter.next(), &mut front)
+            };
+            match next {
+                Some(k) => side.push(k),
+                None => break,
+            }
+        }
+
+        front.extend(back.into_iter().rev());
+        front == expected && iter.next().is_none() && iter.next_back().is_none()
+    }
+    quickcheck(prop as fn(RandomKeys, Vec<bool>) -> bool);
+}
+
+#[test]
+fn remove_prefix() {
+    fn prop(RandomKeys(keys): RandomKeys, prefix: Key) -> bool {
+        let mut trie = length_trie(keys.clone());
+        let removed = trie.remove_prefix(&prefix);
+
+        let (expected_removed, expected_kept): (HashSet<Key>, HashSet<Key>) =
+            keys.into_iter().partition(|k| k.0.starts_with(&prefix.0));
+
+        trie.check_integrity()
+            && removed.check_integrity()
+            && trie == length_trie(expected_kept)
+            && removed == length_trie(expected_removed)
+    }
+    quickcheck(prop as fn(RandomKeys, Key) -> bool);
+}
+
+#[test]
+fn retain() {
+    fn prop(RandomKeys(keys): RandomKeys, modulus: u8) -> bool {
+        let modulus = modulus as usize % 4 + 1;
+        let mut trie = length_trie(keys.clone());
+        trie.retain(|k, _| k.len() % modulus == 0);
+
+        let expected = keys.into_iter().filter(|k| k.len() % modulus == 0);
+        trie.check_integrity() && trie == length_trie(expected.collect())
+    }
+    quickcheck(prop as fn(RandomKeys, u8) -> bool);
+}
+
+#[test]
+fn retain_prefixes() {
+    fn prop(RandomKeys(keys): RandomKeys, prefix: Key) -> bool {
+        // Reject all keys that start with `prefix` (a prefix-closed predicate).
+        let mut trie = length_trie(keys.clone());
+        trie.retain_prefixes(|p| !p.starts_with(&prefix.0));
+
+        let expected = keys.into_iter().filter(|k| !k.0.starts_with(&prefix.0));
+        trie.check_integrity() && trie == length_trie(expected.collect())
+    }
+    quickcheck(prop as fn(RandomKeys, Key) -> bool);
+}
+
+#[test]
+fn signed_int_keys_order() {
+    fn prop(keys: HashSet<i64>) -> bool {
+        let trie: Trie<i64, ()> = keys.iter().map(|&k| (k, ())).collect();
+        let mut expected: Vec<i64> = keys.into_iter().collect();
+        expected.sort();
+        trie.keys().cloned().collect::<Vec<_>>() == expected
+    }
+    quickcheck(prop as fn(HashSet<i64>) -> bool);
+}
+
+// Pairs of keys should never collide, and should be ordered component by component.
+#[test]
+fn tuple_keys() {
+    fn prop(pairs: HashSet<(Key, Key)>) -> bool {
+        let trie: Trie<(Key, Key), ()> = pairs.iter().map(|p| (p.clone(), ())).collect();
+        let mut expected: Vec<&(Key, Key)> = pairs.iter().collect();
+        expected.sort();
+        trie.keys().collect::<Vec<_>>() == expected && trie.check_integrity()
+    }
+    quickcheck(prop as fn(HashSet<(Key, Key)>) -> bool);
+}
+
+#[test]
+fn decode_keys() {
+    fn prop(tuple: (Vec<u8>, String, i32), ints: Vec<i64>, unsigned: u64) -> bool {
+        <(Vec<u8>, String, i32)>::decode_bytes(&tuple.encode_bytes()) == tuple
+            && Vec::<i64>::decode_bytes(&ints.encode_bytes()) == ints
+            && u64::decode_bytes(&unsigned.encode_bytes()) == unsigned
+    }
+    quickcheck(prop as fn((Vec<u8>, String, i32), Vec<i64>, u64) -> bool);
+}
+
+// A PathTrie should behave exactly like a Trie.
+#[test]
+fn path_trie() {
+    fn prop(RandomKeys(keys): RandomKeys, removed: RandomKeys, query: Key) -> bool {
+        let mut trie = Trie::new();
+        let mut path_trie = PathTrie::new();
+        for (i, key) in keys.iter().enumerate() {
+            trie.insert(key.0.clone(), i);
+            path_trie.insert(key.0.clone(), i);
+        }
+        for key in &removed.0 {
+            if trie.remove(&key.0) != path_trie.remove(&key.0) {
+                return false;
+            }
+        }
+
+        let ancestor = trie.get_ancestor(&query.0);
+        let prefix = trie.get_raw_descendant(&query.0);
+        path_trie.check_integrity()
+            && path_trie.len() == trie.len()
+            && path_trie
+                .iter()
+                .eq(trie.iter().map(|(k, v)| (k.clone(), v)))
+            && path_trie.values().eq(trie.values())
+            && path_trie.get_ancestor(&query.0)
+                == ancestor.map(|t| (t.key().unwrap().clone(), t.value().unwrap()))
+            && path_trie.iter_prefix(&query.0).eq(prefix
+                .iter()
+                .flat_map(|t| t.iter())
+                .map(|(k, v)| (k.clone(), v)))
+    }
+    quickcheck(prop as fn(RandomKeys, RandomKeys, Key) -> bool);
+}
+
+// Lookups by borrowed bytes should agree with lookups by encoded keys.
+#[test]
+fn borrowed_lookups() {
+    fn prop(RandomKeys(keys): RandomKeys, queries: Vec<Key>) -> bool {
+        let mut trie = Trie::new();
+        let mut encoded = Trie::new();
+        for (i, key) in keys.iter().enumerate() {
+            trie.insert(key.0.clone(), i);
+            encoded.insert(key.clone(), i);
+        }
+
+        queries.iter().chain(&keys).all(|query| {
+            let bytes: &[u8] = &query.0;
+            trie.get(bytes) == encoded.get(query)
+                && trie.get_ancestor_value(bytes) == encoded.get_ancestor_value(query)
+                && trie.longest_prefix_match(bytes).map(|(_, v, len)| (v, len))
+                    == encoded
+                        .longest_prefix_match(query)
+                        .map(|(_, v, len)| (v, len))
+        })
+    }
+    quickcheck(prop as fn(RandomKeys, Vec<Key>) -> bool);
+}
+
+// Comparing whole bytes should give the same result as comparing nibbles one at a time.
+#[test]
+fn match_keys_bytewise() {
+    fn prop(first: Vec<u8>, second: Vec<u8>, shared: Vec<u8>, start_idx: usize) -> bool {
+        let first = [&shared[..], &first[..]].concat();
+        let second = [&shared[..], &second[..]].concat();
+        let first = Nibblet::from_byte_vec(first);
+        let start_idx = start_idx % (first.len() + 1);
+        // Start the second key part way through a byte, sometimes.
+        let mut second = Nibblet::from_byte_vec(second);
+        if !second.is_empty() {
+            second = second.split(start_idx % 2);
+        }
+
+        let first_len = first.len() - start_idx;
+        let expected = (0..first_len.min(second.len()))
+            .find(|&i| first.get(start_idx + i) != second.get(i))
+            .map(KeyMatch::Partial)
+            .unwrap_or(match first_len.cmp(&second.len()) {
+                Ordering::Less => KeyMatch::FirstPrefix,
+                Ordering::Equal => KeyMatch::Full,
+                Ordering::Greater => KeyMatch::SecondPrefix,
+            });
+
+        let bytes = ByteNibbles(first.as_bytes());
+        match_keys(start_idx, &first, &second) == expected
+            && match_keys(start_idx, &bytes, &second) == expected
+    }
+    quickcheck(prop as fn(Vec<u8>, Vec<u8>, Vec<u8>, usize) -> bool);
+}
+
+// Keys with every nibble value, so that nodes grow past a sparse child list and shrink back.
+#[test]
+fn wide_nodes() {
+    fn prop(keys: HashSet<u16>, removed: Vec<u16>) -> bool {
+        let mut trie: Trie<u16, u16> = keys.iter().map(|&k| (k, k)).collect();
+        if !trie.check_integrity() {
+            return false;
+        }
+
+        let mut remaining = keys.clone();
+        for k in removed.iter().chain(&keys) {
+            if trie.remove(k) != remaining.take(k) || !trie.check_integrity() {
+                return false;
+            }
+            if trie.clone() != trie || !trie.iter().map(|(&k, _)| k).eq(sorted(&remaining)) {
+                return false;
+            }
+        }
+        trie.is_empty()
+    }
+
+    fn sorted(keys: &HashSet<u16>) -> Vec<u16> {
+        let mut keys: Vec<_> = keys.iter().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    quickcheck(prop as fn(HashSet<u16>, Vec<u16>) -> bool);
+}
+
+// A trie with byte granularity should behave exactly like one with nibble granularity.
+#[test]
+fn byte_granularity() {
+    fn prop(
+        RandomKeys(keys): RandomKeys,
+        wide: Vec<(u8, Key)>,
+        queries: Vec<Key>,
+        prefix: Key,
+    ) -> bool {
+        // Keys with many different first bytes, for nodes with many children.
+        let wide = wide.into_iter().map(|(b, k)| Key(vec![b]).extend(k));
+        let keys: HashSet<Key> = keys.into_iter().chain(wide).collect();
+
+        let mut nibble_trie = Trie::new();
+        let mut byte_trie = Trie::with_granularity(Granularity::Byte);
+        for k in &keys {
+            nibble_trie.insert(k.clone(), k.len());
+            byte_trie.insert(k.clone(), k.len());
+        }
+        if !byte_trie.check_integrity() || !byte_trie.iter().eq(nibble_trie.iter()) {
+            return false;
+        }
+
+        // The tries have value-less nodes at different places, so only the subtries at values
+        // are the same.
+        for q in queries.iter().chain(keys.iter().take(queries.len())) {
+            let both =
+                |f: &dyn Fn(&Trie<Key, usize>) -> Option<usize>| f(&nibble_trie) == f(&byte_trie);
+            if !both(&|t| t.get(q).cloned())
+                || !both(&|t| t.get_ancestor_value(q).cloned())
+                || !both(&|t| {
+                    t.subtrie(q)
+                        .filter(|s| s.value().is_some())
+                        .map(|s| s.len())
+                })
+                || !both(&|t| t.get_raw_descendant(q).map(|s| s.len()))
+                || !both(&|t| Some(t.range(q..).count()))
+                || !both(&|t| Some(t.range(..=q).count()))
+                || !both(&|t| Some(t.ancestors(q).count()))
+            {
+                return false;
+            }
+        }
+
+        let clone = byte_trie.clone();
+        let removed = byte_trie.remove_prefix(&prefix);
+        let nibble_removed = nibble_trie.remove_prefix(&prefix);
+        if !clone.check_integrity()
+            || clone.granularity() != Granularity::Byte
+            || removed.granularity() != Granularity::Byte
+            || !removed.check_integrity()
+            || removed != nibble_removed
+        {
+            return false;
+        }
+
+        for k in &keys {
+            if byte_trie.remove(k) != nibble_trie.remove(k) || !byte_trie.check_integrity() {
+                return false;
+            }
+        }
+        byte_trie.is_empty()
+    }
+    quickcheck(prop as fn(RandomKeys, Vec<(u8, Key)>, Vec<Key>, Key) -> bool);
+}
+
+// Looking up an address in a trie of prefixes should find the longest prefix containing it.
+#[test]
+fn ip_routing() {
+    fn prop(prefixes: Vec<(u32, u8)>, addrs: Vec<u32>) -> bool {
+        // Short prefixes, so that they often contain each other.
+        let prefixes: HashSet<IpPrefix<Ipv4Addr>> = prefixes
+            .into_iter()
+            .map(|(addr, len)| IpPrefix::new(Ipv4Addr::from_bits(addr), len % 12))
+            .collect();
+        let trie: Trie<_, _> = prefixes.iter().map(|&p| (p, p.prefix_len())).collect();
+        let mut sorted: Vec<_> = prefixes.iter().copied().collect();
+        sorted.sort();
+        if !trie.check_integrity() || !trie.keys().copied().eq(sorted) {
+            return false;
+        }
+
+        addrs.into_iter().map(Ipv4Addr::from_bits).all(|addr| {
+            let longest = prefixes
+                .iter()
+                .filter(|p| p.contains(addr))
+                .map(|p| p.prefix_len())
+                .max();
+            trie.get_ancestor_value(&IpPrefix::from(addr)).copied() == longest
+        })
+    }
+    quickcheck(prop as fn(Vec<(u32, u8)>, Vec<u32>) -> bool);
+}
+
+// Set operations on TrieSets should give the same keys as on BTreeSets, in the same order.
+#[test]
+fn trie_set_ops() {
+    fn prop(RandomKeys(a): RandomKeys, RandomKeys(b): RandomKeys, prefix: Key) -> bool {
+        let trie_a: TrieSet<Key> = a.iter().cloned().collect();
+        let trie_b: TrieSet<Key> = b.iter().cloned().collect();
+        let a: BTreeSet<Key> = a.into_iter().collect();
+        let b: BTreeSet<Key> = b.into_iter().collect();
+
+        trie_a.check_integrity()
+            && trie_a.iter().eq(a.iter())
+            && trie_a.union(&trie_b).eq(a.union(&b))
+            && trie_a.intersection(&trie_b).eq(a.intersection(&b))
+            && trie_a.difference(&trie_b).eq(a.difference(&b))
+            && trie_a
+                .symmetric_difference(&trie_b)
+                .eq(a.symmetric_difference(&b))
+            && trie_a
+                .iter_prefix(&prefix)
+                .eq(a.iter().filter(|k| k.0.starts_with(&prefix.0)))
+            && trie_a.get_ancestor(&prefix) == a.iter().rfind(|k| prefix.0.starts_with(&k.0))
+    }
+    quickcheck(prop as fn(RandomKeys, RandomKeys, Key) -> bool);
+}
+
+// Structural union, intersection and difference should match the same operations on maps.
+#[test]
+fn union_intersection_difference() {
+    fn prop(
+        RandomKeys(a): RandomKeys,
+        RandomKeys(b): RandomKeys,
+        byte_a: bool,
+        byte_b: bool,
+    ) -> bool {
+        let granularity = |byte| match byte {
+            true => Granularity::Byte,
+            false => Granularity::Nibble,
+        };
+        let make_trie = |keys: &HashSet<Key>, byte, offset| {
+            let mut trie = Trie::with_granularity(granularity(byte));
+            for k in keys {
+                trie.insert(k.clone(), k.len() + offset);
+            }
+            trie
+        };
+        let check = |trie: &Trie<Key, usize>, expected: BTreeMap<&Key, usize>| {
+            trie.check_integrity()
+                && trie.len() == expected.len()
+                && trie.granularity() == granularity(byte_a)
+                && trie.iter().map(|(k, &v)| (k, v)).eq(expected)
+        };
+
+        let mut union = make_trie(&a, byte_a, 0);
+        union.union_with(make_trie(&b, byte_b, 100), |x, y| x * 1000 + y);
+        let mut expected: BTreeMap<&Key, usize> = a.iter().map(|k| (k, k.len())).collect();
+        for k in &b {
+            let v = expected.get(k).map_or(0, |&x| x * 1000) + k.len() + 100;
+            expected.insert(k, v);
+        }
+        if !check(&union, expected) {
+            return false;
+        }
+
+        let mut intersection = make_trie(&a, byte_a, 0);
+        intersection.intersection_with(make_trie(&b, byte_b, 100), |x, y| x * 1000 + y);
+        let expected = a
+            .intersection(&b)
+            .map(|k| (k, k.len() * 1000 + k.len() + 100))
+            .collect();
+        if !check(&intersection, expected) {
+            return false;
+        }
+
+        let mut difference = make_trie(&a, byte_a, 0);
+        difference.difference(&make_trie(&b, byte_b, 100));
+        let expected = a.difference(&b).map(|k| (k, k.len())).collect();
+        check(&difference, expected)
+    }
+    quickcheck(prop as fn(RandomKeys, RandomKeys, bool, bool) -> bool);
+}
+
+// Splitting a trie at a key and appending the parts should give back the same trie.
+#[test]
+fn split_off_append() {
+    fn prop(RandomKeys(keys): RandomKeys, split: Key, byte: bool) -> bool {
+        let granularity = match byte {
+            true => Granularity::Byte,
+            false => Granularity::Nibble,
+        };
+        let mut trie = Trie::with_granularity(granularity);
+        for k in &keys {
+            trie.insert(k.clone(), k.len());
+        }
+        let original = trie.clone();
+
+        let mut right = trie.split_off(&split);
+        if !trie.check_integrity()
+            || !right.check_integrity()
+            || right.granularity() != granularity
+            || trie.len() + right.len() != keys.len()
+            || trie.keys().any(|k| *k >= split)
+            || right.keys().any(|k| *k < split)
+        {
+            return false;
+        }
+
+        trie.append(&mut right);
+        trie.check_integrity() && right.is_empty() && trie == original
+    }
+    quickcheck(prop as fn(RandomKeys, Key, bool) -> bool);
+}
+
+// Every version of a persistent trie should keep the keys and values it was made with.
+#[test]
+fn persistent_versions() {
+    fn prop(RandomKeys(keys): RandomKeys, ops: Vec<(Key, Option<usize>)>, byte: bool) -> bool {
+        let granularity = match byte {
+            true => Granularity::Byte,
+            false => Granularity::Nibble,
+        };
+        let keys: Vec<Key> = keys.into_iter().collect();
+        let mut trie = Trie::with_granularity(granularity);
+        for k in &keys {
+            trie.insert(k.clone(), 0);
+        }
+        let model: BTreeMap<Key, usize> = keys.iter().map(|k| (k.clone(), 0)).collect();
+
+        let mut versions = vec![(PersistentTrie::from(trie), model)];
+        for (i, (key, removed)) in ops.into_iter().enumerate() {
+            let (trie, mut model) = versions.last().unwrap().clone();
+            let trie = match removed {
+                // Remove an existing key, or a new key if there are none.
+                Some(idx) => {
+                    let key = keys.get(idx % keys.len().max(1)).unwrap_or(&key);
+                    model.remove(key);
+                    trie.remove(key)
+                }
+                None => {
+                    model.insert(key.clone(), i + 1);
+                    trie.insert(key, i + 1)
+                }
+            };
+            versions.push((trie, model));
+        }
+
+        versions.iter().all(|(trie, model)| {
+            trie.check_integrity()
+                && trie.len() == model.len()
+                && trie.granularity() == granularity
+                && trie.iter().map(|(k, &v)| (k.clone(), v)).eq(model.clone())
+        })
+    }
+    quickcheck(prop as fn(RandomKeys, Vec<(Key, Option<usize>)>, bool) -> bool);
+}
+
+// Updating a persistent trie in place should leave its earlier snapshots unchanged.
+#[test]
+fn persistent_snapshots() {
+    fn prop(RandomKeys(keys): RandomKeys, ops: Vec<(Key, Option<usize>)>) -> bool {
+        let keys: Vec<Key> = keys.into_iter().collect();
+        let mut trie: PersistentTrie<Key, usize> = keys.iter().map(|k| (k.clone(), 0)).collect();
+        let mut model: BTreeMap<Key, usize> = keys.iter().map(|k| (k.clone(), 0)).collect();
+
+        let mut snapshots = vec![(trie.snapshot(), model.clone())];
+        for (i, (key, removed)) in ops.into_iter().enumerate() {
+            let matches = match removed {
+                Some(idx) => {
+                    let key = keys.get(idx % keys.len().max(1)).unwrap_or(&key);
+                    trie.remove_mut(key) == model.remove(key)
+                }
+                None if i % 2 == 0 => {
+                    let (value, expected) = (trie.get_mut(&key), model.get_mut(&key));
+                    let matches = value == expected;
+                    if let (Some(value), Some(expected)) = (value, expected) {
+                        *value += 1;
+                        *expected += 1;
+                    }
+                    matches
+                }
+                None => trie.insert_mut(key.clone(), i) == model.insert(key, i),
+            };
+            if !matches {
+                return false;
+            }
+            snapshots.push((trie.snapshot(), model.clone()));
+        }
+
+        snapshots.iter().all(|(trie, model)| {
+            trie.check_integrity()
+                && trie.len() == model.len()
+                && trie.iter().map(|(k, &v)| (k.clone(), v)).eq(model.clone())
+        })
+    }
+    quickcheck(prop as fn(RandomKeys, Vec<(Key, Option<usize>)>) -> bool);
+}
+
+// A concurrent trie should behave like a trie, including across its shards.
+#[test]
+fn concurrent_trie() {
+    fn prop(
+        RandomKeys(keys): RandomKeys,
+        removed: Vec<Key>,
+        queries: Vec<Key>,
+        byte: bool,
+    ) -> bool {
+        let granularity = match byte {
+            true => Granularity::Byte,
+            false => Granularity::Nibble,
+        };
+        let concurrent = ConcurrentTrie::with_granularity(granularity);
+        let mut trie = Trie::with_granularity(granularity);
+        for k in &keys {
+            if concurrent.insert(k.clone(), k.len()) != trie.insert(k.clone(), k.len()) {
+                return false;
+            }
+        }
+        for k in removed.iter().chain(keys.iter().step_by(3)) {
+            if concurrent.remove(k) != trie.remove(k) {
+                return false;
+            }
+        }
+        if !concurrent.check_integrity() || concurrent.len() != trie.len() {
+            return false;
+        }
+
+        for q in queries.iter().chain(keys.iter().take(queries.len())) {
+            let extended = q.extend(Key(vec![1, 2, 3]));
+            for q in [q, &extended] {
+                let expected = trie.longest_prefix_match(q);
+                if concurrent.get(q).as_ref() != trie.get(q)
+                    || concurrent
+                        .longest_prefix_match(q)
+                        .as_ref()
+                        .map(|(k, v, l)| (k, v, *l))
+                        != expected
+                {
+                    return false;
+                }
+            }
+        }
+
+        concurrent.into_trie() == trie
+    }
+    quickcheck(prop as fn(RandomKeys, Vec<Key>, Vec<Key>, bool) -> bool);
+}
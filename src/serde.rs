@@ -1,8 +1,8 @@
 extern crate serde;
 
-use self::serde::ser::SerializeMap;
+use self::serde::ser::{SerializeMap, SerializeSeq};
 use self::serde::{Deserialize, Deserializer, Serialize, Serializer, de};
-use super::{Trie, TrieCommon, TrieKey};
+use super::{Trie, TrieCommon, TrieKey, TrieSet};
 use std::fmt::{self, Formatter};
 use std::marker::PhantomData;
 
@@ -82,11 +82,76 @@ where
     }
 }
 
+impl<K> Serialize for TrieSet<K>
+where
+    K: Serialize + TrieKey,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut seq = serializer.serialize_seq(Some(self.len()))?;
+        for k in self.iter() {
+            seq.serialize_element(k)?;
+        }
+        seq.end()
+    }
+}
+
+struct TrieSetVisitor<K> {
+    marker: PhantomData<TrieSet<K>>,
+}
+
+impl<'a, K> de::Visitor<'a> for TrieSetVisitor<K>
+where
+    K: Deserialize<'a> + TrieKey,
+{
+    type Value = TrieSet<K>;
+
+    fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(formatter, "a serialized trie set")
+    }
+
+    fn visit_seq<A>(self, mut visitor: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::SeqAccess<'a>,
+    {
+        let mut set = TrieSet::new();
+
+        while let Some(key) = visitor.next_element()? {
+            set.insert(key);
+        }
+
+        Ok(set)
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(TrieSet::new())
+    }
+}
+
+impl<'a, K> Deserialize<'a> for TrieSet<K>
+where
+    K: Deserialize<'a> + TrieKey,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'a>,
+    {
+        deserializer.deserialize_seq(TrieSetVisitor {
+            marker: PhantomData,
+        })
+    }
+}
+
 #[cfg(test)]
 mod test {
     extern crate serde_test;
     use self::serde_test::Token;
-    use super::super::Trie;
+    use super::super::{Trie, TrieSet};
 
     macro_rules! tests_de {
         ($($name:ident => $value:expr => $tokens:expr,)+) => {
@@ -209,4 +274,35 @@ mod test {
             Token::StructEnd,
         ],
     }
+
+    tests_ser! {
+        test_ser_empty_set => TrieSet::<&str>::new() => &[
+            Token::Seq { len: Some(0) },
+            Token::SeqEnd,
+        ],
+        test_ser_set => ["3", "1", "12"].into_iter().collect::<TrieSet<_>>() => &[
+            Token::Seq { len: Some(3) },
+            Token::Str("1"),
+            Token::Str("12"),
+            Token::Str("3"),
+            Token::SeqEnd,
+        ],
+    }
+
+    tests_de! {
+        test_de_empty_set1 => TrieSet::<String>::new() => &[
+            Token::Unit,
+        ],
+        test_de_empty_set2 => TrieSet::<String>::new() => &[
+            Token::Seq { len: Some(0) },
+            Token::SeqEnd,
+        ],
+        test_de_set => ["1".to_string(), "3".to_string()].into_iter().collect::<TrieSet<_>>() => &[
+            Token::Seq { len: Some(3) },
+                Token::Str("3"),
+                Token::Str("1"),
+                Token::Str("3"),
+            Token::SeqEnd,
+        ],
+    }
 }
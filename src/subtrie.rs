@@ -1,11 +1,15 @@
 use crate::TrieNode;
+use crate::child_map::ChildPtr;
+use crate::entry::Entry;
+use crate::iter::{IterMut, ValuesMut};
 use crate::keys::*;
-use crate::{SubTrie, SubTrieMut, SubTrieResult};
+use crate::traversal::DescendantResult;
+use crate::{Granularity, SubTrie, SubTrieMut, SubTrieResult};
 use std::borrow::Borrow;
 
 use nibble_vec::Nibblet;
 
-impl<'a, K, V> SubTrie<'a, K, V>
+impl<'a, K, V, P: ChildPtr> SubTrie<'a, K, V, P>
 where
     K: TrieKey,
 {
@@ -18,13 +22,14 @@ where
         K: Borrow<Q>,
         Q: TrieKey,
     {
-        subtrie_get(&self.prefix, self.node, key)
+        subtrie_get(&self.prefix, self.granularity, self.node, key)
     }
 }
 
-fn subtrie_get<'a, K, Q: ?Sized, V>(
+fn subtrie_get<'a, K, Q: ?Sized, V, P: ChildPtr>(
     prefix: &Nibblet,
-    node: &'a TrieNode<K, V>,
+    granularity: Granularity,
+    node: &'a TrieNode<K, V, P>,
     key: &Q,
 ) -> SubTrieResult<&'a V>
 where
@@ -36,19 +41,53 @@ where
     match match_keys(0, prefix, &key_enc) {
         KeyMatch::Full => Ok(node.value()),
         KeyMatch::FirstPrefix => Ok(node
-            .get(&stripped(key_enc, prefix))
+            .get(&stripped(key_enc, prefix), granularity)
             .and_then(TrieNode::value)),
         _ => Err(()),
     }
 }
 
+/// Find the node of the subtrie with the given key, along with the node's full key.
+///
+/// This is the highest node whose key starts with the subtrie's key: the node for the key
+/// itself, unless removing that key merged the node with its only remaining child. There is no
+/// node once every key of the subtrie has been removed.
+pub(crate) fn subtrie_node<'b, K, V>(
+    root: &'b TrieNode<K, V>,
+    prefix: &Nibblet,
+    granularity: Granularity,
+) -> Option<(&'b TrieNode<K, V>, Nibblet)>
+where
+    K: TrieKey,
+{
+    root.get_raw_descendant(prefix, granularity)
+        .map(|result| match result {
+            DescendantResult::NoModification(node) => (node, prefix.clone()),
+            DescendantResult::ExtendKey(node, depth, key) => {
+                let mut node_prefix = prefix.clone();
+                node_prefix.split(depth);
+                (node, node_prefix.join(key))
+            }
+        })
+}
+
 impl<'a, K, V> SubTrieMut<'a, K, V>
 where
     K: TrieKey,
 {
+    /// Determine if a key lies within this subtrie, given its encoding.
+    fn contains_prefix(&self, key_enc: &Nibblet) -> bool {
+        matches!(
+            match_keys(0, &self.prefix, key_enc),
+            KeyMatch::Full | KeyMatch::FirstPrefix
+        )
+    }
+
     /// Mutable reference to the node's value.
     pub fn value_mut(&mut self) -> Option<&mut V> {
-        self.node.value_mut()
+        self.root
+            .get_descendant_mut(&self.prefix, self.granularity)
+            .and_then(TrieNode::value_mut)
     }
 
     /// Look up the value for the given key, which should be an extension of this subtrie's key.
@@ -60,22 +99,37 @@ where
         K: Borrow<Q>,
         Q: TrieKey,
     {
-        subtrie_get(&self.prefix, &*self.node, key)
+        let key_enc = key.encode();
+        if !self.contains_prefix(&key_enc) {
+            return Err(());
+        }
+        Ok(self
+            .root
+            .get(&key_enc, self.granularity)
+            .and_then(TrieNode::value))
+    }
+
+    /// Return an iterator over the keys and mutable values of this subtrie.
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        match self.root.get_descendant_mut(&self.prefix, self.granularity) {
+            Some(node) => IterMut::new(node),
+            None => IterMut::empty(),
+        }
+    }
+
+    /// Return an iterator over the mutable values of this subtrie.
+    pub fn values_mut(&mut self) -> ValuesMut<'_, K, V> {
+        ValuesMut::new(self.iter_mut())
     }
 
     /// Insert a value in this subtrie. The key should be an extension of this subtrie's key.
     pub fn insert(&mut self, key: K, value: V) -> SubTrieResult<V> {
         let key_enc = key.encode();
-        let previous = match match_keys(0, &self.prefix, &key_enc) {
-            KeyMatch::Full => self.node.replace_value(key, value),
-            KeyMatch::FirstPrefix => self
-                .node
-                .insert(key, value, stripped(key_enc, &self.prefix)),
-            _ => {
-                return Err(());
-            }
-        };
+        if !self.contains_prefix(&key_enc) {
+            return Err(());
+        }
 
+        let previous = self.root.insert(key, value, key_enc, self.granularity);
         if previous.is_none() {
             *self.length += 1;
         }
@@ -83,6 +137,25 @@ where
         Ok(previous)
     }
 
+    /// Get the entry for the given key, which should be an extension of this subtrie's key.
+    ///
+    /// Returns `None` if the key is not an extension of this subtrie's key.
+    pub fn entry(&mut self, key: K) -> Option<Entry<'_, K, V>> {
+        let key_enc = key.encode();
+        if !self.contains_prefix(&key_enc) {
+            return None;
+        }
+
+        let position = self.root.find_position(&key_enc, self.granularity);
+        Some(Entry::new(
+            position,
+            key,
+            key_enc,
+            self.granularity,
+            &mut *self.length,
+        ))
+    }
+
     /// Remove a value from this subtrie. The key should be an extension of this subtrie's key.
     ///
     /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
@@ -93,14 +166,11 @@ where
         Q: TrieKey,
     {
         let key_enc = key.encode();
-        let removed = match match_keys(0, &self.prefix, &key_enc) {
-            KeyMatch::Full => self.node.take_value(key),
-            KeyMatch::FirstPrefix => self.node.remove(key),
-            _ => {
-                return Err(());
-            }
-        };
+        if !self.contains_prefix(&key_enc) {
+            return Err(());
+        }
 
+        let removed = self.root.remove(key, &key_enc, self.granularity);
         if removed.is_some() {
             *self.length -= 1;
         }
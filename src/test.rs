@@ -1,7 +1,16 @@
 use crate::keys::TrieKey;
-use crate::{Trie, TrieCommon};
+use crate::{
+    ConcurrentTrie, Entry, Granularity, IpPrefix, PathTrie, PersistentTrie, Trie, TrieCommon,
+    TrieSet, encode_bits,
+};
+use nibble_vec::Nibblet;
+use std::cell::Cell;
 use std::collections::HashSet;
 use std::iter::FromIterator;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::ops::Bound;
+use std::ptr;
+use std::thread;
 
 const TEST_DATA: [(&'static str, u32); 7] = [
     ("abcdefgh", 19),
@@ -110,6 +119,153 @@ fn map_with_default() {
     assert_eq!(*trie.get(&"zzz").unwrap(), 42);
 }
 
+#[test]
+fn entry_insert() {
+    let mut trie = test_trie();
+    *trie.entry("abcd").or_insert(0) += 1;
+    *trie.entry("abc").or_insert(0) += 1;
+    *trie.entry("abcdefghi").or_default() += 5;
+    assert_eq!(trie.get(&"abcd"), Some(&18));
+    assert_eq!(trie.get(&"abc"), Some(&1));
+    assert_eq!(trie.get(&"abcdefghi"), Some(&5));
+    assert_eq!(trie.len(), TEST_DATA.len() + 2);
+    assert!(trie.check_integrity());
+}
+
+#[test]
+fn entry_and_modify() {
+    let mut trie = test_trie();
+    trie.entry("ab").and_modify(|v| *v *= 2).or_insert(0);
+    trie.entry("az").and_modify(|v| *v *= 2).or_insert(7);
+    assert_eq!(trie.get(&"ab"), Some(&32));
+    assert_eq!(trie.get(&"az"), Some(&7));
+    assert!(trie.check_integrity());
+}
+
+#[test]
+fn entry_remove() {
+    let mut trie = test_trie();
+
+    for &(key, val) in &TEST_DATA {
+        match trie.entry(key) {
+            Entry::Occupied(entry) => assert_eq!(entry.remove_entry(), (key, val)),
+            Entry::Vacant(_) => panic!("key {} should be occupied", key),
+        }
+        assert!(trie.check_integrity());
+    }
+    assert!(trie.is_empty());
+}
+
+#[test]
+fn entry_vacant_branch() {
+    let mut trie = Trie::new();
+    trie.insert("hella", 1);
+    trie.insert("hellb", 2);
+
+    // The "hell" node exists but has no value.
+    match trie.entry("hell") {
+        Entry::Occupied(_) => panic!("key should be vacant"),
+        Entry::Vacant(entry) => assert_eq!(*entry.insert(3), 3),
+    }
+    assert_eq!(trie.get(&"hell"), Some(&3));
+    assert_eq!(trie.len(), 3);
+    assert!(trie.check_integrity());
+}
+
+#[test]
+fn subtrie_entry() {
+    let mut trie = test_trie();
+    {
+        let mut subtrie = trie.subtrie_mut(&"abcd").unwrap();
+        assert!(subtrie.entry("abd").is_none());
+        *subtrie.entry("abcd").unwrap().or_insert(0) += 1;
+        *subtrie.entry("abcdxy").unwrap().or_insert(0) += 1;
+    }
+    assert_eq!(trie.get(&"abcd"), Some(&18));
+    assert_eq!(trie.get(&"abcdxy"), Some(&1));
+    assert_eq!(trie.len(), TEST_DATA.len() + 1);
+    assert!(trie.check_integrity());
+}
+
+#[test]
+fn subtrie_entry_remove() {
+    let mut trie = Trie::new();
+    for (i, key) in ["xabcA", "xabcb", "xq"].into_iter().enumerate() {
+        trie.insert(key, i);
+    }
+    {
+        let mut subtrie = trie.subtrie_mut("xabc").unwrap();
+        match subtrie.entry("xabcA").unwrap() {
+            Entry::Occupied(entry) => assert_eq!(entry.remove(), 0),
+            Entry::Vacant(_) => panic!("expected an occupied entry"),
+        }
+        // The subtrie's node has been merged with its remaining child.
+        assert_eq!(subtrie.get("xabcb"), Ok(Some(&1)));
+        assert_eq!(subtrie.remove("xabcb"), Ok(Some(1)));
+    }
+    assert_eq!(trie.len(), 1);
+    assert_eq!(trie.get("xq"), Some(&2));
+    assert!(trie.check_integrity());
+
+    // Removing a key below a subtrie, or the subtrie's own key, keeps the trie compressed.
+    let mut trie = Trie::new();
+    for (i, key) in ["xabc", "xabcA", "xabcAA", "xabcb", "xq"]
+        .into_iter()
+        .enumerate()
+    {
+        trie.insert(key, i);
+    }
+    {
+        let mut subtrie = trie.subtrie_mut("xabc").unwrap();
+        assert_eq!(subtrie.remove("xabcA"), Ok(Some(1)));
+        assert_eq!(subtrie.remove("xabcA"), Ok(None));
+        assert_eq!(subtrie.remove("xabc"), Ok(Some(0)));
+    }
+    assert_eq!(trie.len(), 3);
+    assert_eq!(trie.get("xabcAA"), Some(&2));
+    assert_eq!(
+        trie.iter().collect::<Vec<_>>(),
+        vec![(&"xabcAA", &2), (&"xabcb", &3), (&"xq", &4)]
+    );
+    assert_eq!(
+        trie.range::<str, _>(..).collect::<Vec<_>>(),
+        trie.iter().collect::<Vec<_>>()
+    );
+    assert!(trie.check_integrity());
+
+    // Removing the key of a subtrie without children removes its node, leaving it empty.
+    let mut trie = Trie::new();
+    trie.insert("a", 1);
+    trie.insert("b", 2);
+    {
+        let mut subtrie = trie.subtrie_mut("b").unwrap();
+        match subtrie.entry("b").unwrap() {
+            Entry::Occupied(entry) => assert_eq!(entry.remove(), 2),
+            Entry::Vacant(_) => panic!("expected an occupied entry"),
+        }
+        assert_eq!(subtrie.get("b"), Ok(None));
+        assert_eq!((&subtrie).iter().count(), 0);
+        assert!((&subtrie).is_leaf());
+        assert_eq!(subtrie.len(), 0);
+    }
+    assert_eq!(trie.iter().collect::<Vec<_>>(), vec![(&"a", &1)]);
+    assert_eq!(
+        trie.range::<str, _>(..).collect::<Vec<_>>(),
+        vec![(&"a", &1)]
+    );
+    assert!(trie.check_integrity());
+
+    // Keys can still be inserted into the empty subtrie.
+    {
+        let mut subtrie = trie.subtrie_mut("a").unwrap();
+        assert_eq!(subtrie.remove("a"), Ok(Some(1)));
+        assert_eq!(subtrie.insert("ab", 3), Ok(None));
+        assert_eq!(subtrie.get("ab"), Ok(Some(&3)));
+    }
+    assert_eq!(trie.iter().collect::<Vec<_>>(), vec![(&"ab", &3)]);
+    assert!(trie.check_integrity());
+}
+
 #[test]
 fn remove() {
     let mut trie = test_trie();
@@ -157,6 +313,86 @@ fn remove_non_existent() {
     assert_eq!(trie.len(), 2);
 }
 
+#[test]
+fn remove_prefix() {
+    let mut trie = test_trie();
+    let removed = trie.remove_prefix("abc");
+
+    assert_eq!(
+        removed.keys().cloned().collect::<Vec<_>>(),
+        vec!["abcd", "abcdef", "abcdefgh"]
+    );
+    assert_eq!(removed.len(), 3);
+    assert_eq!(removed.get(&"abcdef"), Some(&18));
+    assert!(removed.check_integrity());
+
+    assert_eq!(trie.len(), TEST_DATA.len() - 3);
+    assert!(trie.get(&"abcd").is_none());
+    assert_eq!(trie.get(&"ab"), Some(&16));
+    assert!(trie.check_integrity());
+
+    assert!(trie.remove_prefix("abx").is_empty());
+    assert_eq!(trie.remove_prefix("").len(), TEST_DATA.len() - 3);
+    assert!(trie.is_empty());
+}
+
+#[test]
+fn remove_prefix_merge() {
+    let mut trie = Trie::new();
+    trie.insert("hella", 1);
+    trie.insert("hellb", 2);
+    trie.insert("help", 3);
+
+    // Removing "hella" leaves the "hell" node with a single child.
+    assert_eq!(trie.remove_prefix("hella").len(), 1);
+    assert!(trie.check_integrity());
+    assert_eq!(trie.get(&"hellb"), Some(&2));
+}
+
+#[test]
+fn retain() {
+    let mut trie = test_trie();
+    trie.retain(|k, v| {
+        *v += 1;
+        k.len() % 2 == 0
+    });
+    assert_eq!(
+        trie.iter().map(|(&k, &v)| (k, v)).collect::<Vec<_>>(),
+        vec![
+            ("ab", 17),
+            ("abcd", 18),
+            ("abcdef", 19),
+            ("abcdefgh", 20),
+            ("acbdef", 31)
+        ]
+    );
+    assert!(trie.check_integrity());
+}
+
+#[test]
+fn retain_prefixes() {
+    let mut trie = test_trie();
+    let mut visited = vec![];
+    trie.retain_prefixes(|prefix| {
+        visited.push(prefix.to_vec());
+        !prefix.starts_with(b"abcde") && prefix.len() < 7
+    });
+    assert_eq!(
+        trie.keys().cloned().collect::<Vec<_>>(),
+        vec!["a", "ab", "abcd", "acbdef"]
+    );
+    assert!(trie.check_integrity());
+    assert!(!visited.contains(&b"abcdefgh".to_vec()));
+
+    // Nodes which end halfway through a byte aren't given the same bytes as their parents.
+    let distinct = visited.iter().collect::<HashSet<_>>();
+    assert_eq!(distinct.len(), visited.len());
+
+    trie.retain_prefixes(|_| false);
+    assert!(trie.is_empty());
+    assert!(trie.check_integrity());
+}
+
 #[test]
 fn nearest_ancestor_root() {
     let mut trie = Trie::new();
@@ -204,6 +440,81 @@ fn raw_ancestor() {
     assert_eq!(anc.len(), 2);
 }
 
+// Check that the subtrie prefix is correct for get_ancestor, when the path to the key passes
+// through value-less nodes beyond the ancestor.
+#[test]
+fn ancestor_prefix() {
+    let mut t = Trie::new();
+    t.insert("a", 1);
+    t.insert("abcx", 2);
+    t.insert("abcy", 3);
+    let anc = t.get_ancestor(&"abcz").unwrap();
+    assert_eq!(anc.prefix, "a".encode());
+}
+
+#[test]
+fn longest_prefix_match() {
+    let trie = test_trie();
+    assert_eq!(
+        trie.longest_prefix_match("abcdefz"),
+        Some((&"abcdef", &18, 6))
+    );
+    assert_eq!(trie.longest_prefix_match("abcd"), Some((&"abcd", &17, 4)));
+    assert_eq!(trie.longest_prefix_match("az"), Some((&"a", &15, 1)));
+    assert_eq!(trie.longest_prefix_match("z"), None);
+    assert_eq!(trie.longest_prefix_match(""), None);
+}
+
+#[test]
+fn longest_prefix_match_bytes() {
+    let mut trie = Trie::new();
+    trie.insert("if".to_string(), 1);
+    trie.insert("iff".to_string(), 2);
+    trie.insert("else".to_string(), 3);
+
+    // Tokenise a buffer by repeatedly taking the longest match.
+    let text = b"ifelseiffelse";
+    let mut pos = 0;
+    let mut tokens = vec![];
+    while let Some((_, &token, len)) = trie.longest_prefix_match_bytes(&text[pos..]) {
+        tokens.push(token);
+        pos += len;
+    }
+    assert_eq!(tokens, vec![1, 3, 2, 3]);
+    assert_eq!(pos, text.len());
+}
+
+#[test]
+fn ancestors() {
+    let trie = test_trie();
+    let ancestors = |key| {
+        trie.ancestors(key)
+            .map(|(&k, &v)| (k, v))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        ancestors("abcdefz"),
+        vec![("a", 15), ("ab", 16), ("abcd", 17), ("abcdef", 18)]
+    );
+    assert_eq!(ancestors("abcd"), vec![("a", 15), ("ab", 16), ("abcd", 17)]);
+    assert_eq!(ancestors("abc"), vec![("a", 15), ("ab", 16)]);
+    assert_eq!(ancestors("bcd"), vec![]);
+    assert_eq!(ancestors(""), vec![]);
+}
+
+#[test]
+fn ancestors_root() {
+    let mut trie = Trie::new();
+    trie.insert("/".to_string(), 0);
+    trie.insert("/a".to_string(), 1);
+    trie.insert("/a/b".to_string(), 2);
+    trie.insert("/a/bc".to_string(), 3);
+    trie.insert("".to_string(), 4);
+    let values: Vec<_> = trie.ancestors("/a/b/c").map(|(_, &v)| v).collect();
+    assert_eq!(values, vec![4, 0, 1, 2]);
+}
+
 // Check that the subtrie prefix is correct for raw_ancestor.
 #[test]
 fn raw_ancestor_prefix() {
@@ -225,6 +536,176 @@ fn iter() {
     assert_eq!(expected, observed);
 }
 
+#[test]
+fn iter_rev() {
+    let trie = test_trie();
+    let forward: Vec<_> = trie.iter().collect();
+    let mut backward: Vec<_> = trie.iter().rev().collect();
+    backward.reverse();
+    assert_eq!(forward, backward);
+
+    let last_two: Vec<_> = trie
+        .subtrie(&"abcd")
+        .unwrap()
+        .keys()
+        .rev()
+        .take(2)
+        .collect();
+    assert_eq!(last_two, vec![&"abcdefgh", &"abcdef"]);
+    assert_eq!(trie.values().next_back(), Some(&29));
+}
+
+#[test]
+fn iter_double_ended() {
+    let trie = test_trie();
+    let mut iter = trie.keys();
+    assert_eq!(iter.next(), Some(&"a"));
+    assert_eq!(iter.next_back(), Some(&"bcdefgh"));
+    assert_eq!(iter.next_back(), Some(&"acbdef"));
+    assert_eq!(iter.next(), Some(&"ab"));
+    assert_eq!(iter.next(), Some(&"abcd"));
+    assert_eq!(iter.next(), Some(&"abcdef"));
+    assert_eq!(iter.next_back(), Some(&"abcdefgh"));
+    assert_eq!(iter.next_back(), None);
+    assert_eq!(iter.next(), None);
+}
+
+#[test]
+fn children_rev() {
+    let trie = test_trie();
+    let forward: Vec<_> = trie.children().map(|c| c.prefix().clone()).collect();
+    let mut backward: Vec<_> = trie.children().rev().map(|c| c.prefix().clone()).collect();
+    backward.reverse();
+    assert_eq!(forward, backward);
+}
+
+#[test]
+fn iter_mut() {
+    let mut trie = test_trie();
+    for (key, value) in trie.iter_mut() {
+        *value += key.len() as u32;
+    }
+    for &(key, val) in &TEST_DATA {
+        assert_eq!(*trie.get(&key).unwrap(), val + key.len() as u32);
+    }
+
+    let order: Vec<_> = trie.iter_mut().map(|(k, _)| *k).collect();
+    assert_eq!(order, trie.keys().cloned().collect::<Vec<_>>());
+}
+
+#[test]
+fn values_mut() {
+    let mut trie = test_trie();
+    trie.subtrie_mut(&"abcd")
+        .unwrap()
+        .values_mut()
+        .for_each(|v| *v = 0);
+    assert_eq!(trie.get(&"abcdefgh"), Some(&0));
+    assert_eq!(trie.get(&"abcd"), Some(&0));
+    assert_eq!(trie.get(&"ab"), Some(&16));
+
+    for v in trie.values_mut() {
+        *v /= 2;
+    }
+    assert_eq!(trie.get(&"ab"), Some(&8));
+}
+
+#[test]
+fn into_iter() {
+    let trie = test_trie();
+    let expected: Vec<_> = trie.iter().map(|(&k, &v)| (k, v)).collect();
+    let iter = trie.into_iter();
+    assert_eq!(iter.len(), TEST_DATA.len());
+    assert_eq!(iter.collect::<Vec<_>>(), expected);
+}
+
+#[test]
+fn into_iter_non_clone() {
+    let mut trie = Trie::new();
+    trie.insert("b", Box::new(2));
+    trie.insert("a", Box::new(1));
+    let values: Vec<Box<u32>> = trie.into_iter().map(|(_, v)| v).collect();
+    assert_eq!(values, vec![Box::new(1), Box::new(2)]);
+}
+
+#[test]
+fn drain() {
+    let mut trie = test_trie();
+    assert_eq!(trie.drain().count(), TEST_DATA.len());
+    assert!(trie.is_empty());
+    assert!(trie.check_integrity());
+    trie.insert("abc", 1);
+    assert_eq!(trie.get(&"abc"), Some(&1));
+}
+
+#[test]
+fn ref_into_iter() {
+    let mut trie = test_trie();
+    for (_, v) in &mut trie {
+        *v += 1;
+    }
+    let mut count = 0;
+    for (k, v) in &trie {
+        assert_eq!(
+            *v,
+            TEST_DATA.iter().find(|&&(key, _)| key == *k).unwrap().1 + 1
+        );
+        count += 1;
+    }
+    assert_eq!(count, TEST_DATA.len());
+}
+
+#[test]
+fn range() {
+    let trie = test_trie();
+    fn keys(r: Vec<(&&'static str, &u32)>) -> Vec<&'static str> {
+        r.into_iter().map(|(k, _)| *k).collect()
+    }
+
+    assert_eq!(
+        keys(trie.range("ab".."abcdef").collect()),
+        vec!["ab", "abcd"]
+    );
+    assert_eq!(
+        keys(trie.range("ab"..="abcdef").collect()),
+        vec!["ab", "abcd", "abcdef"]
+    );
+    assert_eq!(
+        keys(trie.range("abc"..).collect()),
+        vec!["abcd", "abcdef", "abcdefgh", "acbdef", "bcdefgh"]
+    );
+    assert_eq!(keys(trie.range(.."ab").collect()), vec!["a"]);
+    assert_eq!(
+        keys(trie.range::<str, _>(..).collect()),
+        keys(trie.iter().collect())
+    );
+    assert_eq!(
+        keys(
+            trie.range::<str, _>((Bound::Excluded("abcd"), Bound::Excluded("b")))
+                .collect()
+        ),
+        vec!["abcdef", "abcdefgh", "acbdef"]
+    );
+    assert_eq!(trie.range("abcde".."abcdef").count(), 0);
+    assert_eq!(trie.range("b".."a").count(), 0);
+    assert_eq!(trie.range("c"..).count(), 0);
+}
+
+#[test]
+fn range_after_subtrie_remove() {
+    let mut trie = Trie::new();
+    trie.insert("a", 1);
+    trie.insert("b", 2);
+    assert_eq!(trie.subtrie_mut("b").unwrap().remove("b"), Ok(Some(2)));
+    assert!(trie.check_integrity());
+
+    assert_eq!(trie.range("a"..).collect::<Vec<_>>(), vec![(&"a", &1)]);
+    assert_eq!(trie.range(..="c").collect::<Vec<_>>(), vec![(&"a", &1)]);
+    assert_eq!(trie.range("a"..="c").collect::<Vec<_>>(), vec![(&"a", &1)]);
+    assert_eq!(trie.range("a".."b").collect::<Vec<_>>(), vec![(&"a", &1)]);
+    assert_eq!(trie.range("b"..).count(), 0);
+}
+
 #[test]
 fn get_raw_descendant() {
     let trie = test_trie();
@@ -358,6 +839,73 @@ fn int_keys() {
     assert!(trie.check_integrity());
 }
 
+#[test]
+fn signed_int_keys_order() {
+    let keys = [i32::MIN, -70000, -256, -1, 0, 1, 255, 70000, i32::MAX];
+    let trie: Trie<i32, ()> = keys.iter().rev().map(|&k| (k, ())).collect();
+    assert_eq!(trie.keys().cloned().collect::<Vec<_>>(), keys);
+
+    let keys = [-128i8, -1, 0, 127];
+    let trie: Trie<i8, ()> = keys.iter().rev().map(|&k| (k, ())).collect();
+    assert_eq!(trie.keys().cloned().collect::<Vec<_>>(), keys);
+}
+
+#[test]
+fn signed_int_vec_keys_order() {
+    let keys = vec![
+        vec![-5i64],
+        vec![-5, -3],
+        vec![-5, 3],
+        vec![0],
+        vec![2, i64::MIN],
+    ];
+    let trie: Trie<Vec<i64>, ()> = keys.iter().rev().map(|k| (k.clone(), ())).collect();
+    assert_eq!(trie.keys().cloned().collect::<Vec<_>>(), keys);
+}
+
+#[test]
+fn tuple_keys_distinct() {
+    let mut trie = Trie::new();
+    trie.insert(("a", "bc"), 1);
+    trie.insert(("ab", "c"), 2);
+    trie.insert(("a\0", "c"), 3);
+    trie.insert(("a", "\0c"), 4);
+    assert_eq!(trie.get(&("a", "bc")), Some(&1));
+    assert_eq!(trie.get(&("ab", "c")), Some(&2));
+    assert_eq!(trie.get(&("a\0", "c")), Some(&3));
+    assert_eq!(trie.get(&("a", "\0c")), Some(&4));
+    assert!(trie.check_integrity());
+}
+
+#[test]
+fn tuple_keys_order() {
+    let keys = vec![
+        ("a".to_string(), -1i64),
+        ("a".to_string(), 5),
+        ("a\0".to_string(), 0),
+        ("ab".to_string(), -3),
+        ("b".to_string(), 0),
+    ];
+    let trie: Trie<_, _> = keys.iter().rev().map(|k| (k.clone(), ())).collect();
+    assert_eq!(trie.keys().cloned().collect::<Vec<_>>(), keys);
+}
+
+#[test]
+fn tuple_keys_prefix() {
+    let mut trie = Trie::new();
+    trie.insert(("acme".to_string(), 1u64), "x");
+    trie.insert(("acme".to_string(), 2u64), "y");
+    trie.insert(("acmex".to_string(), 1u64), "z");
+
+    let acme = trie.get_raw_descendant(&("acme",)).unwrap();
+    assert_eq!(acme.values().cloned().collect::<Vec<_>>(), vec!["x", "y"]);
+
+    let removed = trie.remove_prefix(&("acme",));
+    assert_eq!(removed.len(), 2);
+    assert_eq!(trie.len(), 1);
+    assert!(trie.check_integrity());
+}
+
 #[test]
 fn from_iter() {
     let trie: Trie<&str, u32> = Trie::from_iter(vec![("test", 10), ("hello", 12)]);
@@ -478,3 +1026,612 @@ fn clone() {
 
     assert_eq!(t1, t2);
 }
+
+#[test]
+fn path_trie() {
+    let mut trie: PathTrie<String, u32> = TEST_DATA
+        .iter()
+        .map(|&(key, val)| (key.to_string(), val))
+        .collect();
+    assert!(trie.check_integrity());
+    assert_eq!(trie.len(), TEST_DATA.len());
+
+    for &(key, val) in &TEST_DATA {
+        assert_eq!(trie.get(key), Some(&val));
+    }
+    assert_eq!(trie.get("abc"), None);
+    assert_eq!(trie.get_ancestor("abcde"), Some(("abcd".to_string(), &17)));
+    assert_eq!(trie.get_ancestor_value("b"), None);
+
+    let keys: Vec<String> = trie.keys().collect();
+    assert_eq!(
+        keys,
+        ["a", "ab", "abcd", "abcdef", "abcdefgh", "acbdef", "bcdefgh"]
+    );
+    let prefixed: Vec<String> = trie.iter_prefix("abcde").map(|(k, _)| k).collect();
+    assert_eq!(prefixed, ["abcdef", "abcdefgh"]);
+    assert_eq!(trie.iter_prefix("abd").count(), 0);
+
+    *trie.get_mut("ab").unwrap() += 100;
+    assert_eq!(trie.remove("ab"), Some(116));
+    assert_eq!(trie.remove("ab"), None);
+    assert_eq!(trie.remove("abc"), None);
+    assert_eq!(trie.len(), TEST_DATA.len() - 1);
+    assert!(trie.check_integrity());
+}
+
+#[test]
+fn path_trie_tuple_keys() {
+    let mut trie = PathTrie::new();
+    trie.insert(("tenant".to_string(), 2u64), "b");
+    trie.insert(("tenant".to_string(), 1u64), "a");
+    trie.insert(("other".to_string(), 1u64), "c");
+    assert!(trie.check_integrity());
+
+    let tenant: Vec<_> = trie.iter_prefix(&("tenant".to_string(),)).collect();
+    assert_eq!(
+        tenant,
+        [
+            (("tenant".to_string(), 1), &"a"),
+            (("tenant".to_string(), 2), &"b")
+        ]
+    );
+}
+
+/// Key which encodes to the given number of zero bytes, so that each key is a prefix of all
+/// the longer ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ZeroKey(usize);
+
+impl TrieKey for ZeroKey {
+    fn encode_bytes(&self) -> Vec<u8> {
+        vec![0; self.0]
+    }
+}
+
+#[test]
+fn very_deep_trie() {
+    // A chain of 10k nodes, plus a key 100k nibbles long below them. Keys are inserted longest
+    // first, so that each insert splits the node below the root rather than walking the chain.
+    const DEPTH: usize = 10_000;
+    const LONGEST: usize = 50_000;
+
+    fn run() {
+        let mut trie = Trie::new();
+        trie.insert(ZeroKey(LONGEST), LONGEST);
+        for i in (1..=DEPTH).rev() {
+            trie.insert(ZeroKey(i), i);
+        }
+        assert_eq!(trie.len(), DEPTH + 1);
+
+        let mut clone = trie.clone();
+        assert_eq!(clone.len(), DEPTH + 1);
+        assert_eq!(clone.get(&ZeroKey(LONGEST)), Some(&LONGEST));
+
+        assert_eq!(trie.remove(&ZeroKey(LONGEST)), Some(LONGEST));
+        assert_eq!(trie.remove(&ZeroKey(DEPTH / 2)), Some(DEPTH / 2));
+        assert_eq!(trie.remove(&ZeroKey(DEPTH / 2)), None);
+        assert_eq!(trie.remove(&ZeroKey(LONGEST - 1)), None);
+        assert_eq!(trie.len(), DEPTH - 1);
+        assert_eq!(trie.get(&ZeroKey(DEPTH)), Some(&DEPTH));
+        assert_eq!(clone.get(&ZeroKey(LONGEST)), Some(&LONGEST));
+
+        // Splitting and set operations follow the chain down too.
+        let mut lower = clone.clone();
+        let upper = lower.split_off(&ZeroKey(DEPTH / 2));
+        assert_eq!(lower.len(), DEPTH / 2 - 1);
+        assert_eq!(upper.len(), DEPTH / 2 + 2);
+        lower.union_with(upper, |value, _| value);
+        assert_eq!(lower.len(), DEPTH + 1);
+
+        let evens: Trie<_, _> = (2..=DEPTH)
+            .rev()
+            .step_by(2)
+            .map(|i| (ZeroKey(i), i))
+            .collect();
+        let mut common = lower.clone();
+        common.intersection_with(evens.clone(), |value, _| value);
+        assert_eq!(common.len(), DEPTH / 2);
+        lower.difference(&evens);
+        assert_eq!(lower.len(), DEPTH / 2 + 1);
+        assert_eq!(lower.get(&ZeroKey(LONGEST)), Some(&LONGEST));
+        assert_eq!(common.get(&ZeroKey(DEPTH)), Some(&DEPTH));
+
+        // As does retaining keys.
+        lower.retain(|key, _| key.0 % 3 != 0);
+        assert_eq!(lower.len(), DEPTH / 3 + 1);
+        common.retain_prefixes(|prefix| prefix.len() <= DEPTH / 2);
+        assert_eq!(common.len(), DEPTH / 4);
+        drop(lower);
+        drop(common);
+
+        drop(trie);
+        assert_eq!(clone.remove(&ZeroKey(1)), Some(1));
+        drop(clone);
+    }
+
+    // Use a small stack, so that recursing over the nodes would overflow it.
+    std::thread::Builder::new()
+        .stack_size(128 * 1024)
+        .spawn(run)
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn byte_granularity() {
+    let mut trie = Trie::with_granularity(Granularity::Byte);
+    for &(key, val) in &TEST_DATA {
+        trie.insert(key, val);
+    }
+    assert!(trie.check_integrity());
+    assert_eq!(trie.granularity(), Granularity::Byte);
+    assert_eq!(trie, test_trie());
+    assert_eq!(trie.get_ancestor_value(&"abcde"), Some(&17));
+
+    // Enough children at one node for it to store them in a table of 256.
+    let keys: Vec<Vec<u8>> = (0..=255).map(|b| vec![b'x', b]).collect();
+    let mut wide_trie = Trie::with_granularity(Granularity::Byte);
+    for k in &keys {
+        wide_trie.insert(k.clone(), 0);
+    }
+    assert!(wide_trie.check_integrity());
+    assert_eq!(
+        wide_trie.subtrie(&vec![b'x']).unwrap().children().count(),
+        256
+    );
+    for k in &keys[10..] {
+        assert_eq!(wide_trie.remove(k), Some(0));
+        assert!(wide_trie.check_integrity());
+    }
+    assert_eq!(wide_trie.len(), 10);
+
+    let removed = trie.remove_prefix(&"abc");
+    assert_eq!(removed.granularity(), Granularity::Byte);
+    assert_eq!(removed.len(), 3);
+    assert!(removed.check_integrity());
+    trie.drain();
+    assert_eq!(trie.granularity(), Granularity::Byte);
+}
+
+/// Key which encodes to a single nibble.
+#[derive(PartialEq, Eq)]
+struct NibbleKey(u8);
+
+impl TrieKey for NibbleKey {
+    fn encode_bytes(&self) -> Vec<u8> {
+        unimplemented!()
+    }
+
+    fn encode(&self) -> Nibblet {
+        let mut nv = Nibblet::new();
+        nv.push(self.0);
+        nv
+    }
+}
+
+#[test]
+#[should_panic(expected = "whole bytes")]
+fn byte_granularity_partial_byte_key() {
+    let mut trie = Trie::with_granularity(Granularity::Byte);
+    trie.insert(NibbleKey(1), ());
+}
+
+#[test]
+fn encode_bits_prefixes() {
+    let bytes = [0b1010_0110, 0b1000_0000];
+    let whole = encode_bits(&bytes, 9);
+    assert_eq!(
+        (0..whole.len()).map(|i| whole.get(i)).collect::<Vec<_>>(),
+        [1, 0, 1, 0, 0, 1, 1, 0, 1]
+    );
+    for len in 0..9 {
+        let mut prefix = whole.clone();
+        prefix.split(len);
+        assert_eq!(encode_bits(&bytes, len), prefix);
+    }
+}
+
+#[test]
+fn ip_routing() {
+    let prefix = |a, b, c, d, len| IpPrefix::new(Ipv4Addr::new(a, b, c, d), len);
+
+    let mut routes = Trie::new();
+    routes.insert(prefix(0, 0, 0, 0, 0), "default");
+    routes.insert(prefix(10, 0, 0, 0, 8), "a");
+    routes.insert(prefix(10, 8, 0, 0, 13), "b");
+    routes.insert(prefix(10, 8, 1, 0, 24), "c");
+    routes.insert(prefix(10, 16, 0, 0, 13), "d");
+    assert!(routes.check_integrity());
+
+    let route = |a, b, c, d| routes.get_ancestor_value(&IpPrefix::from(Ipv4Addr::new(a, b, c, d)));
+    assert_eq!(route(10, 8, 1, 200), Some(&"c"));
+    assert_eq!(route(10, 8, 2, 0), Some(&"b"));
+    assert_eq!(route(10, 15, 255, 255), Some(&"b"));
+    assert_eq!(route(10, 7, 255, 255), Some(&"a"));
+    assert_eq!(route(10, 23, 0, 0), Some(&"d"));
+    assert_eq!(route(11, 0, 0, 0), Some(&"default"));
+
+    // Match lengths are counted in bits.
+    let (_, _, len) = routes
+        .longest_prefix_match(&IpPrefix::from(Ipv4Addr::new(10, 9, 0, 1)))
+        .unwrap();
+    assert_eq!(len, 13);
+
+    // Host bits are ignored, and prefixes are visited in order.
+    assert_eq!(prefix(10, 9, 1, 1, 13), prefix(10, 8, 0, 0, 13));
+    assert_eq!(routes.get(&prefix(10, 9, 1, 1, 13)), Some(&"b"));
+    let keys: Vec<_> = routes.keys().cloned().collect();
+    let mut sorted = keys.clone();
+    sorted.sort();
+    assert_eq!(keys, sorted);
+    assert_eq!(keys[2].to_string(), "10.8.0.0/13");
+    assert!(keys[2].contains(Ipv4Addr::new(10, 15, 0, 1)));
+    assert!(!keys[2].contains(Ipv4Addr::new(10, 16, 0, 1)));
+
+    // The addresses in a network can be found from its prefix.
+    let hosts: Trie<Ipv4Addr, ()> = [[10, 8, 0, 1], [10, 9, 0, 1], [10, 16, 0, 1], [11, 8, 0, 1]]
+        .into_iter()
+        .map(|octets| (Ipv4Addr::from(octets), ()))
+        .collect();
+    let network = hosts.get_raw_descendant(&prefix(10, 8, 0, 0, 13)).unwrap();
+    assert_eq!(network.keys().count(), 2);
+
+    let mut routes6 = Trie::new();
+    let prefix6 = |s: &str, len| IpPrefix::new(s.parse::<Ipv6Addr>().unwrap(), len);
+    routes6.insert(prefix6("2001:db8::", 32), 1);
+    routes6.insert(prefix6("2001:db8:1::", 48), 2);
+    let route6 =
+        |s: &str| routes6.get_ancestor_value(&IpPrefix::from(s.parse::<Ipv6Addr>().unwrap()));
+    assert_eq!(route6("2001:db8:1::1"), Some(&2));
+    assert_eq!(route6("2001:db8:2::1"), Some(&1));
+    assert_eq!(route6("2001:db9::1"), None);
+    assert!(routes6.check_integrity());
+}
+
+#[test]
+fn ip_key_components() {
+    // Addresses and prefixes are encoded as whole bytes within tuples.
+    let prefix = |a, b, c, d, len| IpPrefix::new(Ipv4Addr::new(a, b, c, d), len);
+    let keys = [
+        (1, prefix(10, 0, 0, 0, 8)),
+        (1, prefix(10, 0, 0, 0, 9)),
+        (1, prefix(10, 128, 0, 0, 9)),
+        (2, prefix(0, 0, 0, 0, 0)),
+    ];
+    let mut trie = Trie::with_granularity(Granularity::Byte);
+    for (i, &key) in keys.iter().rev().enumerate() {
+        trie.insert(key, i);
+    }
+    assert!(trie.check_integrity());
+    assert!(trie.keys().eq(keys.iter()));
+    assert_eq!(trie.get_raw_descendant(&(1,)).unwrap().len(), 3);
+
+    let mut hosts = Trie::new();
+    hosts.insert(("a", Ipv4Addr::new(10, 0, 0, 1)), 1);
+    hosts.insert(("a", Ipv4Addr::new(10, 0, 0, 2)), 2);
+    hosts.insert(("b", Ipv4Addr::new(10, 0, 0, 1)), 3);
+    assert_eq!(hosts.get(&("a", Ipv4Addr::new(10, 0, 0, 2))), Some(&2));
+    assert!(hosts.check_integrity());
+}
+
+#[test]
+#[should_panic(expected = "bit by bit")]
+fn byte_granularity_bitwise_key() {
+    let _: Trie<IpPrefix<Ipv4Addr>, ()> = Trie::with_granularity(Granularity::Byte);
+}
+
+#[test]
+fn trie_set() {
+    let mut set: TrieSet<&str> = TEST_DATA.iter().map(|&(key, _)| key).collect();
+    assert!(set.check_integrity());
+    assert_eq!(set.len(), TEST_DATA.len());
+    assert!(set.contains("abcd"));
+    assert!(!set.contains("abc"));
+    assert!(!set.insert("abcd"));
+    assert!(set.insert("abc"));
+    assert!(set.remove("abc"));
+    assert!(!set.remove("abc"));
+
+    assert_eq!(set.get_ancestor("abcde"), Some(&"abcd"));
+    assert_eq!(set.get_ancestor("abcd"), Some(&"abcd"));
+    assert_eq!(set.get_ancestor("b"), None);
+    assert!(set.has_ancestor("abcxyz"));
+    assert!(!set.has_ancestor("xyz"));
+
+    let prefixed: Vec<_> = set.iter_prefix("abc").collect();
+    assert_eq!(prefixed, [&"abcd", &"abcdef", &"abcdefgh"]);
+    assert_eq!(set.iter_prefix("abd").count(), 0);
+    assert_eq!(set.iter().next_back(), Some(&"bcdefgh"));
+
+    let other: TrieSet<&str> = ["a", "abc", "b", "bcdefgh"].into_iter().collect();
+    let union: Vec<_> = set.union(&other).copied().collect();
+    assert_eq!(
+        union,
+        [
+            "a", "ab", "abc", "abcd", "abcdef", "abcdefgh", "acbdef", "b", "bcdefgh"
+        ]
+    );
+    let intersection: Vec<_> = set.intersection(&other).collect();
+    assert_eq!(intersection, [&"a", &"bcdefgh"]);
+    let difference: Vec<_> = other.difference(&set).collect();
+    assert_eq!(difference, [&"abc", &"b"]);
+    let symmetric: Vec<_> = set.symmetric_difference(&other).copied().collect();
+    assert_eq!(
+        symmetric,
+        ["ab", "abc", "abcd", "abcdef", "abcdefgh", "acbdef", "b"]
+    );
+
+    set.extend(other);
+    assert_eq!(set.into_iter().collect::<Vec<_>>(), union);
+
+    let mut set = TrieSet::with_granularity(Granularity::Byte);
+    assert!(set.insert("a"));
+    set.clear();
+    assert!(set.is_empty());
+    assert!(set.check_integrity());
+    assert_eq!(set.granularity(), Granularity::Byte);
+}
+
+#[test]
+fn union_intersection_difference() {
+    let mut trie = test_trie();
+    let mut other = Trie::new();
+    other.insert("abc", 1);
+    other.insert("abcd", 2);
+    other.insert("b", 3);
+    other.insert("bcdefgh", 4);
+
+    let mut union = trie.clone();
+    union.union_with(other.clone(), |a, b| a * 100 + b);
+    assert!(union.check_integrity());
+    assert_eq!(union.len(), TEST_DATA.len() + 2);
+    assert_eq!(union.get("abcd"), Some(&1702));
+    assert_eq!(union.get("abc"), Some(&1));
+    assert_eq!(union.get("b"), Some(&3));
+    assert_eq!(union.get("a"), Some(&15));
+
+    let mut intersection = trie.clone();
+    intersection.intersection_with(other.clone(), |a, b| a * 100 + b);
+    assert!(intersection.check_integrity());
+    let pairs: Vec<_> = intersection.iter().map(|(&k, &v)| (k, v)).collect();
+    assert_eq!(pairs, [("abcd", 1702), ("bcdefgh", 2904)]);
+
+    trie.difference(&other);
+    assert!(trie.check_integrity());
+    assert_eq!(trie.len(), TEST_DATA.len() - 2);
+    assert_eq!(trie.get("abcd"), None);
+    assert_eq!(trie.get("abcdef"), Some(&18));
+    trie.difference(&test_trie());
+    assert!(trie.is_empty());
+}
+
+#[test]
+fn split_off_append() {
+    let mut trie = test_trie();
+    let mut right = trie.split_off("abcdd");
+    assert!(trie.check_integrity());
+    assert!(right.check_integrity());
+    assert_eq!(
+        trie.keys().copied().collect::<Vec<_>>(),
+        ["a", "ab", "abcd"]
+    );
+    assert_eq!(
+        right.keys().copied().collect::<Vec<_>>(),
+        ["abcdef", "abcdefgh", "acbdef", "bcdefgh"]
+    );
+
+    let mut all = trie.split_off("");
+    assert!(trie.is_empty());
+    assert_eq!(all.len(), 3);
+
+    right.insert("a", 100);
+    all.append(&mut right);
+    assert!(all.check_integrity());
+    assert!(right.is_empty());
+    assert_eq!(all.len(), TEST_DATA.len());
+    assert_eq!(all.get("a"), Some(&100));
+    assert_eq!(all.get("abcdef"), Some(&18));
+}
+
+#[test]
+fn send_unsync_values() {
+    fn assert_send<T: Send>(_: &T) {}
+
+    // Only the nodes of a PersistentTrie are shared, so other tries can be sent to another
+    // thread even if their values can't be shared between threads.
+    let mut trie = Trie::new();
+    trie.insert("a".to_string(), Cell::new(1u32));
+    assert_send(&trie);
+    let mut path_trie = PathTrie::<String, _>::new();
+    path_trie.insert("a".to_string(), Cell::new(1u32));
+    assert_send(&path_trie);
+
+    let trie = thread::spawn(move || {
+        trie.get("a").unwrap().set(2);
+        trie
+    })
+    .join()
+    .unwrap();
+    assert_eq!(trie.get("a").map(Cell::get), Some(2));
+}
+
+#[test]
+fn persistent_trie() {
+    let v1 = PersistentTrie::from(test_trie());
+    let v2 = v1.insert("abcx", 1).insert("ab", 2);
+    let v3 = v2.remove("abcdef").remove("a");
+    let v4 = v3.remove("abcdef");
+    for version in [&v1, &v2, &v3, &v4] {
+        assert!(version.check_integrity());
+    }
+
+    assert_eq!(v1.len(), TEST_DATA.len());
+    for &(key, value) in &TEST_DATA {
+        assert_eq!(v1.get(key), Some(&value));
+    }
+    assert_eq!(v1.get("abcx"), None);
+    assert_eq!(v2.len(), TEST_DATA.len() + 1);
+    assert_eq!(v2.get("abcx"), Some(&1));
+    assert_eq!(v2.get("ab"), Some(&2));
+    assert_eq!(v3.len(), TEST_DATA.len() - 1);
+    assert_eq!(v3.get("abcdef"), None);
+    assert_eq!(v3.get_ancestor_value("abcdefg"), Some(&17));
+    assert_eq!(
+        v3.keys().copied().collect::<Vec<_>>(),
+        ["ab", "abcd", "abcdefgh", "abcx", "acbdef", "bcdefgh"]
+    );
+    assert_eq!(v4, v3);
+
+    // Nodes off the paths to the changed keys are shared between the versions.
+    fn node(version: &PersistentTrie<&'static str, u32>, key: &str) -> *const () {
+        ptr::from_ref(version.subtrie(key).unwrap().node).cast()
+    }
+    assert!(ptr::eq(node(&v1, "bcdefgh"), node(&v3, "bcdefgh")));
+    assert!(ptr::eq(node(&v1, "acbdef"), node(&v2, "acbdef")));
+    assert!(!ptr::eq(node(&v1, "ab"), node(&v2, "ab")));
+    assert!(ptr::eq(node(&v3, "abcdefgh"), node(&v4, "abcdefgh")));
+}
+
+#[test]
+fn persistent_trie_snapshots() {
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    let mut trie = PersistentTrie::new();
+    for i in 0..100u32 {
+        trie.insert_mut(i, 0);
+    }
+    let before = trie.snapshot();
+    assert_send_sync(&before);
+
+    // Each reader checks that its snapshot doesn't change while the writer updates the trie.
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let snapshot = trie.snapshot();
+            s.spawn(move || {
+                for _ in 0..100 {
+                    assert_eq!(snapshot.len(), 100);
+                    assert!(snapshot.values().all(|&v| v == 0));
+                }
+            });
+        }
+        for i in 0..50u32 {
+            *trie.get_mut(&i).unwrap() += 1;
+            assert_eq!(trie.remove_mut(&(i + 50)), Some(0));
+            assert_eq!(trie.insert_mut(i + 1000, 1), None);
+        }
+    });
+
+    assert!(before.check_integrity());
+    assert!(trie.check_integrity());
+    assert_eq!(before.len(), 100);
+    assert!(before.values().all(|&v| v == 0));
+    assert_eq!(trie.len(), 100);
+    assert!(trie.values().all(|&v| v == 1));
+    assert_eq!(trie.get_mut(&50), None);
+    assert_eq!(trie.remove_mut(&50), None);
+}
+
+#[test]
+fn concurrent_trie_stress() {
+    const THREADS: u32 = 8;
+    const KEYS: u32 = 2000;
+
+    let trie = ConcurrentTrie::new();
+    trie.insert(String::new(), 0);
+    trie.insert("t".to_string(), 1);
+
+    // Each thread inserts and removes its own keys, and counts the shared keys.
+    thread::scope(|s| {
+        for t in 0..THREADS {
+            let trie = &trie;
+            s.spawn(move || {
+                for i in 0..KEYS {
+                    let key = format!("t{}/{}.", t, i);
+                    assert_eq!(trie.insert(key.clone(), i), None);
+                    assert_eq!(trie.get(&key), Some(i));
+                    trie.map_with_default(format!("shared/{}", i % 100), |n| *n += 1, 1);
+                    if i % 2 == 1 {
+                        assert_eq!(trie.remove(&key), Some(i));
+                    }
+                    let (prefix, _, _) = trie.longest_prefix_match(&format!("{}x", key)).unwrap();
+                    assert_eq!(prefix, if i % 2 == 0 { &key[..] } else { "t" });
+                }
+            });
+        }
+    });
+
+    assert!(trie.check_integrity());
+    assert_eq!(trie.len() as u32, 2 + THREADS * KEYS / 2 + 100);
+    for i in 0..100 {
+        assert_eq!(
+            trie.get(&format!("shared/{}", i)),
+            Some(THREADS * KEYS / 100)
+        );
+    }
+    assert_eq!(
+        trie.longest_prefix_match("t3/11."),
+        Some(("t".to_string(), 1, 1))
+    );
+    assert_eq!(trie.longest_prefix_match("u"), Some((String::new(), 0, 0)));
+
+    let trie = trie.into_trie();
+    assert!(trie.check_integrity());
+    assert_eq!(trie.get("t3/10."), Some(&10));
+    assert_eq!(trie.get("t3/11."), None);
+}
+
+#[test]
+fn concurrent_trie_routing() {
+    let routes = ConcurrentTrie::new();
+    for (addr, len) in [([0, 0, 0, 0], 0), ([10, 0, 0, 0], 7), ([10, 1, 0, 0], 16)] {
+        routes.insert(IpPrefix::new(Ipv4Addr::from(addr), len), len);
+    }
+    let route = |addr: [u8; 4]| {
+        let prefix = IpPrefix::from(Ipv4Addr::from(addr));
+        routes.longest_prefix_match(&prefix).map(|(_, len, _)| len)
+    };
+    assert_eq!(route([10, 1, 2, 3]), Some(16));
+    assert_eq!(route([11, 1, 2, 3]), Some(7));
+    assert_eq!(route([12, 1, 2, 3]), Some(0));
+    routes.remove(&IpPrefix::new(Ipv4Addr::new(0, 0, 0, 0), 0));
+    assert_eq!(route([12, 1, 2, 3]), None);
+    assert!(routes.check_integrity());
+}
+
+#[test]
+fn concurrent_trie_shards() {
+    fn shards_used<K: TrieKey, V>(trie: &ConcurrentTrie<K, V>) -> usize {
+        let shards = trie.shards.iter();
+        shards
+            .filter(|shard| !shard.read().unwrap().is_empty())
+            .count()
+    }
+
+    // Keys which only share their first few bytes, or the network part of an address, are
+    // spread between the shards.
+    let urls = ConcurrentTrie::new();
+    let hosts = ConcurrentTrie::new();
+    for i in 0..=255 {
+        urls.insert(format!("https://{}.example.com/", i), u32::from(i));
+        hosts.insert(IpPrefix::from(Ipv4Addr::new(10, 0, 0, i)), u32::from(i));
+    }
+    assert!(shards_used(&urls) > 32);
+    assert!(shards_used(&hosts) > 32);
+
+    // Prefixes too short to choose a shard by are still found.
+    urls.insert("https://".to_string(), 256);
+    hosts.insert(IpPrefix::new(Ipv4Addr::new(10, 0, 0, 0), 8), 256);
+    assert_eq!(
+        urls.longest_prefix_match("https://300.example.com/"),
+        Some(("https://".to_string(), 256, 8))
+    );
+    assert_eq!(
+        hosts
+            .longest_prefix_match(&IpPrefix::from(Ipv4Addr::new(10, 1, 0, 0)))
+            .map(|(_, value, len)| (value, len)),
+        Some((256, 8))
+    );
+    assert!(urls.check_integrity() && hosts.check_integrity());
+}
@@ -1,49 +1,193 @@
 //! This module contains the core algorithms.
 
-use crate::TrieKey;
-use crate::TrieNode;
-use crate::keys::{KeyMatch, match_keys};
+use crate::child_map::{Child, ChildMap, ChildPtr, Owned, Shared};
+use crate::keys::{KeyMatch, Nibbles, check_keys, match_keys};
+use crate::trie_node::KeyValue;
+use crate::{Granularity, TrieKey, TrieNode};
 use std::borrow::Borrow;
+use std::mem;
 
 use nibble_vec::Nibblet;
 
 use self::DescendantResult::*;
+use self::KeyPosition::*;
 
-impl<K, V> TrieNode<K, V>
+impl<K, V, P: ChildPtr> TrieNode<K, V, P>
 where
     K: TrieKey,
 {
     #[inline]
-    pub fn get(&self, nv: &Nibblet) -> Option<&TrieNode<K, V>> {
-        iterative_get(self, nv)
+    pub fn get<N>(&self, nv: &N, granularity: Granularity) -> Option<&TrieNode<K, V, P>>
+    where
+        N: ?Sized + Nibbles,
+    {
+        iterative_get(self, nv, granularity)
+    }
+    #[inline]
+    pub fn get_mut<N>(&mut self, nv: &N, granularity: Granularity) -> Option<&mut TrieNode<K, V, P>>
+    where
+        N: ?Sized + Nibbles,
+    {
+        iterative_get_mut(self, nv, granularity)
     }
     #[inline]
-    pub fn get_mut(&mut self, nv: &Nibblet) -> Option<&mut TrieNode<K, V>> {
-        iterative_get_mut(self, nv)
+    pub fn insert(&mut self, key: K, value: V, nv: Nibblet, granularity: Granularity) -> Option<V> {
+        iterative_insert(self, key, value, nv, granularity)
     }
     #[inline]
-    pub fn insert(&mut self, key: K, value: V, nv: Nibblet) -> Option<V> {
-        iterative_insert(self, key, value, nv)
+    pub fn find_position(
+        &mut self,
+        nv: &Nibblet,
+        granularity: Granularity,
+    ) -> KeyPosition<'_, K, V, P> {
+        find_position(self, nv, granularity)
     }
     #[inline]
-    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V>
+    pub fn remove<Q: ?Sized>(
+        &mut self,
+        key: &Q,
+        nv: &Nibblet,
+        granularity: Granularity,
+    ) -> Option<V>
     where
         K: Borrow<Q>,
         Q: TrieKey,
     {
-        recursive_remove(self, key)
+        iterative_remove(self, key, nv, granularity)
+    }
+    #[inline]
+    pub fn remove_prefix(
+        &mut self,
+        nv: &Nibblet,
+        granularity: Granularity,
+    ) -> Option<Child<K, V, P>> {
+        remove_prefix(self, nv, granularity)
+    }
+    #[inline]
+    pub fn retain<F>(&mut self, f: &mut F) -> usize
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        retain(self, f)
+    }
+    #[inline]
+    pub fn retain_prefixes<F>(&mut self, prefix: &Nibblet, f: &mut F) -> usize
+    where
+        F: FnMut(&[u8]) -> bool,
+    {
+        retain_prefixes(self, prefix, f)
+    }
+    #[inline]
+    pub fn union_with<F>(
+        &mut self,
+        other: TrieNode<K, V, P>,
+        granularity: Granularity,
+        merge: &mut F,
+    ) -> usize
+    where
+        F: FnMut(V, V) -> V,
+    {
+        union_with(self, other, granularity, merge)
+    }
+    #[inline]
+    pub fn intersection_with<W, F>(
+        &mut self,
+        other: TrieNode<K, W, P>,
+        granularity: Granularity,
+        merge: &mut F,
+    ) -> usize
+    where
+        F: FnMut(V, W) -> V,
+    {
+        intersection_with(self, other, granularity, merge)
+    }
+    #[inline]
+    pub fn difference<W>(&mut self, other: &TrieNode<K, W, P>, granularity: Granularity) -> usize {
+        difference(self, other, granularity)
+    }
+    #[inline]
+    pub fn split_off(&mut self, nv: &Nibblet, granularity: Granularity) -> TrieNode<K, V, P> {
+        split_off(self, nv, granularity)
+    }
+    #[inline]
+    pub fn get_ancestor<N>(
+        &self,
+        nv: &N,
+        granularity: Granularity,
+    ) -> Option<(&TrieNode<K, V, P>, usize)>
+    where
+        N: ?Sized + Nibbles,
+    {
+        get_ancestor(self, nv, granularity)
     }
     #[inline]
-    pub fn get_ancestor(&self, nv: &Nibblet) -> Option<(&TrieNode<K, V>, usize)> {
-        get_ancestor(self, nv)
+    pub fn next_ancestor<N>(
+        &self,
+        nv: &N,
+        depth: usize,
+        granularity: Granularity,
+    ) -> Option<(&TrieNode<K, V, P>, usize)>
+    where
+        N: ?Sized + Nibbles,
+    {
+        next_ancestor(self, nv, depth, granularity)
     }
     #[inline]
-    pub fn get_raw_ancestor(&self, nv: &Nibblet) -> (&TrieNode<K, V>, usize) {
-        get_raw_ancestor(self, nv)
+    pub fn get_raw_ancestor(
+        &self,
+        nv: &Nibblet,
+        granularity: Granularity,
+    ) -> (&TrieNode<K, V, P>, usize) {
+        get_raw_ancestor(self, nv, granularity)
     }
     #[inline]
-    pub fn get_raw_descendant<'a>(&'a self, nv: &Nibblet) -> Option<DescendantResult<'a, K, V>> {
-        get_raw_descendant(self, nv)
+    pub fn seek_lower_bound(
+        &self,
+        nv: &Nibblet,
+        inclusive: bool,
+        granularity: Granularity,
+    ) -> Vec<(&TrieNode<K, V, P>, usize)> {
+        seek_lower_bound(self, nv, inclusive, granularity)
+    }
+    #[inline]
+    pub fn seek_upper_bound(
+        &self,
+        nv: &Nibblet,
+        inclusive: bool,
+        granularity: Granularity,
+    ) -> Option<&TrieNode<K, V, P>> {
+        seek_upper_bound(self, nv, inclusive, granularity)
+    }
+    #[inline]
+    pub fn last_value_node(&self) -> Option<&TrieNode<K, V, P>> {
+        last_value_node(self)
+    }
+    #[inline]
+    pub fn get_raw_descendant<'a>(
+        &'a self,
+        nv: &Nibblet,
+        granularity: Granularity,
+    ) -> Option<DescendantResult<'a, K, V, P>> {
+        get_raw_descendant(self, nv, granularity)
+    }
+    #[inline]
+    pub fn get_descendant_mut(
+        &mut self,
+        nv: &Nibblet,
+        granularity: Granularity,
+    ) -> Option<&mut TrieNode<K, V, P>> {
+        get_descendant_mut(self, nv, granularity)
+    }
+}
+
+impl<K, V> TrieNode<K, V, Shared>
+where
+    K: TrieKey + Clone,
+    V: Clone,
+{
+    #[inline]
+    pub fn unshare_path(&mut self, nv: &Nibblet, merging: bool, granularity: Granularity) {
+        unshare_path(self, nv, merging, granularity)
     }
 }
 
@@ -51,265 +195,895 @@ macro_rules! get_func {
     (
         name: $name:ident,
         trie_type: $trie_type:ty,
-        mutability: $($mut_:tt)*
-    ) => {id!{
-        #[inline]
-        fn $name<'a, K, V>(trie: $trie_type, nv: &Nibblet) -> Option<$trie_type> {
-            if nv.len() == 0 {
-                return Some(trie);
-            }
-
-            let mut prev = trie;
-            let mut depth = 0;
-
-            loop {
-                let bucket = nv.get(depth) as usize;
-                let current = prev;
-                if let Some(ref $($mut_)* child) = current.children[bucket] {
-                    match match_keys(depth, nv, &child.key) {
-                        KeyMatch::Full => {
-                            return Some(child);
-                        }
-                        KeyMatch::SecondPrefix => {
-                            depth += child.key.len();
-                            prev = child;
-                        }
-                        _ => {
-                            return None;
+        get_child: $get_child:ident
+    ) => {
+        id! {
+            #[inline]
+            fn $name<'a, K, V, P: ChildPtr, N>(
+            trie: $trie_type,
+            nv: &N,
+            granularity: Granularity,
+        ) -> Option<$trie_type>
+            where
+                N: ?Sized + Nibbles,
+            {
+                if nv.len() == 0 {
+                    return Some(trie);
+                }
+
+                let mut prev = trie;
+                let mut depth = 0;
+
+                loop {
+                    let bucket = granularity.bucket(nv, depth);
+                    let current = prev;
+                    if let Some(child) = current.children.$get_child(bucket) {
+                        match match_keys(depth, nv, &child.key) {
+                            KeyMatch::Full => {
+                                return Some(child);
+                            }
+                            KeyMatch::SecondPrefix => {
+                                depth += child.key.len();
+                                prev = child;
+                            }
+                            _ => {
+                                return None;
+                            }
                         }
+                    } else {
+                        return None;
                     }
-                } else {
-                    return None;
                 }
             }
         }
-    }}
+    };
 }
 
-get_func!(name: iterative_get, trie_type: &'a TrieNode<K, V>, mutability: );
-get_func!(name: iterative_get_mut, trie_type: &'a mut TrieNode<K, V>, mutability: mut);
+get_func!(name: iterative_get, trie_type: &'a TrieNode<K, V, P>, get_child: get);
+get_func!(name: iterative_get_mut, trie_type: &'a mut TrieNode<K, V, P>, get_child: get_mut);
 
 #[inline]
-fn iterative_insert<K, V>(trie: &mut TrieNode<K, V>, key: K, value: V, mut nv: Nibblet) -> Option<V>
+fn iterative_insert<K, V, P: ChildPtr>(
+    trie: &mut TrieNode<K, V, P>,
+    key: K,
+    value: V,
+    nv: Nibblet,
+    granularity: Granularity,
+) -> Option<V>
 where
     K: TrieKey,
 {
+    match find_position(trie, &nv, granularity) {
+        Root(node) => node.replace_value(key, value),
+        Child(parent, bucket, _) => parent
+            .children
+            .get_mut(bucket)
+            .unwrap()
+            .replace_value(key, value),
+        Missing(parent, depth) => {
+            insert_below(parent, depth, nv, key, value, granularity);
+            None
+        }
+    }
+}
+
+/// Location of the node for a key, as found by `find_position`.
+pub enum KeyPosition<'a, K: 'a, V: 'a, P: ChildPtr = Owned> {
+    /// The key is empty, so its node is the root of the trie.
+    Root(&'a mut TrieNode<K, V, P>),
+    /// The key's node is the child of the given parent node, stored in the given bucket. It may
+    /// or may not have a value. The flag is set if the parent is the root of the trie.
+    Child(&'a mut TrieNode<K, V, P>, usize, bool),
+    /// The key has no node, and one should be created below the given node (at the given depth).
+    Missing(&'a mut TrieNode<K, V, P>, usize),
+}
+
+#[inline]
+fn find_position<'a, K, V, P: ChildPtr>(
+    trie: &'a mut TrieNode<K, V, P>,
+    nv: &Nibblet,
+    granularity: Granularity,
+) -> KeyPosition<'a, K, V, P> {
     if nv.is_empty() {
-        return trie.replace_value(key, value);
+        return Root(trie);
     }
 
     let mut prev = trie;
     let mut depth = 0;
 
     loop {
-        let bucket = nv.get(depth) as usize;
+        let bucket = granularity.bucket(nv, depth);
         let current = prev;
-        if let Some(ref mut child) = current.children[bucket] {
-            match match_keys(depth, &nv, &child.key) {
+        let child_key_len = match current.children.get(bucket) {
+            Some(child) => match match_keys(depth, nv, &child.key) {
                 KeyMatch::Full => {
-                    return child.replace_value(key, value);
+                    return Child(current, bucket, depth == 0);
+                }
+                KeyMatch::SecondPrefix => child.key.len(),
+                KeyMatch::FirstPrefix | KeyMatch::Partial(_) => {
+                    return Missing(current, depth);
+                }
+            },
+            None => {
+                return Missing(current, depth);
+            }
+        };
+        depth += child_key_len;
+        prev = current.children.get_mut(bucket).unwrap();
+    }
+}
+
+/// Insert a key-value pair below `parent`, given the `Missing` position found by
+/// `find_position`, and return a reference to the newly inserted value.
+///
+/// Panics if the key can't be stored at the given granularity.
+pub fn insert_below<K, V, P: ChildPtr>(
+    parent: &mut TrieNode<K, V, P>,
+    depth: usize,
+    mut nv: Nibblet,
+    key: K,
+    value: V,
+    granularity: Granularity,
+) -> &mut V
+where
+    K: TrieKey,
+{
+    assert!(
+        granularity.is_aligned(nv.len()),
+        "keys of a byte-granular trie must encode to whole bytes"
+    );
+
+    let bucket = granularity.bucket(&nv, depth);
+    if parent.children.get(bucket).is_none() {
+        let node_key = nv.split(depth);
+        parent.add_child(
+            bucket,
+            P::new(TrieNode::with_key_value(node_key, key, value)),
+            granularity,
+        );
+        return parent
+            .children
+            .get_mut(bucket)
+            .unwrap()
+            .value_mut()
+            .unwrap();
+    }
+    let child = parent.children.get_mut(bucket).unwrap();
+
+    match match_keys(depth, &nv, &child.key) {
+        KeyMatch::Partial(idx) => {
+            // Split the existing child, at the start of the bucket in which the keys differ.
+            let idx = granularity.align(idx);
+            child.split(idx, granularity);
+
+            // Insert the new key below the prefix node.
+            let new_key = nv.split(depth + idx);
+            let new_key_bucket = granularity.bucket(&new_key, 0);
+
+            child.add_child(
+                new_key_bucket,
+                P::new(TrieNode::with_key_value(new_key, key, value)),
+                granularity,
+            );
+            child
+                .children
+                .get_mut(new_key_bucket)
+                .unwrap()
+                .value_mut()
+                .unwrap()
+        }
+        KeyMatch::FirstPrefix => {
+            child.split(nv.len() - depth, granularity);
+            child.add_key_value(key, value);
+            child.value_mut().unwrap()
+        }
+        KeyMatch::Full | KeyMatch::SecondPrefix => {
+            unreachable!("key has a node, so should not be inserted below its parent")
+        }
+    }
+}
+
+/// Remove the value of the child of `parent` in `bucket`, given the `Child` position found by
+/// `find_position`, and restore the compression of the nodes involved.
+///
+/// The parent is only merged with its remaining child if it isn't the root of the trie.
+pub fn remove_child_value<K, V, P: ChildPtr>(
+    parent: &mut TrieNode<K, V, P>,
+    bucket: usize,
+    parent_is_root: bool,
+) -> Option<Box<KeyValue<K, V>>>
+where
+    K: TrieKey,
+{
+    let child = parent.children.get_mut(bucket)?;
+    let result = child.key_value.take();
+
+    match child.child_count() {
+        // If this node has no children, remove it.
+        0 => {
+            parent.take_child(bucket);
+
+            // If the parent node now only has a single child, merge it.
+            if !parent_is_root && parent.child_count() == 1 && parent.key_value.is_none() {
+                let repl = get_merge_child(parent);
+                *parent = repl;
+            }
+        }
+        // If removing this node's value has made it a value-less node with a
+        // single child, then merge its child.
+        1 => {
+            let repl = get_merge_child(child);
+            *child = repl;
+        }
+        _ => (),
+    }
+
+    result
+}
+
+#[inline]
+fn iterative_remove<K, Q, V, P: ChildPtr>(
+    trie: &mut TrieNode<K, V, P>,
+    key: &Q,
+    nv: &Nibblet,
+    granularity: Granularity,
+) -> Option<V>
+where
+    K: TrieKey + Borrow<Q>,
+    Q: ?Sized + TrieKey,
+{
+    match find_position(trie, nv, granularity) {
+        Root(node) => node.take_value(key),
+        Child(parent, bucket, parent_is_root) => {
+            // Check the key before modifying the trie.
+            match parent.children.get(bucket).unwrap().key_value {
+                Some(ref kv) => check_keys(kv.key.borrow(), key),
+                None => return None,
+            }
+            remove_child_value(parent, bucket, parent_is_root).map(|kv| kv.value)
+        }
+        Missing(..) => None,
+    }
+}
+
+/// Merge the keys and values of `other` into `trie`, which lies at the same position as `other`
+/// (e.g. they are both roots, or children in the same bucket of nodes with the same key).
+///
+/// Subtrees of `other` which don't overlap `trie` are moved over whole. Returns the number of
+/// keys which were in both, whose values were combined by `merge`.
+fn union_with<K, V, P: ChildPtr, F>(
+    trie: &mut TrieNode<K, V, P>,
+    other: TrieNode<K, V, P>,
+    granularity: Granularity,
+    merge: &mut F,
+) -> usize
+where
+    K: TrieKey,
+    F: FnMut(V, V) -> V,
+{
+    let mut shared = 0;
+    // The nodes on the path to the current node, each detached from its parent, with the bucket
+    // it belongs in and the children of `other` left to merge into its children.
+    let mut stack = vec![];
+    let mut next = Some((mem::take(trie), 0, other));
+
+    loop {
+        while let Some((mut node, bucket, mut other)) = next.take() {
+            let mut pairs = vec![];
+            match match_keys(0, &node.key, &other.key) {
+                KeyMatch::Full => (),
+                // The other node lies below this one.
+                KeyMatch::FirstPrefix => {
+                    other.key = other.key.split(node.key.len());
+                    let child_bucket = granularity.bucket(&other.key, 0);
+                    match node.children.get_mut(child_bucket) {
+                        Some(child) => next = Some((mem::take(child), child_bucket, other)),
+                        None => node.add_child(child_bucket, P::new(other), granularity),
+                    }
+                    stack.push((node, bucket, pairs.into_iter()));
+                    continue;
                 }
+                // This node lies below the other one, so split it to match.
+                KeyMatch::SecondPrefix => node.split(other.key.len(), granularity),
+                // The nodes diverge, so split this one and add the other as a sibling of the rest.
                 KeyMatch::Partial(idx) => {
-                    // Split the existing child.
-                    child.split(idx);
+                    let idx = granularity.align(idx);
+                    node.split(idx, granularity);
+                    other.key = other.key.split(idx);
+                    let child_bucket = granularity.bucket(&other.key, 0);
+                    node.add_child(child_bucket, P::new(other), granularity);
+                    stack.push((node, bucket, pairs.into_iter()));
+                    continue;
+                }
+            }
 
-                    // Insert the new key below the prefix node.
-                    let new_key = nv.split(depth + idx);
-                    let new_key_bucket = new_key.get(0) as usize;
+            // The nodes have the same key.
+            node.key_value = match (node.key_value.take(), other.key_value.take()) {
+                (Some(mut kv), Some(other_kv)) => {
+                    check_keys(&kv.key, &other_kv.key);
+                    kv.value = merge(kv.value, other_kv.value);
+                    shared += 1;
+                    Some(kv)
+                }
+                (kv, other_kv) => kv.or(other_kv),
+            };
 
-                    child.add_child(
-                        new_key_bucket,
-                        Box::new(TrieNode::with_key_value(new_key, key, value)),
-                    );
+            for child in mem::take(&mut other.children) {
+                let child_bucket = granularity.bucket(&child.key, 0);
+                if node.children.get(child_bucket).is_some() {
+                    pairs.push((child_bucket, child));
+                } else {
+                    node.add_child(child_bucket, child, granularity);
+                }
+            }
+            stack.push((node, bucket, pairs.into_iter()));
+        }
 
-                    return None;
+        let (node, _, pairs) = stack.last_mut().unwrap();
+        match pairs.next() {
+            Some((child_bucket, other_child)) => {
+                let child = node.children.get_mut(child_bucket).unwrap();
+                next = Some((mem::take(child), child_bucket, P::into_node(other_child)));
+            }
+            None => {
+                if let Some(root) = reattach(&mut stack) {
+                    *trie = root;
+                    return shared;
                 }
+            }
+        }
+    }
+}
+
+/// Remove the keys of `trie` which aren't in `other`, where `other` lies at the same position as
+/// `trie`, and combine the values of the keys in both using `merge`.
+///
+/// Returns the number of values left below `trie`. The compression of `trie` itself is left to
+/// its parent to restore, see `compress_child`.
+fn intersection_with<K, V, W, P: ChildPtr, F>(
+    trie: &mut TrieNode<K, V, P>,
+    other: TrieNode<K, W, P>,
+    granularity: Granularity,
+    merge: &mut F,
+) -> usize
+where
+    K: TrieKey,
+    F: FnMut(V, W) -> V,
+{
+    let mut kept = 0;
+    // The nodes on the path to the current node, each detached from its parent, with the bucket
+    // it belongs in and the children of `other` left to intersect with its children.
+    let mut stack = vec![];
+    let mut next = Some((mem::take(trie), 0, other));
+
+    loop {
+        while let Some((mut node, bucket, mut other)) = next.take() {
+            let mut pairs = vec![];
+            match match_keys(0, &node.key, &other.key) {
+                KeyMatch::Full => (),
+                // The other node lies below this one, so only the child leading to it can have
+                // any keys in common with it.
                 KeyMatch::FirstPrefix => {
-                    child.split(nv.len() - depth);
-                    child.add_key_value(key, value);
-                    return None;
+                    other.key = other.key.split(node.key.len());
+                    let child_bucket = granularity.bucket(&other.key, 0);
+                    let child = node.take_child(child_bucket);
+                    clear(&mut node);
+                    if let Some(mut child) = child {
+                        next = Some((mem::take(P::get_mut(&mut child)), child_bucket, other));
+                        node.add_child(child_bucket, child, granularity);
+                    }
+                    stack.push((node, bucket, pairs.into_iter()));
+                    continue;
                 }
-                KeyMatch::SecondPrefix => {
-                    depth += child.key.len();
-                    prev = child;
+                KeyMatch::SecondPrefix => node.split(other.key.len(), granularity),
+                KeyMatch::Partial(_) => {
+                    clear(&mut node);
+                    stack.push((node, bucket, pairs.into_iter()));
+                    continue;
+                }
+            }
+
+            // The nodes have the same key.
+            node.key_value = match (node.key_value.take(), other.key_value.take()) {
+                (Some(mut kv), Some(other_kv)) => {
+                    check_keys(&kv.key, &other_kv.key);
+                    kv.value = merge(kv.value, other_kv.value);
+                    kept += 1;
+                    Some(kv)
+                }
+                _ => None,
+            };
+
+            for child_bucket in child_buckets(&node) {
+                match other.children.remove(child_bucket) {
+                    Some(other_child) => pairs.push((child_bucket, other_child)),
+                    None => {
+                        node.take_child(child_bucket);
+                    }
+                }
+            }
+            stack.push((node, bucket, pairs.into_iter()));
+        }
+
+        let (node, _, pairs) = stack.last_mut().unwrap();
+        match pairs.next() {
+            Some((child_bucket, other_child)) => {
+                let child = node.children.get_mut(child_bucket).unwrap();
+                next = Some((mem::take(child), child_bucket, P::into_node(other_child)));
+            }
+            None => {
+                if let Some(root) = reattach(&mut stack) {
+                    *trie = root;
+                    return kept;
                 }
             }
-        } else {
-            let node_key = nv.split(depth);
-            current.add_child(
-                bucket,
-                Box::new(TrieNode::with_key_value(node_key, key, value)),
-            );
-            return None;
         }
     }
 }
 
-// TODO: clean this up and make it iterative.
-#[inline]
-fn recursive_remove<K, Q: ?Sized, V>(trie: &mut TrieNode<K, V>, key: &Q) -> Option<V>
+/// Remove the keys of `other` from `trie`, where `other` lies at the same position as `trie`.
+///
+/// Returns the number of values removed. The compression of `trie` itself is left to its parent
+/// to restore, see `compress_child`.
+fn difference<K, V, W, P: ChildPtr>(
+    trie: &mut TrieNode<K, V, P>,
+    other: &TrieNode<K, W, P>,
+    granularity: Granularity,
+) -> usize
 where
     K: TrieKey,
-    K: Borrow<Q>,
-    Q: TrieKey,
 {
-    let nv = key.encode();
+    let mut removed = 0;
+    // The nodes on the path to the current node, each detached from its parent, with the bucket
+    // it belongs in and the children of `other` left to remove from its children.
+    let mut stack = vec![];
+    // The next node to visit, and the node of `other` at its position, once the first `depth`
+    // nibbles of the other node's key are removed.
+    let mut next = Some((mem::take(trie), 0, other, 0));
 
-    if nv.is_empty() {
-        return trie.take_value(key);
+    loop {
+        while let Some((mut node, bucket, other, depth)) = next.take() {
+            match match_keys(depth, &other.key, &node.key) {
+                KeyMatch::Full => (),
+                // The other node lies below this one.
+                KeyMatch::SecondPrefix => {
+                    let depth = depth + node.key.len();
+                    let child_bucket = granularity.bucket(&other.key, depth);
+                    if let Some(child) = node.children.get_mut(child_bucket) {
+                        next = Some((mem::take(child), child_bucket, other, depth));
+                    }
+                    stack.push((node, bucket, None));
+                    continue;
+                }
+                // This node lies below the other one, so split it to match.
+                KeyMatch::FirstPrefix => node.split(other.key.len() - depth, granularity),
+                KeyMatch::Partial(_) => {
+                    stack.push((node, bucket, None));
+                    continue;
+                }
+            }
+
+            // The nodes have the same key.
+            if let Some(ref other_kv) = other.key_value
+                && let Some(kv) = node.key_value.take()
+            {
+                check_keys(&kv.key, &other_kv.key);
+                removed += 1;
+            }
+            stack.push((node, bucket, Some(other.children.iter())));
+        }
+
+        let (node, _, other_children) = stack.last_mut().unwrap();
+        match other_children.as_mut().and_then(Iterator::next) {
+            Some((child_bucket, other_child)) => {
+                if let Some(child) = node.children.get_mut(child_bucket) {
+                    next = Some((mem::take(child), child_bucket, other_child, 0));
+                }
+            }
+            None => {
+                if let Some(root) = reattach(&mut stack) {
+                    *trie = root;
+                    return removed;
+                }
+            }
+        }
     }
+}
 
-    let bucket = nv.get(0) as usize;
+/// Move the keys below `trie` which are greater than or equal to `nv` into a new node, with the
+/// same key as `trie`, and return it. The key of `trie` must be a proper prefix of `nv`, so that
+/// its own key stays.
+///
+/// Whole subtrees are moved where possible, so only the nodes along the path to `nv` are split.
+/// The compression of `trie` and of the new node is left to their parents to restore.
+fn split_off<K, V, P: ChildPtr>(
+    trie: &mut TrieNode<K, V, P>,
+    nv: &Nibblet,
+    granularity: Granularity,
+) -> TrieNode<K, V, P>
+where
+    K: TrieKey,
+{
+    // The nodes on the path to `nv`, each detached from its parent, with the new node holding
+    // its greater keys and the bucket they belong in.
+    let mut stack = vec![];
+    let mut next = Some((mem::take(trie), 0));
+    let mut depth = 0;
 
-    let child = trie.take_child(bucket);
+    while let Some((mut node, bucket)) = next.take() {
+        depth += node.key.len();
+        let nv_bucket = granularity.bucket(nv, depth);
 
-    match child {
-        Some(mut child) => {
-            match match_keys(0, &nv, &child.key) {
-                KeyMatch::Full => {
-                    let result = child.take_value(key);
-                    if child.child_count != 0 {
-                        // If removing this node's value has made it a value-less node with a
-                        // single child, then merge its child.
-                        let repl = if child.child_count == 1 {
-                            get_merge_child(&mut child)
-                        } else {
-                            child
-                        };
-                        trie.add_child(bucket, repl);
-                    }
-                    result
+        let mut right = TrieNode::new();
+        right.key = node.key.clone();
+
+        // Children after the bucket of `nv` only have greater keys.
+        for child_bucket in child_buckets(&node) {
+            if child_bucket > nv_bucket {
+                let child = node.take_child(child_bucket).unwrap();
+                right.add_child(child_bucket, child, granularity);
+            }
+        }
+
+        if let Some(child) = node.children.get_mut(nv_bucket) {
+            match match_keys(depth, nv, &child.key) {
+                // The child's key is a prefix of `nv`, so its keys lie on both sides.
+                KeyMatch::SecondPrefix => next = Some((mem::take(child), nv_bucket)),
+                // The child's keys are all less than `nv`.
+                KeyMatch::Partial(idx) if nv.get(depth + idx) > child.key.get(idx) => (),
+                // The child's keys are all greater than or equal to `nv`.
+                _ => {
+                    let child = node.take_child(nv_bucket).unwrap();
+                    right.add_child(nv_bucket, child, granularity);
                 }
-                KeyMatch::SecondPrefix => {
-                    let depth = child.key.len();
-                    rec_remove(trie, child, bucket, key, depth, &nv)
+            }
+        }
+        stack.push((node, right, bucket));
+    }
+
+    // Put the nodes back together, from the bottom of the path up.
+    loop {
+        let (node, right, bucket) = stack.pop().unwrap();
+        match stack.last_mut() {
+            Some((parent, parent_right, _)) => {
+                *parent.children.get_mut(bucket).unwrap() = node;
+                parent_right.add_child(bucket, P::new(right), granularity);
+                compress_child(parent, bucket);
+                compress_child(parent_right, bucket);
+            }
+            None => {
+                *trie = node;
+                return right;
+            }
+        }
+    }
+}
+
+/// Put the node on top of `stack` back in its bucket of the node below it, which it was detached
+/// from, and restore the compression of the parent's children. The node is returned instead if
+/// it is the last one on the stack.
+fn reattach<K, V, P: ChildPtr, T>(
+    stack: &mut Vec<(TrieNode<K, V, P>, usize, T)>,
+) -> Option<TrieNode<K, V, P>>
+where
+    K: TrieKey,
+{
+    let (node, bucket, _) = stack.pop().unwrap();
+    match stack.last_mut() {
+        Some((parent, _, _)) => {
+            *parent.children.get_mut(bucket).unwrap() = node;
+            compress_child(parent, bucket);
+            None
+        }
+        None => Some(node),
+    }
+}
+
+/// Replace the nodes below `trie` on the path to `nv` which are shared with other tries by
+/// copies, so that the key can be inserted or removed in place.
+///
+/// The copies share their children with the original nodes. If `merging` is set, the nodes
+/// which removing the key could merge with their parents are copied too.
+fn unshare_path<K, V>(
+    trie: &mut TrieNode<K, V, Shared>,
+    nv: &Nibblet,
+    merging: bool,
+    granularity: Granularity,
+) where
+    K: TrieKey + Clone,
+    V: Clone,
+{
+    let mut parent = trie;
+    let mut depth = 0;
+
+    while depth < nv.len() {
+        let bucket = granularity.bucket(nv, depth);
+        let Some(child) = parent.children.get(bucket) else {
+            return;
+        };
+        match match_keys(depth, nv, &child.key) {
+            KeyMatch::SecondPrefix => {
+                depth += child.key.len();
+                parent = parent.children.make_unique(bucket).unwrap();
+            }
+            // The key's node is the child, so removing its value could merge the child with its
+            // only child, or remove the child and merge its parent with its only sibling.
+            KeyMatch::Full => {
+                let child = parent.children.make_unique(bucket).unwrap();
+                let leaf = child.child_count() == 0;
+                if merging && child.child_count() == 1 {
+                    child.children.make_unique(child_buckets(child)[0]);
                 }
-                KeyMatch::FirstPrefix | KeyMatch::Partial(_) => {
-                    trie.add_child(bucket, child);
-                    None
+                if merging && leaf && parent.child_count() == 2 && parent.key_value.is_none() {
+                    for sibling in child_buckets(parent) {
+                        parent.children.make_unique(sibling);
+                    }
                 }
+                return;
+            }
+            // Inserting the key splits the child.
+            KeyMatch::FirstPrefix | KeyMatch::Partial(_) => {
+                parent.children.make_unique(bucket);
+                return;
             }
         }
-        None => None,
     }
 }
+
+/// Remove the value and children of a node.
+fn clear<K, V, P: ChildPtr>(trie: &mut TrieNode<K, V, P>) {
+    trie.key_value = None;
+    trie.children = ChildMap::new();
+}
+
 #[inline]
-fn get_merge_child<K, V>(trie: &mut TrieNode<K, V>) -> Box<TrieNode<K, V>>
+fn get_merge_child<K, V, P: ChildPtr>(trie: &mut TrieNode<K, V, P>) -> TrieNode<K, V, P>
 where
     K: TrieKey,
 {
-    let mut child = trie.take_only_child();
+    let mut child = P::into_node(trie.take_only_child());
 
     // Join the child's key onto the existing one.
     child.key = trie.key.clone().join(&child.key);
 
     child
 }
-
-// Tail-recursive remove function used by `recursive_remove`.
+/// Detach the subtree containing all keys that extend the (non-empty) prefix `nv`.
+///
+/// The detached node's key is extended to its full key from the root of the search, and its
+/// former parent is merged with its remaining child if necessary.
 #[inline]
-fn rec_remove<K, Q: ?Sized, V>(
-    parent: &mut TrieNode<K, V>,
-    mut middle: Box<TrieNode<K, V>>,
-    prev_bucket: usize,
-    key: &Q,
-    depth: usize,
+fn remove_prefix<K, V, P: ChildPtr>(
+    trie: &mut TrieNode<K, V, P>,
     nv: &Nibblet,
-) -> Option<V>
+    granularity: Granularity,
+) -> Option<Child<K, V, P>>
 where
     K: TrieKey,
-    K: Borrow<Q>,
-    Q: TrieKey,
 {
-    let bucket = nv.get(depth) as usize;
+    debug_assert!(!nv.is_empty());
 
-    let child = middle.take_child(bucket);
-    parent.add_child(prev_bucket, middle);
-
-    match child {
-        Some(mut child) => {
-            let middle = parent.children[prev_bucket].as_mut().unwrap();
-            match match_keys(depth, nv, &child.key) {
-                KeyMatch::Full => {
-                    let result = child.take_value(key);
-
-                    // If this node has children, keep it.
-                    if child.child_count != 0 {
-                        // If removing this node's value has made it a value-less node with a
-                        // single child, then merge its child.
-                        let repl = if child.child_count == 1 {
-                            get_merge_child(&mut *child)
-                        } else {
-                            child
-                        };
-                        middle.add_child(bucket, repl);
-                    }
-                    // Otherwise, if the parent node now only has a single child, merge it.
-                    else if middle.child_count == 1 && middle.key_value.is_none() {
-                        let repl = get_merge_child(middle);
-                        *middle = repl;
-                    }
+    let mut prev = trie;
+    let mut depth = 0;
 
-                    result
-                }
-                KeyMatch::SecondPrefix => {
-                    let new_depth = depth + child.key.len();
-                    rec_remove(middle, child, bucket, key, new_depth, nv)
+    loop {
+        let bucket = granularity.bucket(nv, depth);
+        let current = prev;
+        let child_key_len = match current.children.get(bucket) {
+            Some(child) => match match_keys(depth, nv, &child.key) {
+                KeyMatch::Full | KeyMatch::FirstPrefix => None,
+                KeyMatch::SecondPrefix => Some(child.key.len()),
+                KeyMatch::Partial(_) => {
+                    return None;
                 }
-                KeyMatch::FirstPrefix | KeyMatch::Partial(_) => {
-                    middle.add_child(bucket, child);
-                    None
+            },
+            None => {
+                return None;
+            }
+        };
+
+        match child_key_len {
+            Some(len) => {
+                depth += len;
+                prev = current.children.get_mut(bucket).unwrap();
+            }
+            None => {
+                let mut child = current.take_child(bucket).unwrap();
+                let mut key = nv.clone();
+                key.split(depth);
+                let child_key = &mut P::get_mut(&mut child).key;
+                *child_key = key.join(child_key);
+
+                // If the parent now only has a single child, merge it.
+                if depth != 0 && current.child_count() == 1 && current.key_value.is_none() {
+                    let repl = get_merge_child(current);
+                    *current = repl;
                 }
+                return Some(child);
             }
         }
-        None => None,
     }
 }
-#[inline]
-fn get_ancestor<'a, K, V>(
-    trie: &'a TrieNode<K, V>,
-    nv: &Nibblet,
-) -> Option<(&'a TrieNode<K, V>, usize)>
+
+/// Remove the values below `trie` for which `f` returns false, returning how many were removed.
+fn retain<K, V, P: ChildPtr, F>(trie: &mut TrieNode<K, V, P>, f: &mut F) -> usize
 where
     K: TrieKey,
+    F: FnMut(&K, &mut V) -> bool,
 {
-    if nv.is_empty() {
-        return trie.as_value_node().map(|node| (node, 0));
+    let mut removed = 0;
+    // The nodes on the path to the current node, each detached from its parent, with the bucket
+    // it belongs in and the buckets of its children left to visit.
+    let mut stack = vec![];
+    let mut next = Some((mem::take(trie), 0));
+
+    loop {
+        if let Some((mut node, bucket)) = next.take() {
+            if let Some(ref mut kv) = node.key_value
+                && !f(&kv.key, &mut kv.value)
+            {
+                node.key_value = None;
+                removed += 1;
+            }
+            let buckets = child_buckets(&node).into_iter();
+            stack.push((node, bucket, buckets));
+        }
+
+        let (node, _, buckets) = stack.last_mut().unwrap();
+        match buckets.next() {
+            Some(child_bucket) => {
+                let child = node.children.get_mut(child_bucket).unwrap();
+                next = Some((mem::take(child), child_bucket));
+            }
+            None => {
+                if let Some(root) = reattach(&mut stack) {
+                    *trie = root;
+                    return removed;
+                }
+            }
+        }
     }
+}
 
-    let mut prev = trie;
-    // The ancestor is such that all nodes upto and including `prev` have
-    // already been considered.
-    let mut ancestor = prev.as_value_node();
-    let mut depth = 0;
+/// Remove the subtrees below `trie` whose prefixes are rejected by `f`, returning how many
+/// values were removed. The prefix of `trie` itself is given by `prefix`.
+///
+/// Rejected subtrees are removed whole, without calling `f` on any of their descendants. `f` is
+/// given the whole bytes of each prefix, and is only called when they differ from the bytes
+/// already accepted for the node's parent.
+fn retain_prefixes<K, V, P: ChildPtr, F>(
+    trie: &mut TrieNode<K, V, P>,
+    prefix: &Nibblet,
+    f: &mut F,
+) -> usize
+where
+    K: TrieKey,
+    F: FnMut(&[u8]) -> bool,
+{
+    let mut removed = 0;
+    // The prefix of the current node.
+    let mut path = prefix.clone();
+    // The nodes on the path to the current node, each detached from its parent, with the bucket
+    // it belongs in, the length of its prefix and the buckets of its children left to visit.
+    let buckets = child_buckets(trie).into_iter();
+    let mut stack = vec![(mem::take(trie), 0, (path.len(), buckets))];
 
     loop {
-        let bucket = nv.get(depth) as usize;
-        let current = prev;
-        if let Some(ref child) = current.children[bucket] {
-            match match_keys(depth, nv, &child.key) {
-                KeyMatch::Full => {
-                    return child
-                        .as_value_node()
-                        .map(|node| (node, depth + node.key.len()))
-                        .or_else(|| ancestor.map(|anc| (anc, depth)));
-                }
-                KeyMatch::FirstPrefix | KeyMatch::Partial(_) => {
-                    return ancestor.map(|anc| (anc, depth));
+        let (node, _, (prefix_len, buckets)) = stack.last_mut().unwrap();
+        let prefix_len = *prefix_len;
+        match buckets.next() {
+            Some(child_bucket) => {
+                let child = node.children.get_mut(child_bucket).unwrap();
+                path.split(prefix_len);
+                path = path.join(&child.key);
+                let num_bytes = path.len() / 2;
+                if num_bytes == prefix_len / 2 || f(&path.as_bytes()[..num_bytes]) {
+                    let child = mem::take(child);
+                    let buckets = child_buckets(&child).into_iter();
+                    stack.push((child, child_bucket, (path.len(), buckets)));
+                } else {
+                    removed += child.compute_size();
+                    node.take_child(child_bucket);
                 }
-                KeyMatch::SecondPrefix => {
-                    depth += child.key.len();
-                    ancestor = child.as_value_node().or(ancestor);
-                    prev = child;
+            }
+            None => {
+                if let Some(root) = reattach(&mut stack) {
+                    *trie = root;
+                    return removed;
                 }
             }
-        } else {
-            return ancestor.map(|anc| (anc, depth));
         }
     }
 }
+
+/// Get the buckets of the children of `trie`, so that they can be modified one at a time.
+fn child_buckets<K, V, P: ChildPtr>(trie: &TrieNode<K, V, P>) -> Vec<usize> {
+    trie.children.iter().map(|(bucket, _)| bucket).collect()
+}
+
+/// Restore the compression of the child in `bucket`, after values have been removed from it or
+/// its descendants.
+///
+/// Value-less children with no children of their own are removed, and value-less children with
+/// a single child are merged with it.
+fn compress_child<K, V, P: ChildPtr>(trie: &mut TrieNode<K, V, P>, bucket: usize)
+where
+    K: TrieKey,
+{
+    if let Some(child) = trie.children.get_mut(bucket)
+        && child.key_value.is_none()
+    {
+        match child.child_count() {
+            0 => {
+                trie.take_child(bucket);
+            }
+            1 => {
+                let repl = get_merge_child(child);
+                *child = repl;
+            }
+            _ => (),
+        }
+    }
+}
+
+#[inline]
+fn get_ancestor<'a, K, V, P: ChildPtr, N>(
+    trie: &'a TrieNode<K, V, P>,
+    nv: &N,
+    granularity: Granularity,
+) -> Option<(&'a TrieNode<K, V, P>, usize)>
+where
+    K: TrieKey,
+    N: ?Sized + Nibbles,
+{
+    // The ancestor is such that all nodes upto and including `current` have
+    // already been considered.
+    let mut ancestor = trie.as_value_node().map(|node| (node, 0));
+    let mut current = trie;
+    let mut depth = 0;
+
+    while let Some((child, child_depth)) = next_ancestor(current, nv, depth, granularity) {
+        if child.key_value.is_some() {
+            ancestor = Some((child, child_depth));
+        }
+        current = child;
+        depth = child_depth;
+    }
+
+    ancestor
+}
+
+/// Take a single step along the path to the key `nv`, from `trie` which lies at `depth`.
+///
+/// Return the child of `trie` whose full key is a prefix of `nv`, along with its depth (the
+/// length of its full key), if there is one. The child need not have a value.
+#[inline]
+fn next_ancestor<'a, K, V, P: ChildPtr, N>(
+    trie: &'a TrieNode<K, V, P>,
+    nv: &N,
+    depth: usize,
+    granularity: Granularity,
+) -> Option<(&'a TrieNode<K, V, P>, usize)>
+where
+    N: ?Sized + Nibbles,
+{
+    if depth == nv.len() {
+        return None;
+    }
+
+    let bucket = granularity.bucket(nv, depth);
+    let child = trie.children.get(bucket)?;
+    match match_keys(depth, nv, &child.key) {
+        KeyMatch::Full | KeyMatch::SecondPrefix => Some((child, depth + child.key.len())),
+        KeyMatch::FirstPrefix | KeyMatch::Partial(_) => None,
+    }
+}
 #[inline]
-fn get_raw_ancestor<'a, K, V>(trie: &'a TrieNode<K, V>, nv: &Nibblet) -> (&'a TrieNode<K, V>, usize)
+fn get_raw_ancestor<'a, K, V, P: ChildPtr>(
+    trie: &'a TrieNode<K, V, P>,
+    nv: &Nibblet,
+    granularity: Granularity,
+) -> (&'a TrieNode<K, V, P>, usize)
 where
     K: TrieKey,
 {
@@ -324,9 +1098,9 @@ where
     let mut depth = 0;
 
     loop {
-        let bucket = nv.get(depth) as usize;
+        let bucket = granularity.bucket(nv, depth);
         let current = prev;
-        if let Some(ref child) = current.children[bucket] {
+        if let Some(child) = current.children.get(bucket) {
             match match_keys(depth, nv, &child.key) {
                 KeyMatch::Full => {
                     return (child, depth + child.key.len());
@@ -348,15 +1122,16 @@ where
 
 // Type used to propogate subtrie construction instructions to the top-level `get_raw_descendant`
 // method.
-pub enum DescendantResult<'a, K: 'a, V: 'a> {
-    NoModification(&'a TrieNode<K, V>),
-    ExtendKey(&'a TrieNode<K, V>, usize, &'a Nibblet),
+pub enum DescendantResult<'a, K: 'a, V: 'a, P: ChildPtr = Owned> {
+    NoModification(&'a TrieNode<K, V, P>),
+    ExtendKey(&'a TrieNode<K, V, P>, usize, &'a Nibblet),
 }
 #[inline]
-fn get_raw_descendant<'a, K, V>(
-    trie: &'a TrieNode<K, V>,
+fn get_raw_descendant<'a, K, V, P: ChildPtr>(
+    trie: &'a TrieNode<K, V, P>,
     nv: &Nibblet,
-) -> Option<DescendantResult<'a, K, V>> {
+    granularity: Granularity,
+) -> Option<DescendantResult<'a, K, V, P>> {
     if nv.is_empty() {
         return Some(NoModification(trie));
     }
@@ -365,9 +1140,9 @@ fn get_raw_descendant<'a, K, V>(
     let mut depth = 0;
 
     loop {
-        let bucket = nv.get(depth) as usize;
+        let bucket = granularity.bucket(nv, depth);
         let current = prev;
-        if let Some(ref child) = current.children[bucket] {
+        if let Some(child) = current.children.get(bucket) {
             match match_keys(depth, nv, &child.key) {
                 KeyMatch::Full => {
                     return Some(NoModification(child));
@@ -388,3 +1163,179 @@ fn get_raw_descendant<'a, K, V>(
         }
     }
 }
+
+/// Find the highest node whose key starts with `nv`, like `get_raw_descendant`.
+#[inline]
+fn get_descendant_mut<'a, K, V, P: ChildPtr>(
+    trie: &'a mut TrieNode<K, V, P>,
+    nv: &Nibblet,
+    granularity: Granularity,
+) -> Option<&'a mut TrieNode<K, V, P>> {
+    let mut current = trie;
+    let mut depth = 0;
+
+    while depth < nv.len() {
+        let bucket = granularity.bucket(nv, depth);
+        let child = current.children.get_mut(bucket)?;
+        match match_keys(depth, nv, &child.key) {
+            KeyMatch::Full | KeyMatch::FirstPrefix => return Some(child),
+            KeyMatch::SecondPrefix => {
+                depth += child.key.len();
+                current = child;
+            }
+            KeyMatch::Partial(_) => return None,
+        }
+    }
+    Some(current)
+}
+
+/// Find the starting point for an in-order traversal of the keys after a lower bound.
+///
+/// The result is a path of nodes, each paired with the first of its buckets that lies after the
+/// bound. Visiting the children of the last node from its bucket onwards, then those of the
+/// second last node and so on, yields exactly the keys after the bound.
+#[inline]
+fn seek_lower_bound<'a, K, V, P: ChildPtr>(
+    trie: &'a TrieNode<K, V, P>,
+    nv: &Nibblet,
+    inclusive: bool,
+    granularity: Granularity,
+) -> Vec<(&'a TrieNode<K, V, P>, usize)>
+where
+    K: TrieKey,
+{
+    let mut path = vec![];
+    let mut current = trie;
+    let mut depth = 0;
+
+    // Every node on the path has a key which is a strict prefix of the bound, so its value
+    // lies before the bound and is never visited.
+    while depth < nv.len() {
+        let bucket = granularity.bucket(nv, depth);
+        let child = match current.children.get(bucket) {
+            Some(child) => child,
+            None => {
+                path.push((current, bucket + 1));
+                return path;
+            }
+        };
+
+        match match_keys(depth, nv, &child.key) {
+            KeyMatch::Full => {
+                // The child's key is the bound, and all of its descendants lie after it.
+                if inclusive {
+                    path.push((current, bucket));
+                } else {
+                    path.push((current, bucket + 1));
+                    path.push((child, 0));
+                }
+                return path;
+            }
+            KeyMatch::FirstPrefix => {
+                path.push((current, bucket));
+                return path;
+            }
+            KeyMatch::Partial(idx) => {
+                // The child's subtree lies entirely before or entirely after the bound.
+                if child.key.get(idx) > nv.get(depth + idx) {
+                    path.push((current, bucket));
+                } else {
+                    path.push((current, bucket + 1));
+                }
+                return path;
+            }
+            KeyMatch::SecondPrefix => {
+                path.push((current, bucket + 1));
+                depth += child.key.len();
+                current = child;
+            }
+        }
+    }
+
+    path.push((current, 0));
+    path
+}
+
+/// Find the node with the greatest key that lies before an upper bound (and has a value).
+#[inline]
+fn seek_upper_bound<'a, K, V, P: ChildPtr>(
+    trie: &'a TrieNode<K, V, P>,
+    nv: &Nibblet,
+    inclusive: bool,
+    granularity: Granularity,
+) -> Option<&'a TrieNode<K, V, P>>
+where
+    K: TrieKey,
+{
+    // Nodes with keys that are strict prefixes of the bound, paired with the bucket on the
+    // path to the bound. Everything in the buckets before it lies before the bound.
+    let mut path = vec![];
+    let mut current = trie;
+    let mut depth = 0;
+
+    if nv.is_empty() && inclusive {
+        return trie.as_value_node();
+    }
+
+    while depth < nv.len() {
+        let bucket = granularity.bucket(nv, depth);
+        path.push((current, bucket));
+        let child = match current.children.get(bucket) {
+            Some(child) => child,
+            None => break,
+        };
+
+        match match_keys(depth, nv, &child.key) {
+            KeyMatch::Full => {
+                if inclusive && child.key_value.is_some() {
+                    return Some(child);
+                }
+                break;
+            }
+            KeyMatch::FirstPrefix => break,
+            KeyMatch::Partial(idx) => {
+                if child.key.get(idx) < nv.get(depth + idx)
+                    && let Some(node) = last_value_node(child)
+                {
+                    return Some(node);
+                }
+                break;
+            }
+            KeyMatch::SecondPrefix => {
+                depth += child.key.len();
+                current = child;
+            }
+        }
+    }
+
+    // Take the greatest key before the bound from the deepest possible node.
+    for (node, bucket) in path.into_iter().rev() {
+        for (_, child) in node.children.range(..bucket).rev() {
+            if let Some(node) = last_value_node(child) {
+                return Some(node);
+            }
+        }
+        if node.key_value.is_some() {
+            return Some(node);
+        }
+    }
+    None
+}
+
+/// Find the node with the greatest key below (and including) the given node which has a value.
+#[inline]
+fn last_value_node<K, V, P: ChildPtr>(trie: &TrieNode<K, V, P>) -> Option<&TrieNode<K, V, P>> {
+    // Visit each node after all of its children, from the last child to the first.
+    let mut stack = vec![(trie, trie.children.iter())];
+    while let Some((_, children)) = stack.last_mut() {
+        if let Some((_, child)) = children.next_back() {
+            stack.push((child, child.children.iter()));
+            continue;
+        }
+        let (node, _) = stack.pop().unwrap();
+        if node.key_value.is_some() {
+            return Some(node);
+        }
+    }
+    None
+}
@@ -1,7 +1,13 @@
 use crate::TrieNode;
+use crate::entry::Entry;
+use crate::iter::{Ancestors, IntoIter, IterMut, Range, ValuesMut};
+use crate::keys::{ByteNibbles, Nibbles, compare_keys, match_len};
 use crate::traversal::DescendantResult::*;
-use crate::{SubTrie, SubTrieMut, Trie, TrieCommon, TrieKey};
+use crate::{Granularity, SubTrie, SubTrieMut, Trie, TrieCommon, TrieKey};
 use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::mem;
+use std::ops::{Bound, RangeBounds};
 
 use nibble_vec::Nibblet;
 
@@ -12,12 +18,28 @@ where
     /// Create an empty Trie.
     #[inline]
     pub fn new() -> Trie<K, V> {
+        Trie::with_granularity(Granularity::Nibble)
+    }
+
+    /// Create an empty Trie, which branches on the given number of key bits at each node.
+    ///
+    /// Panics if the keys are encoded bit by bit and `granularity` is `Granularity::Byte`.
+    #[inline]
+    pub fn with_granularity(granularity: Granularity) -> Trie<K, V> {
+        granularity.check_key::<K>();
         Trie {
             length: 0,
+            granularity,
             node: TrieNode::new(),
         }
     }
 
+    /// Get the number of key bits that this trie branches on at each node.
+    #[inline]
+    pub fn granularity(&self) -> Granularity {
+        self.granularity
+    }
+
     /// Fetch a reference to the given key's corresponding value, if any.
     ///
     /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
@@ -28,9 +50,22 @@ where
         K: Borrow<Q>,
         Q: TrieKey,
     {
-        let key_fragments = key.encode();
+        match key.encode_borrowed() {
+            Some(bytes) => self.get_nv(&ByteNibbles(bytes), key),
+            None => self.get_nv(&key.encode(), key),
+        }
+    }
+
+    /// Fetch a reference to the value of a key, given its encoding.
+    #[inline]
+    pub(crate) fn get_nv<N, Q>(&self, nv: &N, key: &Q) -> Option<&V>
+    where
+        N: ?Sized + Nibbles,
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
         self.node
-            .get(&key_fragments)
+            .get(nv, self.granularity)
             .and_then(|t| t.value_checked(key))
     }
 
@@ -44,23 +79,52 @@ where
         K: Borrow<Q>,
         Q: TrieKey,
     {
-        let key_fragments = key.encode();
-        self.node
-            .get_mut(&key_fragments)
-            .and_then(|t| t.value_checked_mut(key))
+        let node = match key.encode_borrowed() {
+            Some(bytes) => self.node.get_mut(&ByteNibbles(bytes), self.granularity),
+            None => self.node.get_mut(&key.encode(), self.granularity),
+        };
+        node.and_then(|t| t.value_checked_mut(key))
     }
 
     /// Insert the given key-value pair, returning any previous value associated with the key.
     #[inline]
     pub fn insert(&mut self, key: K, value: V) -> Option<V> {
         let key_fragments = key.encode();
-        let result = self.node.insert(key, value, key_fragments);
+        self.insert_nv(key, value, key_fragments)
+    }
+
+    /// Insert a key-value pair, given the encoding of the key.
+    #[inline]
+    pub(crate) fn insert_nv(&mut self, key: K, value: V, key_fragments: Nibblet) -> Option<V> {
+        let result = self
+            .node
+            .insert(key, value, key_fragments, self.granularity);
         if result.is_none() {
             self.length += 1;
         }
         result
     }
 
+    /// Get the entry for the given key, for in-place manipulation of its value.
+    #[inline]
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V> {
+        let key_fragments = key.encode();
+        self.entry_nv(key, key_fragments)
+    }
+
+    /// Get the entry for a key, given its encoding.
+    #[inline]
+    pub(crate) fn entry_nv(&mut self, key: K, key_fragments: Nibblet) -> Entry<'_, K, V> {
+        let position = self.node.find_position(&key_fragments, self.granularity);
+        Entry::new(
+            position,
+            key,
+            key_fragments,
+            self.granularity,
+            &mut self.length,
+        )
+    }
+
     /// Remove the value associated with the given key.
     ///
     /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
@@ -71,18 +135,202 @@ where
         K: Borrow<Q>,
         Q: TrieKey,
     {
-        let removed = self.node.remove(key);
+        self.remove_nv(key, &key.encode())
+    }
+
+    /// Remove the value associated with a key, given its encoding.
+    #[inline]
+    pub(crate) fn remove_nv<Q>(&mut self, key: &Q, key_fragments: &Nibblet) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        let removed = self.node.remove(key, key_fragments, self.granularity);
         if removed.is_some() {
             self.length -= 1;
         }
         removed
     }
 
+    /// Remove all keys that start with the given prefix, and return them as a new trie.
+    ///
+    /// The prefix is matched against the encoded keys, so it needn't be a key itself, or even
+    /// have the same type as the keys (e.g. it could be a shorter tuple, for tuple keys).
+    pub fn remove_prefix<Q>(&mut self, prefix: &Q) -> Trie<K, V>
+    where
+        Q: ?Sized + TrieKey,
+    {
+        let nv = prefix.encode();
+        if nv.is_empty() {
+            return mem::replace(self, Trie::with_granularity(self.granularity));
+        }
+
+        let mut removed = Trie::with_granularity(self.granularity);
+        if let Some(child) = self.node.remove_prefix(&nv, self.granularity) {
+            let length = child.compute_size();
+            self.length -= length;
+
+            let bucket = self.granularity.bucket(&child.key, 0);
+            removed.node.add_child(bucket, child, self.granularity);
+            removed.length = length;
+        }
+        removed
+    }
+
+    /// Retain only the keys and values for which `f` returns true.
+    ///
+    /// Keys are visited in the same order as `iter`.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        self.length -= self.node.retain(&mut f);
+    }
+
+    /// Retain only the keys whose encodings have prefixes accepted by `f`.
+    ///
+    /// The function `f` is called with the bytes of the encoded prefix of each node, starting
+    /// from the root (with no bytes). If it returns false, the node's entire subtree is removed
+    /// without calling `f` on any of its descendants. Nodes only exist at the prefixes where
+    /// keys branch, so `f` should be prefix-closed: if it rejects a prefix, it should also
+    /// reject every extension of that prefix.
+    ///
+    /// With `Granularity::Nibble`, a node's prefix may end halfway through a byte. Such a node
+    /// is judged by the whole bytes of its prefix, which all of the keys below it share, so `f`
+    /// isn't called again if they were already accepted for the node's parent.
+    pub fn retain_prefixes<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&[u8]) -> bool,
+    {
+        let root_prefix = Nibblet::new();
+        if f(&[]) {
+            self.length -= self.node.retain_prefixes(&root_prefix, &mut f);
+        } else {
+            *self = Trie::with_granularity(self.granularity);
+        }
+    }
+
+    /// Move all the keys and values of `other` into this trie, combining the values of keys in
+    /// both tries with `merge(value, other_value)`.
+    ///
+    /// The tries are merged node by node, so subtrees which are only in `other` are moved over
+    /// whole, and keys aren't encoded. Keys which are in both tries keep the key from `self`.
+    pub fn union_with<F>(&mut self, other: Trie<K, V>, mut merge: F)
+    where
+        F: FnMut(V, V) -> V,
+    {
+        let other = other.regranulate(self.granularity);
+        let shared = self
+            .node
+            .union_with(other.node, self.granularity, &mut merge);
+        self.length += other.length - shared;
+    }
+
+    /// Retain only the keys which are also in `other`, combining their values with
+    /// `merge(value, other_value)`.
+    ///
+    /// Like `union_with`, this works node by node, so subtrees which are only in one of the
+    /// tries are discarded whole.
+    pub fn intersection_with<W, F>(&mut self, other: Trie<K, W>, mut merge: F)
+    where
+        F: FnMut(V, W) -> V,
+    {
+        let other = other.regranulate(self.granularity);
+        self.length = self
+            .node
+            .intersection_with(other.node, self.granularity, &mut merge);
+    }
+
+    /// Remove all the keys which are in `other`.
+    ///
+    /// Like `union_with`, this works node by node, so subtrees which are only in one of the
+    /// tries are skipped whole.
+    pub fn difference<W>(&mut self, other: &Trie<K, W>) {
+        if other.granularity == self.granularity {
+            self.length -= self.node.difference(&other.node, self.granularity);
+        } else {
+            for key in other.keys() {
+                self.remove(key);
+            }
+        }
+    }
+
+    /// Split the trie in two at the given key, returning a trie of all the keys greater than or
+    /// equal to it, and keeping the keys less than it.
+    ///
+    /// Keys are ordered as in `iter`. Subtrees are moved whole, so only the nodes along the path
+    /// to the key are split, and no keys are encoded.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    pub fn split_off<Q>(&mut self, key: &Q) -> Trie<K, V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        let nv = key.encode();
+        if nv.is_empty() {
+            return mem::replace(self, Trie::with_granularity(self.granularity));
+        }
+
+        let node = self.node.split_off(&nv, self.granularity);
+        let length = node.compute_size();
+        self.length -= length;
+        Trie {
+            length,
+            granularity: self.granularity,
+            node,
+        }
+    }
+
+    /// Move all the keys and values of `other` into this trie, leaving `other` empty.
+    ///
+    /// If a key is in both tries, its value from `other` replaces the one in this trie. Like
+    /// `union_with`, this works node by node.
+    pub fn append(&mut self, other: &mut Trie<K, V>) {
+        let other = mem::replace(other, Trie::with_granularity(other.granularity));
+        self.union_with(other, |_, value| value);
+    }
+
+    /// Rebuild this trie with the given granularity, if it has a different one.
+    fn regranulate(self, granularity: Granularity) -> Trie<K, V> {
+        if self.granularity == granularity {
+            return self;
+        }
+        let mut trie = Trie::with_granularity(granularity);
+        for (key, value) in self {
+            trie.insert(key, value);
+        }
+        trie
+    }
+
     /// Get a mutable reference to the value stored at this node, if any.
     pub fn value_mut(&mut self) -> Option<&mut V> {
         self.node.value_mut()
     }
 
+    /// Return an iterator over the keys and mutable values of the Trie.
+    ///
+    /// Keys are visited in the same order as `iter`.
+    #[inline]
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        IterMut::new(&mut self.node)
+    }
+
+    /// Return an iterator over the mutable values of the Trie.
+    #[inline]
+    pub fn values_mut(&mut self) -> ValuesMut<'_, K, V> {
+        ValuesMut::new(self.iter_mut())
+    }
+
+    /// Remove all keys and values from the Trie, and return an iterator over them.
+    ///
+    /// Keys are visited in the same order as `iter`.
+    #[inline]
+    pub fn drain(&mut self) -> IntoIter<K, V> {
+        mem::replace(self, Trie::with_granularity(self.granularity)).into_iter()
+    }
+
     /// Fetch a reference to the subtrie for a given key.
     ///
     /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
@@ -95,8 +343,8 @@ where
     {
         let key_fragments = key.encode();
         self.node
-            .get(&key_fragments)
-            .map(|node| node.as_subtrie(key_fragments))
+            .get(&key_fragments, self.granularity)
+            .map(|node| node.as_subtrie(key_fragments, self.granularity))
     }
 
     /// Fetch a mutable reference to the subtrie for a given key.
@@ -110,10 +358,13 @@ where
         Q: TrieKey,
     {
         let key_fragments = key.encode();
-        let length_ref = &mut self.length;
-        self.node
-            .get_mut(&key_fragments)
-            .map(move |node| node.as_subtrie_mut(key_fragments, length_ref))
+        self.node.get(&key_fragments, self.granularity)?;
+        Some(SubTrieMut {
+            prefix: key_fragments,
+            granularity: self.granularity,
+            length: &mut self.length,
+            root: &mut self.node,
+        })
     }
 
     /// Fetch a reference to the closest ancestor node of the given key.
@@ -134,10 +385,10 @@ where
     {
         let mut key_fragments = key.encode();
         self.node
-            .get_ancestor(&key_fragments)
+            .get_ancestor(&key_fragments, self.granularity)
             .map(|(node, node_key_len)| {
                 key_fragments.split(node_key_len);
-                node.as_subtrie(key_fragments)
+                node.as_subtrie(key_fragments, self.granularity)
             })
     }
 
@@ -153,7 +404,78 @@ where
         K: Borrow<Q>,
         Q: TrieKey,
     {
-        self.get_ancestor(key).and_then(|t| t.node.value())
+        let ancestor = match key.encode_borrowed() {
+            Some(bytes) => self
+                .node
+                .get_ancestor(&ByteNibbles(bytes), self.granularity),
+            None => self.node.get_ancestor(&key.encode(), self.granularity),
+        };
+        ancestor.and_then(|(node, _)| node.value())
+    }
+
+    /// Find the longest key that is a prefix of the given key, along with its value and its
+    /// length.
+    ///
+    /// The length is the number of bytes of the given key's encoding covered by the match, or
+    /// the number of bits for keys encoded bit by bit (see `TrieKey::BITWISE`), like the prefix
+    /// length of an `IpPrefix`. For other keys that don't encode to whole bytes, it is rounded
+    /// down.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn longest_prefix_match<Q>(&self, key: &Q) -> Option<(&K, &V, usize)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        let found = match key.encode_borrowed() {
+            Some(bytes) => self.longest_prefix_match_nv(&ByteNibbles(bytes)),
+            None => self.longest_prefix_match_nv(&key.encode()),
+        };
+        found.map(|(key, value, depth)| (key, value, match_len::<K>(depth)))
+    }
+
+    /// Find the longest key whose encoding is a prefix of the given bytes, along with its value
+    /// and the number of bytes matched.
+    ///
+    /// This is the same as `longest_prefix_match`, but for input that is already encoded, like
+    /// a buffer of text being tokenised.
+    #[inline]
+    pub fn longest_prefix_match_bytes(&self, bytes: &[u8]) -> Option<(&K, &V, usize)> {
+        self.longest_prefix_match_nv(&ByteNibbles(bytes))
+            .map(|(key, value, depth)| (key, value, depth / 2))
+    }
+
+    /// Find the longest key that is a prefix of `nv`, along with its value and the number of
+    /// nibbles matched.
+    fn longest_prefix_match_nv<N>(&self, nv: &N) -> Option<(&K, &V, usize)>
+    where
+        N: ?Sized + Nibbles,
+    {
+        self.node
+            .get_ancestor(nv, self.granularity)
+            .and_then(|(node, depth)| {
+                node.key_value
+                    .as_ref()
+                    .map(|kv| (&kv.key, &kv.value, depth))
+            })
+    }
+
+    /// Return an iterator over all the keys and values whose keys are prefixes of the given key.
+    ///
+    /// Prefixes are visited from the shortest to the longest, so the last item yielded is the
+    /// one returned by `get_ancestor`. The given key is included if it is in the trie.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn ancestors<Q>(&self, key: &Q) -> Ancestors<'_, K, V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        Ancestors::new(&self.node, key.encode(), self.granularity)
     }
 
     /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
@@ -165,34 +487,74 @@ where
         Q: TrieKey,
     {
         let mut nv = key.encode();
-        let (ancestor_node, depth) = self.node.get_raw_ancestor(&nv);
+        let (ancestor_node, depth) = self.node.get_raw_ancestor(&nv, self.granularity);
         nv.split(depth);
-        ancestor_node.as_subtrie(nv)
+        ancestor_node.as_subtrie(nv, self.granularity)
     }
 
     /// Fetch the closest descendant for a given key.
     ///
     /// If the key is in the trie, this is the same as `subtrie`.
     ///
-    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
-    /// form *must* match those for the key type
+    /// The key is only used as a prefix of the encoded keys, so it needn't have the same type
+    /// as the keys (e.g. it could be a shorter tuple, for tuple keys).
     #[inline]
     pub fn get_raw_descendant<'a, Q: ?Sized>(&'a self, key: &Q) -> Option<SubTrie<'a, K, V>>
     where
-        K: Borrow<Q>,
         Q: TrieKey,
     {
         let mut nv = key.encode();
-        self.node.get_raw_descendant(&nv).map(|desc| {
-            let (node, prefix) = match desc {
-                NoModification(node) => (node, nv),
-                ExtendKey(node, depth, extension) => {
-                    nv.split(depth);
-                    (node, nv.join(extension))
+        self.node
+            .get_raw_descendant(&nv, self.granularity)
+            .map(|desc| {
+                let (node, prefix) = match desc {
+                    NoModification(node) => (node, nv),
+                    ExtendKey(node, depth, extension) => {
+                        nv.split(depth);
+                        (node, nv.join(extension))
+                    }
+                };
+                node.as_subtrie(prefix, self.granularity)
+            })
+    }
+
+    /// Return an iterator over the keys and values that lie within a range.
+    ///
+    /// Keys are ordered by their encodings (lexicographically), which is the same order that
+    /// `iter` uses. This need not agree with their `Ord` implementation, if they have one.
+    ///
+    /// The key may be any borrowed form of the trie's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    pub fn range<Q, R>(&self, range: R) -> Range<'_, K, V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+        R: RangeBounds<Q>,
+    {
+        let mut last = match encode_bound(range.end_bound()) {
+            Some((nv, inclusive)) => self.node.seek_upper_bound(&nv, inclusive, self.granularity),
+            None => self.node.last_value_node(),
+        };
+
+        let start = encode_bound(range.start_bound()).and_then(|(nv, inclusive)| {
+            // Check that the last key doesn't lie before the lower bound.
+            if let Some(last_key) = last.and_then(TrieNode::key) {
+                let last_nv = last_key.encode();
+                match compare_keys(&last_nv, &nv) {
+                    Ordering::Less => last = None,
+                    Ordering::Equal if !inclusive => last = None,
+                    _ => (),
                 }
-            };
-            node.as_subtrie(prefix)
-        })
+            }
+
+            if nv.is_empty() && inclusive {
+                None
+            } else {
+                Some(self.node.seek_lower_bound(&nv, inclusive, self.granularity))
+            }
+        });
+
+        Range::new(&self.node, start, last.and_then(TrieNode::key))
     }
 
     /// Take a function `f` and apply it to the value stored at `key`.
@@ -203,24 +565,32 @@ where
     where
         F: Fn(&mut V),
     {
-        {
-            if let Some(v) = self.get_mut(&key) {
-                f(v);
-                return;
-            }
-        }
-        self.insert(key, default);
+        self.entry(key).and_modify(f).or_insert(default);
     }
 
     /// Check that the Trie invariants are satisfied - you shouldn't ever have to call this!
     /// Quite slow!
     #[doc(hidden)]
     pub fn check_integrity(&self) -> bool {
-        let (ok, length) = self.node.check_integrity_recursive(&Nibblet::new());
+        let (ok, length) = self
+            .node
+            .check_integrity_recursive(&Nibblet::new(), self.granularity);
         ok && length == self.length
     }
 }
 
+/// Encode a range bound as the bound's key fragments, and whether it is inclusive.
+fn encode_bound<Q>(bound: Bound<&Q>) -> Option<(Nibblet, bool)>
+where
+    Q: ?Sized + TrieKey,
+{
+    match bound {
+        Bound::Included(key) => Some((key.encode(), true)),
+        Bound::Excluded(key) => Some((key.encode(), false)),
+        Bound::Unbounded => None,
+    }
+}
+
 impl<K, V> PartialEq for Trie<K, V>
 where
     K: TrieKey,
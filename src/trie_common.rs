@@ -1,11 +1,15 @@
 use crate::TrieNode;
+use crate::child_map::{ChildPtr, Owned, Shared};
 use crate::iter::*;
-use crate::{SubTrie, SubTrieMut, Trie, TrieKey};
+use crate::subtrie::subtrie_node;
+use crate::{PersistentTrie, SubTrie, SubTrieMut, Trie, TrieKey};
+use std::sync::LazyLock;
 
 use nibble_vec::Nibblet;
 
 /// Common functionality available for tries and subtries.
-pub trait TrieCommon<'a, K: 'a, V: 'a>: ContainsTrieNode<'a, K, V>
+pub trait TrieCommon<'a, K: 'a, V: 'a, P: ChildPtr + 'a = Owned>:
+    ContainsTrieNode<'a, K, V, P>
 where
     K: TrieKey,
     Self: Sized,
@@ -13,13 +17,13 @@ where
     /// Get the key stored at this node, if any.
     #[inline]
     fn key(self) -> Option<&'a K> {
-        self.trie_node().key()
+        self.trie_node()?.key()
     }
 
     /// Get the value stored at this node, if any.
     #[inline]
     fn value(self) -> Option<&'a V> {
-        self.trie_node().value()
+        self.trie_node()?.value()
     }
 
     /// Number of key/value pairs stored in this trie.
@@ -34,43 +38,46 @@ where
     /// Determine if the trie is a leaf node (has no children).
     #[inline]
     fn is_leaf(self) -> bool {
-        self.trie_node().child_count == 0
+        self.trie_node().is_none_or(|node| node.child_count() == 0)
     }
 
     /// Return an iterator over the keys and values of the Trie.
     #[inline]
-    fn iter(self) -> Iter<'a, K, V> {
-        Iter::new(self.trie_node())
+    fn iter(self) -> Iter<'a, K, V, P> {
+        self.trie_node().map_or_else(Iter::empty, Iter::new)
     }
 
     /// Return an iterator over the keys of the Trie.
     #[inline]
-    fn keys(self) -> Keys<'a, K, V> {
+    fn keys(self) -> Keys<'a, K, V, P> {
         Keys::new(self.iter())
     }
 
     /// Return an iterator over the values of the Trie.
     #[inline]
-    fn values(self) -> Values<'a, K, V> {
+    fn values(self) -> Values<'a, K, V, P> {
         Values::new(self.iter())
     }
 
     /// Return an iterator over the child subtries of this node.
-    fn children(self) -> Children<'a, K, V>;
+    fn children(self) -> Children<'a, K, V, P>;
 
     /// Get the prefix of this node.
     #[inline]
     fn prefix(self) -> &'a Nibblet {
-        &self.trie_node().key
+        // A subtrie without a node has no key fragments of its own.
+        static NO_PREFIX: LazyLock<Nibblet> = LazyLock::new(Nibblet::new);
+        self.trie_node().map_or(&NO_PREFIX, |node| &node.key)
     }
 }
 
-/// Helper trait for Trie/SubTrie/SubTrieMut, which all contain a trie node.
-pub trait ContainsTrieNode<'a, K: 'a, V: 'a>
+/// Helper trait for Trie/SubTrie/SubTrieMut, which all contain a trie node (except for a
+/// SubTrieMut whose keys have all been removed).
+pub trait ContainsTrieNode<'a, K: 'a, V: 'a, P: ChildPtr + 'a = Owned>
 where
     K: TrieKey,
 {
-    fn trie_node(self) -> &'a TrieNode<K, V>;
+    fn trie_node(self) -> Option<&'a TrieNode<K, V, P>>;
 }
 
 /// Regular trie.
@@ -79,8 +86,8 @@ where
     K: TrieKey,
 {
     #[inline]
-    fn trie_node(self) -> &'a TrieNode<K, V> {
-        &self.node
+    fn trie_node(self) -> Option<&'a TrieNode<K, V>> {
+        Some(&self.node)
     }
 }
 
@@ -94,22 +101,49 @@ where
     }
     #[inline]
     fn children(self) -> Children<'a, K, V> {
-        Children::new(self.node.key.clone(), &self.node)
+        Children::new(self.node.key.clone(), self.granularity, &self.node)
+    }
+}
+
+/// Version of a persistent trie.
+impl<'a, K: 'a, V: 'a> ContainsTrieNode<'a, K, V, Shared> for &'a PersistentTrie<K, V>
+where
+    K: TrieKey,
+{
+    #[inline]
+    fn trie_node(self) -> Option<&'a TrieNode<K, V, Shared>> {
+        Some(&self.node)
+    }
+}
+
+impl<'a, K: 'a, V: 'a> TrieCommon<'a, K, V, Shared> for &'a PersistentTrie<K, V>
+where
+    K: TrieKey,
+{
+    #[inline]
+    fn len(self) -> usize {
+        self.length
+    }
+    #[inline]
+    fn children(self) -> Children<'a, K, V, Shared> {
+        Children::new(self.node.key.clone(), self.granularity, &self.node)
     }
 }
 
 /// Subtrie.
-impl<'a: 'b, 'b, K: 'a, V: 'a> ContainsTrieNode<'a, K, V> for &'b SubTrie<'a, K, V>
+impl<'a: 'b, 'b, K: 'a, V: 'a, P: ChildPtr + 'a> ContainsTrieNode<'a, K, V, P>
+    for &'b SubTrie<'a, K, V, P>
 where
     K: TrieKey,
 {
     #[inline]
-    fn trie_node(self) -> &'a TrieNode<K, V> {
-        self.node
+    fn trie_node(self) -> Option<&'a TrieNode<K, V, P>> {
+        Some(self.node)
     }
 }
 
-impl<'a: 'b, 'b, K: 'a, V: 'a> TrieCommon<'a, K, V> for &'b SubTrie<'a, K, V>
+impl<'a: 'b, 'b, K: 'a, V: 'a, P: ChildPtr + 'a> TrieCommon<'a, K, V, P>
+    for &'b SubTrie<'a, K, V, P>
 where
     K: TrieKey,
 {
@@ -118,8 +152,8 @@ where
         self.node.compute_size()
     }
     #[inline]
-    fn children(self) -> Children<'a, K, V> {
-        Children::new(self.prefix.clone(), self.node)
+    fn children(self) -> Children<'a, K, V, P> {
+        Children::new(self.prefix.clone(), self.granularity, self.node)
     }
 }
 
@@ -129,8 +163,8 @@ where
     K: TrieKey,
 {
     #[inline]
-    fn trie_node(self) -> &'a TrieNode<K, V> {
-        self.node
+    fn trie_node(self) -> Option<&'a TrieNode<K, V>> {
+        subtrie_node(self.root, &self.prefix, self.granularity).map(|(node, _)| node)
     }
 }
 
@@ -141,11 +175,14 @@ where
     /// **Computes** from scratch.
     #[inline]
     fn len(self) -> usize {
-        self.node.compute_size()
+        self.trie_node().map_or(0, TrieNode::compute_size)
     }
     #[inline]
     fn children(self) -> Children<'a, K, V> {
-        Children::new(self.prefix, self.node)
+        match subtrie_node(self.root, &self.prefix, self.granularity) {
+            Some((node, key)) => Children::new(key, self.granularity, node),
+            None => Children::empty(self.granularity),
+        }
     }
 }
 
@@ -155,8 +192,8 @@ where
     K: TrieKey,
 {
     #[inline]
-    fn trie_node(self) -> &'b TrieNode<K, V> {
-        self.node
+    fn trie_node(self) -> Option<&'b TrieNode<K, V>> {
+        subtrie_node(self.root, &self.prefix, self.granularity).map(|(node, _)| node)
     }
 }
 
@@ -166,10 +203,13 @@ where
 {
     #[inline]
     fn len(self) -> usize {
-        self.node.compute_size()
+        self.trie_node().map_or(0, TrieNode::compute_size)
     }
     #[inline]
     fn children(self) -> Children<'b, K, V> {
-        Children::new(self.prefix.clone(), self.node)
+        match subtrie_node(self.root, &self.prefix, self.granularity) {
+            Some((node, key)) => Children::new(key, self.granularity, node),
+            None => Children::empty(self.granularity),
+        }
     }
 }
@@ -1,12 +1,15 @@
+use crate::child_map::{self, Child, ChildMap, ChildPtr, Owned, Shared};
 use crate::keys::*;
-use crate::{BRANCH_FACTOR, SubTrie, SubTrieMut};
+use crate::{Granularity, SubTrie};
 use std::borrow::Borrow;
 use std::default::Default;
+use std::mem;
+use std::sync::Arc;
 
 use nibble_vec::Nibblet;
 
-#[derive(Debug, Clone)]
-pub struct TrieNode<K, V> {
+#[derive(Debug)]
+pub struct TrieNode<K, V, P: ChildPtr = Owned> {
     /// Key fragments/bits associated with this node, such that joining the keys from all
     /// parent nodes and this node is equal to the bit-encoding of this node's key.
     pub key: Nibblet,
@@ -14,12 +17,9 @@ pub struct TrieNode<K, V> {
     /// The key and value stored at this node.
     pub key_value: Option<Box<KeyValue<K, V>>>,
 
-    /// The number of children which are Some rather than None.
-    pub child_count: usize,
-
     /// The children of this node stored such that the first nibble of each child key
     /// dictates the child's bucket.
-    pub children: [Option<Box<TrieNode<K, V>>>; BRANCH_FACTOR],
+    pub children: ChildMap<K, V, P>,
 }
 
 #[derive(Debug, Clone)]
@@ -28,29 +28,27 @@ pub struct KeyValue<K, V> {
     pub value: V,
 }
 
-impl<K, V> TrieNode<K, V>
+impl<K, V, P: ChildPtr> TrieNode<K, V, P>
 where
     K: TrieKey,
 {
     /// Create a value-less, child-less TrieNode.
     #[inline]
-    pub fn new() -> TrieNode<K, V> {
+    pub fn new() -> TrieNode<K, V, P> {
         TrieNode {
             key: Nibblet::new(),
             key_value: None,
-            children: Default::default(),
-            child_count: 0,
+            children: ChildMap::new(),
         }
     }
 
     /// Create a TrieNode with no children.
     #[inline]
-    pub fn with_key_value(key_fragments: Nibblet, key: K, value: V) -> TrieNode<K, V> {
+    pub fn with_key_value(key_fragments: Nibblet, key: K, value: V) -> TrieNode<K, V, P> {
         TrieNode {
             key: key_fragments,
             key_value: Some(Box::new(KeyValue { key, value })),
-            children: Default::default(),
-            child_count: 0,
+            children: ChildMap::new(),
         }
     }
 
@@ -107,44 +105,43 @@ where
     /// Compute the number of keys and values in this node's subtrie.
     #[inline]
     pub fn compute_size(&self) -> usize {
-        let mut size = self.key_value.is_some() as usize;
-
-        for child in &self.children {
-            if let Some(ref child) = *child {
-                // TODO: could unroll this recursion
-                size += child.compute_size();
-            }
+        let mut size = 0;
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            size += node.key_value.is_some() as usize;
+            stack.extend(node.children.iter().map(|(_, child)| child));
         }
-
         size
     }
 
+    /// Get the number of children of this node.
+    #[inline]
+    pub fn child_count(&self) -> usize {
+        self.children.len()
+    }
+
     /// Add a child at the given index, given that none exists there already.
     #[inline]
-    pub fn add_child(&mut self, idx: usize, node: Box<TrieNode<K, V>>) {
-        debug_assert!(self.children[idx].is_none());
-        self.child_count += 1;
-        self.children[idx] = Some(node);
+    pub fn add_child(&mut self, idx: usize, node: Child<K, V, P>, granularity: Granularity) {
+        self.children.insert(idx, node, granularity);
     }
 
     /// Remove a child at the given index, if it exists.
     #[inline]
-    pub fn take_child(&mut self, idx: usize) -> Option<Box<TrieNode<K, V>>> {
-        self.children[idx].take().inspect(|_| {
-            self.child_count -= 1;
-        })
+    pub fn take_child(&mut self, idx: usize) -> Option<Child<K, V, P>> {
+        self.children.remove(idx)
     }
 
     /// Helper function for removing the single child of a node.
     #[inline]
-    pub fn take_only_child(&mut self) -> Box<TrieNode<K, V>> {
-        debug_assert_eq!(self.child_count, 1);
-        for i in 0..BRANCH_FACTOR {
-            if let Some(child) = self.take_child(i) {
-                return child;
-            }
-        }
-        unreachable!("node with child_count 1 has no actual children");
+    pub fn take_only_child(&mut self) -> Child<K, V, P> {
+        debug_assert_eq!(self.child_count(), 1);
+        let (bucket, _) = self
+            .children
+            .iter()
+            .next()
+            .expect("node with child_count 1 has no actual children");
+        self.take_child(bucket).unwrap()
     }
 
     /// Set the key and value of a node, given that it currently lacks one.
@@ -182,14 +179,14 @@ where
 
     /// Get a reference to this node if it has a value.
     #[inline]
-    pub fn as_value_node(&self) -> Option<&TrieNode<K, V>> {
+    pub fn as_value_node(&self) -> Option<&TrieNode<K, V, P>> {
         self.key_value.as_ref().map(|_| self)
     }
 
     /// Split a node at a given index in its key, transforming it into a prefix node of its
     /// previous self.
     #[inline]
-    pub fn split(&mut self, idx: usize) {
+    pub fn split(&mut self, idx: usize, granularity: Granularity) {
         // Extract all the parts of the suffix node, starting with the key.
         let key = self.key.split(idx);
 
@@ -197,54 +194,58 @@ where
         let key_value = self.key_value.take();
 
         // Children.
-        let mut children: [Option<Box<TrieNode<K, V>>>; BRANCH_FACTOR] = Default::default();
-
-        for (i, child) in self.children.iter_mut().enumerate() {
-            if child.is_some() {
-                children[i] = child.take();
-            }
-        }
-
-        // Child count.
-        let child_count = self.child_count;
-        self.child_count = 1;
+        let children = mem::take(&mut self.children);
 
         // Insert the collected items below what is now an empty prefix node.
-        let bucket = key.get(0) as usize;
-        self.children[bucket] = Some(Box::new(TrieNode {
-            key,
-            key_value,
-            children,
-            child_count,
-        }));
+        let bucket = granularity.bucket(&key, 0);
+        self.add_child(
+            bucket,
+            P::new(TrieNode {
+                key,
+                key_value,
+                children,
+            }),
+            granularity,
+        );
     }
     #[inline]
-    pub fn as_subtrie(&self, prefix: Nibblet) -> SubTrie<'_, K, V> {
-        SubTrie { prefix, node: self }
-    }
-    #[inline]
-    pub fn as_subtrie_mut<'a>(
-        &'a mut self,
-        prefix: Nibblet,
-        length: &'a mut usize,
-    ) -> SubTrieMut<'a, K, V> {
-        SubTrieMut {
+    pub fn as_subtrie(&self, prefix: Nibblet, granularity: Granularity) -> SubTrie<'_, K, V, P> {
+        SubTrie {
             prefix,
-            length,
+            granularity,
             node: self,
         }
     }
-
     /// Check the integrity of a trie subtree (quite costly).
     /// Return true and the size of the subtree if all checks are successful,
     /// or false and a junk value if any test fails.
-    pub fn check_integrity_recursive(&self, prefix: &Nibblet) -> (bool, usize) {
+    pub fn check_integrity_recursive(
+        &self,
+        prefix: &Nibblet,
+        granularity: Granularity,
+    ) -> (bool, usize) {
+        self.check_integrity_with(prefix, granularity, &|key: &K, trie_key: &Nibblet| {
+            key.encode() == *trie_key
+        })
+    }
+
+    /// Check the integrity of a trie subtree, using `check_key` to check the key stored at
+    /// each value node against the key fragments leading to it.
+    pub fn check_integrity_with<F>(
+        &self,
+        prefix: &Nibblet,
+        granularity: Granularity,
+        check_key: &F,
+    ) -> (bool, usize)
+    where
+        F: Fn(&K, &Nibblet) -> bool,
+    {
         let mut sub_tree_size = 0;
         let is_root = prefix.is_empty();
 
-        // Check that no value-less, non-root nodes have only 1 child.
-        if !is_root && self.child_count == 1 && self.key_value.is_none() {
-            println!("Value-less node with a single child.");
+        // Check that no value-less, non-root nodes have fewer than 2 children.
+        if !is_root && self.child_count() < 2 && self.key_value.is_none() {
+            println!("Value-less node with fewer than two children.");
             return (false, sub_tree_size);
         }
 
@@ -254,17 +255,10 @@ where
             return (false, sub_tree_size);
         }
 
-        // Check that the child count matches the actual number of children.
-        let child_count = self
-            .children
-            .iter()
-            .fold(0, |acc, e| acc + (e.is_some() as usize));
-
-        if child_count != self.child_count {
-            println!(
-                "Child count error, recorded: {}, actual: {}",
-                self.child_count, child_count
-            );
+        // Check that the child count matches the actual number of children, and that the
+        // children are stored in the right layout for their number.
+        if !self.children.check_integrity(granularity) {
+            println!("Child map error, recorded count: {}", self.children.len());
             return (false, sub_tree_size);
         }
 
@@ -275,20 +269,24 @@ where
         if let Some(ref kv) = self.key_value {
             sub_tree_size += 1;
 
-            let actual_key = kv.key.encode();
-
-            if trie_key != actual_key {
+            if !check_key(&kv.key, &trie_key) {
                 return (false, sub_tree_size);
             }
         }
 
         // Recursively check children.
-        for i in 0..BRANCH_FACTOR {
-            if let Some(ref child) = self.children[i] {
-                match child.check_integrity_recursive(&trie_key) {
-                    (false, _) => return (false, sub_tree_size),
-                    (true, child_size) => sub_tree_size += child_size,
-                }
+        for (bucket, child) in self.children.iter() {
+            // Check that the child's key is a whole number of buckets, and starts with the
+            // bucket it is stored in.
+            if !granularity.is_aligned(child.key.len())
+                || child.key.is_empty()
+                || granularity.bucket(&child.key, 0) != bucket
+            {
+                return (false, sub_tree_size);
+            }
+            match child.check_integrity_with(&trie_key, granularity, check_key) {
+                (false, _) => return (false, sub_tree_size),
+                (true, child_size) => sub_tree_size += child_size,
             }
         }
 
@@ -296,7 +294,117 @@ where
     }
 }
 
-impl<K: TrieKey, V> Default for TrieNode<K, V> {
+impl<K, V> TrieNode<K, V> {
+    /// Move the nodes of this trie into nodes which can be shared between the versions of a
+    /// `PersistentTrie`. The keys and values are moved rather than cloned.
+    pub fn into_shared(mut self, granularity: Granularity) -> TrieNode<K, V, Shared> {
+        // Move the key and value of a node into a shared node, and detach its children.
+        fn move_node<K, V>(
+            node: &mut TrieNode<K, V>,
+        ) -> (TrieNode<K, V, Shared>, child_map::IntoIter<K, V, Owned>) {
+            let shared = TrieNode {
+                key: mem::replace(&mut node.key, Nibblet::new()),
+                key_value: node.key_value.take(),
+                children: ChildMap::new(),
+            };
+            (shared, mem::take(&mut node.children).into_iter())
+        }
+
+        // The shared nodes on the path to the current node, each with an iterator over the
+        // children left to move.
+        let mut stack = vec![move_node(&mut self)];
+
+        loop {
+            let (_, ref mut children) = *stack.last_mut().unwrap();
+            match children.next() {
+                Some(mut child) => stack.push(move_node(&mut child)),
+                None => {
+                    let (node, _) = stack.pop().unwrap();
+                    match stack.last_mut() {
+                        Some((parent, _)) => {
+                            let bucket = granularity.bucket(&node.key, 0);
+                            parent.children.insert(bucket, Arc::new(node), granularity);
+                        }
+                        None => return node,
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<K, V> TrieNode<K, V, Shared>
+where
+    K: Clone,
+    V: Clone,
+{
+    /// Copy this node, sharing its children with the original rather than cloning them.
+    #[inline]
+    pub fn shallow_clone(&self) -> TrieNode<K, V, Shared> {
+        TrieNode {
+            key: self.key.clone(),
+            key_value: self.key_value.clone(),
+            children: self.children.clone(),
+        }
+    }
+}
+
+// Nodes are cloned and dropped using an explicit stack rather than recursion, so that very deep
+// tries can't overflow the call stack.
+
+impl<K, V, P: ChildPtr> Clone for TrieNode<K, V, P>
+where
+    K: Clone,
+    V: Clone,
+{
+    fn clone(&self) -> Self {
+        // Clone a node without its children.
+        fn clone_node<K: Clone, V: Clone, P: ChildPtr>(
+            node: &TrieNode<K, V, P>,
+        ) -> TrieNode<K, V, P> {
+            TrieNode {
+                key: node.key.clone(),
+                key_value: node.key_value.clone(),
+                children: node.children.empty_like(),
+            }
+        }
+
+        // The clones of the nodes on the path to the current node, each with an iterator over
+        // the children left to clone, and the bucket it belongs in.
+        let mut stack = vec![(clone_node(self), self.children.iter(), 0)];
+
+        loop {
+            let (_, ref mut children, _) = *stack.last_mut().unwrap();
+            match children.next() {
+                Some((bucket, child)) => {
+                    stack.push((clone_node(child), child.children.iter(), bucket));
+                }
+                None => {
+                    let (clone, _, bucket) = stack.pop().unwrap();
+                    match stack.last_mut() {
+                        Some((parent, _, _)) => parent.children.push(bucket, P::new(clone)),
+                        None => return clone,
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<K, V, P: ChildPtr> Drop for TrieNode<K, V, P> {
+    fn drop(&mut self) {
+        let mut stack: Vec<Child<K, V, P>> = mem::take(&mut self.children).into_iter().collect();
+        while let Some(mut node) = stack.pop() {
+            // Detach the node's children, so that it is dropped without recursing. A node which
+            // is shared with another trie is left alone, as dropping it only drops a reference.
+            if let Some(node) = P::try_get_mut(&mut node) {
+                stack.extend(mem::take(&mut node.children));
+            }
+        }
+    }
+}
+
+impl<K: TrieKey, V, P: ChildPtr> Default for TrieNode<K, V, P> {
     fn default() -> Self {
         Self::new()
     }
@@ -0,0 +1,235 @@
+use crate::iter::{Difference, Intersection, SetIntoIter, SetIter, SymmetricDifference, Union};
+use crate::{Entry, Granularity, Trie, TrieCommon, TrieKey, TrieSet};
+use std::borrow::Borrow;
+use std::iter::FromIterator;
+
+impl<K> TrieSet<K>
+where
+    K: TrieKey,
+{
+    /// Create an empty set.
+    #[inline]
+    pub fn new() -> TrieSet<K> {
+        TrieSet { trie: Trie::new() }
+    }
+
+    /// Create an empty set, whose trie branches on the given number of key bits at each node.
+    #[inline]
+    pub fn with_granularity(granularity: Granularity) -> TrieSet<K> {
+        TrieSet {
+            trie: Trie::with_granularity(granularity),
+        }
+    }
+
+    /// Get the number of key bits that this set's trie branches on at each node.
+    #[inline]
+    pub fn granularity(&self) -> Granularity {
+        self.trie.granularity()
+    }
+
+    /// Get the number of keys in the set.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.trie.len()
+    }
+
+    /// Determine if the set is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.trie.is_empty()
+    }
+
+    /// Remove all the keys from the set.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.trie = Trie::with_granularity(self.trie.granularity());
+    }
+
+    /// Determine if the set contains the given key.
+    ///
+    /// The key may be any borrowed form of the set's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        self.trie.get(key).is_some()
+    }
+
+    /// Add a key to the set, returning false if it was already present.
+    ///
+    /// If the key was already present, the set keeps the key it had.
+    #[inline]
+    pub fn insert(&mut self, key: K) -> bool {
+        match self.trie.entry(key) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(());
+                true
+            }
+        }
+    }
+
+    /// Remove a key from the set, returning true if it was present.
+    ///
+    /// The key may be any borrowed form of the set's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn remove<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        self.trie.remove(key).is_some()
+    }
+
+    /// Find the longest key in the set which is a prefix of the given key.
+    ///
+    /// The given key counts as a prefix of itself.
+    ///
+    /// The key may be any borrowed form of the set's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn get_ancestor<Q>(&self, key: &Q) -> Option<&K>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        self.trie.longest_prefix_match(key).map(|(k, _, _)| k)
+    }
+
+    /// Determine if the set contains the given key, or any prefix of it.
+    ///
+    /// The key may be any borrowed form of the set's key type, but TrieKey on the borrowed
+    /// form *must* match those for the key type.
+    #[inline]
+    pub fn has_ancestor<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + TrieKey,
+    {
+        self.trie.get_ancestor_value(key).is_some()
+    }
+
+    /// Return an iterator over the keys of the set.
+    #[inline]
+    pub fn iter(&self) -> SetIter<'_, K> {
+        SetIter::new(Some(self.trie.keys()))
+    }
+
+    /// Return an iterator over the keys which start with the given prefix.
+    ///
+    /// The prefix is matched against the encoded keys, so it needn't be a key itself, or even
+    /// have the same type as the keys.
+    #[inline]
+    pub fn iter_prefix<Q>(&self, prefix: &Q) -> SetIter<'_, K>
+    where
+        Q: ?Sized + TrieKey,
+    {
+        SetIter::new(self.trie.get_raw_descendant(prefix).map(|t| t.keys()))
+    }
+
+    /// Return an iterator over the keys which are in either set, without duplicates.
+    #[inline]
+    pub fn union<'a>(&'a self, other: &'a TrieSet<K>) -> Union<'a, K> {
+        Union::new(self.iter(), other.iter())
+    }
+
+    /// Return an iterator over the keys which are in both sets.
+    #[inline]
+    pub fn intersection<'a>(&'a self, other: &'a TrieSet<K>) -> Intersection<'a, K> {
+        Intersection::new(self.iter(), other.iter())
+    }
+
+    /// Return an iterator over the keys which are in this set, but not in `other`.
+    #[inline]
+    pub fn difference<'a>(&'a self, other: &'a TrieSet<K>) -> Difference<'a, K> {
+        Difference::new(self.iter(), other.iter())
+    }
+
+    /// Return an iterator over the keys which are in exactly one of the sets.
+    #[inline]
+    pub fn symmetric_difference<'a>(&'a self, other: &'a TrieSet<K>) -> SymmetricDifference<'a, K> {
+        SymmetricDifference::new(self.iter(), other.iter())
+    }
+
+    /// Check that the Trie invariants are satisfied - you shouldn't ever have to call this!
+    /// Quite slow!
+    #[doc(hidden)]
+    pub fn check_integrity(&self) -> bool {
+        self.trie.check_integrity()
+    }
+}
+
+impl<K> Default for TrieSet<K>
+where
+    K: TrieKey,
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K> PartialEq for TrieSet<K>
+where
+    K: TrieKey,
+{
+    #[inline]
+    fn eq(&self, other: &TrieSet<K>) -> bool {
+        self.trie == other.trie
+    }
+}
+
+impl<K> Eq for TrieSet<K> where K: TrieKey {}
+
+impl<K> FromIterator<K> for TrieSet<K>
+where
+    K: TrieKey,
+{
+    fn from_iter<T>(iter: T) -> TrieSet<K>
+    where
+        T: IntoIterator<Item = K>,
+    {
+        let mut set = TrieSet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+impl<K> Extend<K> for TrieSet<K>
+where
+    K: TrieKey,
+{
+    fn extend<T>(&mut self, iter: T)
+    where
+        T: IntoIterator<Item = K>,
+    {
+        for key in iter {
+            self.insert(key);
+        }
+    }
+}
+
+impl<K> IntoIterator for TrieSet<K> {
+    type Item = K;
+    type IntoIter = SetIntoIter<K>;
+
+    fn into_iter(self) -> SetIntoIter<K> {
+        SetIntoIter::new(self.trie.into_iter())
+    }
+}
+
+impl<'a, K> IntoIterator for &'a TrieSet<K>
+where
+    K: TrieKey,
+{
+    type Item = &'a K;
+    type IntoIter = SetIter<'a, K>;
+
+    fn into_iter(self) -> SetIter<'a, K> {
+        self.iter()
+    }
+}
@@ -0,0 +1,56 @@
+//! Check that lookups by borrowed byte keys don't allocate.
+
+use radix_trie::{PathTrie, Trie};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Count the allocations made on this thread by `f`.
+fn allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+#[test]
+fn borrowed_lookups_dont_allocate() {
+    let words = ["a", "ab", "abc", "abd", "b", "hello", "help", "world"];
+    let mut trie = Trie::new();
+    let mut path_trie = PathTrie::new();
+    for (i, word) in words.iter().enumerate() {
+        trie.insert(word.to_string(), i);
+        path_trie.insert(word.to_string(), i);
+    }
+
+    let count = allocations(|| {
+        for word in words.iter().chain(&["abe", "hel", "xyz", ""]) {
+            std::hint::black_box(trie.get(*word));
+            std::hint::black_box(trie.get_mut(*word));
+            std::hint::black_box(trie.get_ancestor_value(*word));
+            std::hint::black_box(trie.longest_prefix_match(*word));
+            std::hint::black_box(trie.longest_prefix_match_bytes(word.as_bytes()));
+            std::hint::black_box(path_trie.get(*word));
+            std::hint::black_box(path_trie.get_ancestor_value(*word));
+        }
+    });
+    assert_eq!(count, 0);
+}